@@ -0,0 +1,51 @@
+/*
+    Boot ROM mapped at MROM_BASE, equivalent to the reset vector of QEMU virt.
+
+    It jumps to the loaded image with a0 = mhartid, a1 = the address of the device tree blob and
+    a2 = the address of struct fw_dynamic_info, so images don't need to know which emulator they
+    run on.
+
+        auipc t0, 0
+        addi  a2, t0, 40
+        csrr  a0, mhartid
+        lw    a1, 32(t0)
+        lw    t0, 24(t0)
+        jr    t0
+        .dword <entry>
+        .dword <dtb>
+        fw_dynamic_info
+*/
+const CODE: [u32; 6] = [
+    0x0000_0297, // auipc t0, 0
+    0x0282_8613, // addi a2, t0, 40
+    0xf140_2573, // csrr a0, mhartid
+    0x0202_a583, // lw a1, 32(t0)
+    0x0182_a283, // lw t0, 24(t0)
+    0x0002_8067, // jr t0
+];
+
+/*
+    struct fw_dynamic_info of OpenSBI (include/sbi/fw_dynamic.h)
+
+    fw_dynamic.bin reads it to find the next booting stage, which runs in S-mode.
+*/
+const FW_DYNAMIC_INFO_MAGIC: u32 = 0x4942_534f; // "OSBI"
+const FW_DYNAMIC_INFO_VERSION: u32 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u32 = 1;
+
+pub fn bootrom(entry: u32, dtb: u32, next_addr: u32, boot_hart: u32) -> Vec<u8> {
+    let fw_dynamic_info = [
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        next_addr,
+        FW_DYNAMIC_INFO_NEXT_MODE_S,
+        // options
+        0,
+        boot_hart,
+    ];
+    CODE.iter()
+        .chain([entry, 0, dtb, 0].iter())
+        .chain(fw_dynamic_info.iter())
+        .flat_map(|w| w.to_le_bytes())
+        .collect()
+}
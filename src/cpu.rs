@@ -1,13 +1,20 @@
+mod compressed;
 #[allow(dead_code)]
 mod csr;
+mod decode;
 mod execute;
+mod pmp;
+mod snapshot;
+mod tlb;
 mod trap;
 mod vm;
 #[allow(unused_variables)]
 use crate::exception::Exception;
 use crate::memory::*;
+pub use csr::{isa_string, parse_isa, MARCHID, MCONFIGPTR, MHARTID, MIMPID, MVENDORID};
+use decode::{DecodeCache, Fetched, Inst};
+pub use tlb::Tlb;
 
-const SP: usize = 2;
 const NCSR: usize = 0x1000;
 
 #[allow(dead_code)]
@@ -23,37 +30,215 @@ pub struct Cpu {
     pub fregs: [f64; 32],
     pub csrs: [u32; NCSR],
     pub pc: u32,
+    // Address of the instruction being executed. pc already points to the next instruction.
+    pub inst_pc: u32,
     pub mode: Mode,
     pub ram: Memory,
+    // Set the A and D bits of PTEs on access instead of raising a page fault (Svadu).
+    pub svadu: bool,
+    // The value written to xtval when the current instruction traps.
+    pub tval: u32,
+    // The extensions implemented by the hart. misa can only enable these.
+    pub isa: u32,
+    pub tlb: Tlb,
+    // The address reserved by LR.W, which is the whole reservation set.
+    pub reservation: Option<u32>,
+    // The number of instructions retired.
+    pub instret: u64,
+    // run returns after retiring this many instructions.
+    pub stop_at: Option<u64>,
+    pub decode_cache: DecodeCache,
 }
 
 impl Cpu {
+    /*
+        (3.3) Reset
+
+        Upon reset, a hart's privilege mode is set to M. The mstatus fields MIE and MPRV are reset to 0.
+        The misa register is reset to enable the maximal set of supported extensions. The pc is set to
+        an implementation-defined reset vector. The mcause register is set to a value indicating the
+        cause of the reset. The PMP A and L fields are set to 0. All other hart state is unspecified.
+
+        Here all the other registers, including mtvec (direct mode, base 0), are reset to 0 and mcause
+        is 0 as there is only one kind of reset. The reset vector is the start of DRAM.
+    */
     pub fn new() -> Self {
-        let mut xregs: [u32; 32] = [0; 32];
-        xregs[SP] = MEMORY_SIZE;
+        let mut csrs = [0; NCSR];
+        csrs[csr::MISA] = csr::MISA_RESET;
         Cpu {
-            xregs,
+            xregs: [0; 32],
             fregs: [0.0f64; 32],
-            csrs: [0; NCSR],
-            pc: 0,
+            csrs,
+            pc: DRAM_BASE,
+            inst_pc: 0,
             ram: Memory::new(),
             mode: Mode::Machine,
+            svadu: false,
+            tval: 0,
+            isa: csr::MISA_RESET,
+            tlb: Tlb::new(64, true),
+            reservation: None,
+            instret: 0,
+            stop_at: None,
+            decode_cache: DecodeCache::new(),
         }
     }
 
+    // Resets the hart and the devices like power-on. The implemented extensions and the machine
+    // information registers are kept, and the caller sets pc to the reset vector.
+    pub fn reset(&mut self) {
+        let info = self.csrs[MVENDORID..=MCONFIGPTR].to_vec();
+        self.xregs = [0; 32];
+        self.fregs = [0.0; 32];
+        self.csrs = [0; NCSR];
+        self.csrs[csr::MISA] = self.isa;
+        self.csrs[MVENDORID..=MCONFIGPTR].copy_from_slice(&info);
+        unsafe { crate::fpu::FCSR = 0 };
+        self.mode = Mode::Machine;
+        self.tval = 0;
+        self.reservation = None;
+        self.tlb.flush(None, None);
+        self.decode_cache.flush();
+        self.ram.reset();
+    }
+
+    /*
+        Runs until a trap, the end address, a stop of the machine by the test finisher or stop_at.
+
+        Instructions are executed by basic blocks (see decode.rs), and pc is only compared with
+        the end address and stop_at between blocks. The devices are still advanced and interrupts
+        are still taken before each instruction, so the timing does not depend on the blocks.
+    */
     pub fn run(&mut self, end: u32) -> Result<(), Exception> {
-        loop {
-            let inst = self.vm_fetch(self.pc)?;
-            self.pc += 4;
+        // The page which instructions are fetched from without translation.
+        let mut window = None;
+        // Whether the devices are already advanced for the instruction at pc.
+        let mut ticked = false;
+        'blocks: loop {
+            if !ticked {
+                self.tick();
+                if let Some(i) = self.pending_interrupt() {
+                    self.interrupt(i);
+                    window = None;
+                }
+            }
+            ticked = false;
 
-            // println!("[{:08x}] {:08x}", self.pc - 4, inst);
-            self.execute(inst)?;
-            if self.pc == end {
+            self.inst_pc = self.pc;
+            let (page, mut parcel, mut count, bytes) = match self.fetch_block(&mut window)? {
+                Fetched::Block {
+                    page,
+                    parcel,
+                    count,
+                    bytes,
+                } => (page, parcel, count, bytes),
+                Fetched::Single(inst, raw) => {
+                    if inst == Inst::Other {
+                        window = None;
+                    }
+                    self.step(inst, raw)?;
+                    if self.stopped(end) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            // The end address in the middle of the block is reached by executing one by one.
+            if end > self.pc && end - self.pc < bytes {
+                count = 1;
+            }
+            if let Some(stop) = self.stop_at.filter(|&stop| stop > self.instret) {
+                count = count.min((stop - self.instret) as usize);
+            }
+
+            for n in 0..count {
+                if n > 0 {
+                    self.tick();
+                    if let Some(i) = self.pending_interrupt() {
+                        self.interrupt(i);
+                        window = None;
+                        ticked = true;
+                        continue 'blocks;
+                    }
+                    self.inst_pc = self.pc;
+                }
+                let entry = self.decode_cache.entry(page, parcel);
+                // The rest of the block has been overwritten by a store in the block.
+                if entry.inst == Inst::Undecoded {
+                    ticked = true;
+                    continue 'blocks;
+                }
+                if entry.inst == Inst::Other {
+                    window = None;
+                }
+                self.step(entry.inst, entry.raw)?;
+                // The guest powered off or reset the machine through the test finisher.
+                if self.ram.test.finish.is_some() {
+                    return Ok(());
+                }
+                parcel += if entry.raw & 0b11 == 0b11 { 2 } else { 1 };
+            }
+            if self.stopped(end) {
                 return Ok(());
             }
         }
     }
 
+    /*
+        Advances the devices by one instruction and updates the pending interrupts. The instructions
+        overwritten by the virtio devices are invalidated before the next instruction is executed,
+        so the guest does not need a fence.i after DMA into code.
+    */
+    fn tick(&mut self) {
+        self.ram.tick();
+        for virtio in self.ram.virtio.iter_mut() {
+            for (pa, size) in virtio.dma_writes.drain(..) {
+                self.decode_cache.invalidate_range(pa, size);
+            }
+        }
+        self.update_mip();
+    }
+
+    // Executes and retires an instruction.
+    fn step(&mut self, inst: Inst, raw: u32) -> Result<(), Exception> {
+        // println!("[{:08x}] {:08x}", self.pc, raw);
+        if let Err(e) = self.execute_decoded(inst, raw) {
+            // (3.1.17) On an illegal instruction trap, mtval is written with the faulting instruction.
+            if let Exception::IllegalInstruction = e {
+                self.tval = raw;
+            }
+            return Err(e);
+        }
+        self.instret += 1;
+        Ok(())
+    }
+
+    fn stopped(&self, end: u32) -> bool {
+        self.pc == end || self.ram.test.finish.is_some() || self.stop_at == Some(self.instret)
+    }
+
+    /*
+        (3.1.9) MEIP, MTIP and MSIP are read-only bits of mip, which are set and cleared by the PLIC
+        and the CLINT. SEIP is also driven by the PLIC.
+    */
+    fn update_mip(&mut self) {
+        let mut mip =
+            self.csrs[csr::MIP] & !(csr::MIP_MEIP | csr::MIP_MTIP | csr::MIP_MSIP | csr::MIP_SEIP);
+        if self.ram.clint.software_interrupt() {
+            mip |= csr::MIP_MSIP;
+        }
+        if self.ram.clint.timer_interrupt() {
+            mip |= csr::MIP_MTIP;
+        }
+        if self.ram.plic.interrupt(0) {
+            mip |= csr::MIP_MEIP;
+        }
+        if self.ram.plic.interrupt(1) {
+            mip |= csr::MIP_SEIP;
+        }
+        self.csrs[csr::MIP] = mip;
+    }
+
     pub fn dump_registers(&self) {
         for i in (0..32).step_by(4) {
             println!(
@@ -0,0 +1,319 @@
+use super::csr::MISA_C;
+use crate::bits::*;
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+
+/*
+    (16.1) Compressed Instruction Formats
+
+    Each RVC instruction expands into a single 32-bit instruction in the base ISA or the F and D
+    standard extensions. The registers of the CIW, CL, CS, CA and CB formats are 3-bit fields which
+    specify x8–x15 (or f8–f15).
+*/
+const OP_LOAD: u32 = 0b000_0011;
+const OP_LOAD_FP: u32 = 0b000_0111;
+const OP_IMM: u32 = 0b001_0011;
+const OP_STORE: u32 = 0b010_0011;
+const OP_STORE_FP: u32 = 0b010_0111;
+const OP: u32 = 0b011_0011;
+const OP_LUI: u32 = 0b011_0111;
+const OP_BRANCH: u32 = 0b110_0011;
+const OP_JALR: u32 = 0b110_0111;
+const OP_JAL: u32 = 0b110_1111;
+
+const X0: u32 = 0;
+const RA: u32 = 1;
+const SP: u32 = 2;
+
+// Sign-extends the lower `bits` bits of `val`.
+fn sext(val: u32, bits: u32) -> u32 {
+    ((val << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+// rd′/rs1′/rs2′ of the compressed formats
+fn creg(c: u32, start: u32) -> u32 {
+    read_bits(c, start..start + 2) + 8
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xFFF) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    read_bits(imm, 5..11) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | read_bits(imm, 0..4) << 7
+        | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    read_bit(imm, 12) << 31
+        | read_bits(imm, 5..10) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | read_bits(imm, 1..4) << 8
+        | read_bit(imm, 11) << 7
+        | OP_BRANCH
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    read_bit(imm, 20) << 31
+        | read_bits(imm, 1..10) << 21
+        | read_bit(imm, 11) << 20
+        | read_bits(imm, 12..19) << 12
+        | rd << 7
+        | OP_JAL
+}
+
+// CJ-format jump target: offset[11|4|9:8|10|6|7|3:1|5]
+fn cj_offset(c: u32) -> u32 {
+    let imm = read_bit(c, 12) << 11
+        | read_bit(c, 11) << 4
+        | read_bits(c, 9..10) << 8
+        | read_bit(c, 8) << 10
+        | read_bit(c, 7) << 6
+        | read_bit(c, 6) << 7
+        | read_bits(c, 3..5) << 1
+        | read_bit(c, 2) << 5;
+    sext(imm, 12)
+}
+
+// CB-format branch offset: offset[8|4:3] and offset[7:6|2:1|5]
+fn cb_offset(c: u32) -> u32 {
+    let imm = read_bit(c, 12) << 8
+        | read_bits(c, 10..11) << 3
+        | read_bits(c, 5..6) << 6
+        | read_bits(c, 3..4) << 1
+        | read_bit(c, 2) << 5;
+    sext(imm, 9)
+}
+
+// CI-format 6-bit immediate: imm[5] and imm[4:0]
+fn ci_imm(c: u32) -> u32 {
+    sext(read_bit(c, 12) << 5 | read_bits(c, 2..6), 6)
+}
+
+// CL/CS-format word offset: uimm[5:3] and uimm[2|6]
+fn cl_word_offset(c: u32) -> u32 {
+    read_bits(c, 10..12) << 3 | read_bit(c, 6) << 2 | read_bit(c, 5) << 6
+}
+
+// CL/CS-format doubleword offset: uimm[5:3] and uimm[7:6]
+fn cl_double_offset(c: u32) -> u32 {
+    read_bits(c, 10..12) << 3 | read_bits(c, 5..6) << 6
+}
+
+/*
+    Expands a 16-bit compressed instruction into the equivalent 32-bit instruction.
+    Reserved encodings, and RV64/RV128-only encodings, raise an illegal instruction exception.
+*/
+pub fn expand(c: u32) -> Result<u32, Exception> {
+    let funct3 = read_bits(c, 13..15);
+    let rd = read_bits(c, 7..11);
+    let rs2 = read_bits(c, 2..6);
+
+    let inst = match (read_bits(c, 0..1), funct3) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            // c.addi4spn
+            let imm = read_bits(c, 11..12) << 4
+                | read_bits(c, 7..10) << 6
+                | read_bit(c, 6) << 2
+                | read_bit(c, 5) << 3;
+            // The all-zero instruction and nzuimm=0 are illegal.
+            if imm == 0 {
+                return Err(Exception::IllegalInstruction);
+            }
+            i_type(imm, SP, 0b000, creg(c, 2), OP_IMM)
+        }
+        (0b00, 0b001) => {
+            // c.fld
+            i_type(
+                cl_double_offset(c),
+                creg(c, 7),
+                0b011,
+                creg(c, 2),
+                OP_LOAD_FP,
+            )
+        }
+        (0b00, 0b010) => {
+            // c.lw
+            i_type(cl_word_offset(c), creg(c, 7), 0b010, creg(c, 2), OP_LOAD)
+        }
+        (0b00, 0b011) => {
+            // c.flw
+            i_type(cl_word_offset(c), creg(c, 7), 0b010, creg(c, 2), OP_LOAD_FP)
+        }
+        (0b00, 0b101) => {
+            // c.fsd
+            s_type(
+                cl_double_offset(c),
+                creg(c, 2),
+                creg(c, 7),
+                0b011,
+                OP_STORE_FP,
+            )
+        }
+        (0b00, 0b110) => {
+            // c.sw
+            s_type(cl_word_offset(c), creg(c, 2), creg(c, 7), 0b010, OP_STORE)
+        }
+        (0b00, 0b111) => {
+            // c.fsw
+            s_type(
+                cl_word_offset(c),
+                creg(c, 2),
+                creg(c, 7),
+                0b010,
+                OP_STORE_FP,
+            )
+        }
+
+        // Quadrant 1
+        (0b01, 0b000) => {
+            // c.addi (c.nop if rd=0)
+            i_type(ci_imm(c), rd, 0b000, rd, OP_IMM)
+        }
+        (0b01, 0b001) => {
+            // c.jal
+            j_type(cj_offset(c), RA)
+        }
+        (0b01, 0b010) => {
+            // c.li
+            i_type(ci_imm(c), X0, 0b000, rd, OP_IMM)
+        }
+        (0b01, 0b011) if rd == SP => {
+            // c.addi16sp: nzimm[9] and nzimm[4|6|8:7|5]
+            let imm = read_bit(c, 12) << 9
+                | read_bit(c, 6) << 4
+                | read_bit(c, 5) << 6
+                | read_bits(c, 3..4) << 7
+                | read_bit(c, 2) << 5;
+            if imm == 0 {
+                return Err(Exception::IllegalInstruction);
+            }
+            i_type(sext(imm, 10), SP, 0b000, SP, OP_IMM)
+        }
+        (0b01, 0b011) => {
+            // c.lui
+            let imm = ci_imm(c);
+            if imm == 0 {
+                return Err(Exception::IllegalInstruction);
+            }
+            imm << 12 | rd << 7 | OP_LUI
+        }
+        (0b01, 0b100) => {
+            let rd = creg(c, 7);
+            match read_bits(c, 10..11) {
+                // For RV32C, shamt[5] must be zero.
+                0b00 if read_bit(c, 12) == 0 => {
+                    // c.srli
+                    i_type(rs2, rd, 0b101, rd, OP_IMM)
+                }
+                0b01 if read_bit(c, 12) == 0 => {
+                    // c.srai
+                    i_type(0b010_0000 << 5 | rs2, rd, 0b101, rd, OP_IMM)
+                }
+                0b10 => {
+                    // c.andi
+                    i_type(ci_imm(c), rd, 0b111, rd, OP_IMM)
+                }
+                // c.subw and c.addw are RV64C/RV128C-only.
+                0b11 if read_bit(c, 12) == 0 => {
+                    let rs2 = creg(c, 2);
+                    match read_bits(c, 5..6) {
+                        // c.sub
+                        0b00 => r_type(0b010_0000, rs2, rd, 0b000, rd, OP),
+                        // c.xor
+                        0b01 => r_type(0b000_0000, rs2, rd, 0b100, rd, OP),
+                        // c.or
+                        0b10 => r_type(0b000_0000, rs2, rd, 0b110, rd, OP),
+                        // c.and
+                        _ => r_type(0b000_0000, rs2, rd, 0b111, rd, OP),
+                    }
+                }
+                _ => return Err(Exception::IllegalInstruction),
+            }
+        }
+        (0b01, 0b101) => {
+            // c.j
+            j_type(cj_offset(c), X0)
+        }
+        (0b01, 0b110) => {
+            // c.beqz
+            b_type(cb_offset(c), X0, creg(c, 7), 0b000)
+        }
+        (0b01, 0b111) => {
+            // c.bnez
+            b_type(cb_offset(c), X0, creg(c, 7), 0b001)
+        }
+
+        // Quadrant 2
+        (0b10, 0b000) if read_bit(c, 12) == 0 => {
+            // c.slli
+            i_type(rs2, rd, 0b001, rd, OP_IMM)
+        }
+        (0b10, 0b001) => {
+            // c.fldsp: uimm[5] and uimm[4:3|8:6]
+            let imm = read_bit(c, 12) << 5 | read_bits(c, 5..6) << 3 | read_bits(c, 2..4) << 6;
+            i_type(imm, SP, 0b011, rd, OP_LOAD_FP)
+        }
+        (0b10, 0b010) if rd != 0 => {
+            // c.lwsp: uimm[5] and uimm[4:2|7:6]
+            let imm = read_bit(c, 12) << 5 | read_bits(c, 4..6) << 2 | read_bits(c, 2..3) << 6;
+            i_type(imm, SP, 0b010, rd, OP_LOAD)
+        }
+        (0b10, 0b011) => {
+            // c.flwsp
+            let imm = read_bit(c, 12) << 5 | read_bits(c, 4..6) << 2 | read_bits(c, 2..3) << 6;
+            i_type(imm, SP, 0b010, rd, OP_LOAD_FP)
+        }
+        (0b10, 0b100) => match (read_bit(c, 12), rd, rs2) {
+            // c.jr with rs1=0 is reserved.
+            (0, 0, 0) => return Err(Exception::IllegalInstruction),
+            // c.jr
+            (0, _, 0) => i_type(0, rd, 0b000, X0, OP_JALR),
+            // c.mv
+            (0, _, _) => r_type(0, rs2, X0, 0b000, rd, OP),
+            // c.ebreak
+            (_, 0, 0) => 0x0010_0073,
+            // c.jalr
+            (_, _, 0) => i_type(0, rd, 0b000, RA, OP_JALR),
+            // c.add
+            _ => r_type(0, rs2, rd, 0b000, rd, OP),
+        },
+        (0b10, 0b101) => {
+            // c.fsdsp: uimm[5:3|8:6]
+            let imm = read_bits(c, 10..12) << 3 | read_bits(c, 7..9) << 6;
+            s_type(imm, rs2, SP, 0b011, OP_STORE_FP)
+        }
+        (0b10, 0b110) => {
+            // c.swsp: uimm[5:2|7:6]
+            let imm = read_bits(c, 9..12) << 2 | read_bits(c, 7..8) << 6;
+            s_type(imm, rs2, SP, 0b010, OP_STORE)
+        }
+        (0b10, 0b111) => {
+            // c.fswsp
+            let imm = read_bits(c, 9..12) << 2 | read_bits(c, 7..8) << 6;
+            s_type(imm, rs2, SP, 0b010, OP_STORE_FP)
+        }
+        _ => return Err(Exception::IllegalInstruction),
+    };
+    Ok(inst)
+}
+
+impl Cpu {
+    pub fn execute_compressed(&mut self, inst: u32) -> Result<(), Exception> {
+        if !self.has_extension(MISA_C) {
+            return Err(Exception::IllegalInstruction);
+        }
+        self.execute(expand(inst)?)
+    }
+}
@@ -1,23 +1,49 @@
-use crate::cpu::Cpu;
+use crate::bits::*;
+use crate::cpu::{Cpu, Mode};
 use crate::exception::Exception;
 use crate::fpu;
 
 mod address;
+mod misa;
 mod mstatus;
 
 pub use address::*;
+pub use misa::*;
 pub use mstatus::*;
 
 impl Cpu {
     pub fn csrr(&self, src: usize) -> Result<u32, Exception> {
-        // TODO: mask registers
         match src {
-            SSTATUS | USTATUS => Ok(self.csrs[MSTATUS]),
-            SIP | UIP => Ok(self.csrs[MIP]),
-            SIE | UIE => Ok(self.csrs[MIE]),
+            MSTATUS => Ok(self.read_mstatus()),
+            SSTATUS => Ok(self.read_mstatus() & SSTATUS_MASK),
+            USTATUS => Ok(self.read_mstatus() & USTATUS_MASK),
+            SIP => Ok(self.csrs[MIP] & self.s_interrupts()),
+            UIP => Ok(self.csrs[MIP] & self.u_interrupts()),
+            SIE => Ok(self.csrs[MIE] & self.s_interrupts()),
+            UIE => Ok(self.csrs[MIE] & self.u_interrupts()),
+            // (3.1.15) mepc[1] is masked on reads when IALIGN=32.
+            MEPC | SEPC | UEPC => Ok(self.csrs[src] & !self.ialign_mask()),
             FCSR => unsafe { Ok(fpu::FCSR) },
             FFLAGS => unsafe { Ok(fpu::FCSR & 0x1F) },
-            FRM => unsafe { Ok(fpu::FCSR & 0xE0) },
+            FRM => unsafe { Ok((fpu::FCSR & 0xE0) >> 5) },
+            // The time CSR is a read-only shadow of the memory-mapped mtime. (3.1.10)
+            TIME => Ok(self.ram.clint.mtime as u32),
+            TIMEH => Ok((self.ram.clint.mtime >> 32) as u32),
+            MCYCLE | MINSTRET | CYCLE | INSTRET => Ok(self.counter(src) as u32),
+            MCYCLEH | MINSTRETH | CYCLEH | INSTRETH => Ok((self.counter(src) >> 32) as u32),
+            /*
+                (Debug 5.2.1) Trigger Select (tselect)
+
+                Writes of values greater than or equal to the number of supported triggers may
+                result in a different value in this register than what was written. To verify that
+                what they wrote is a valid index, debuggers can read back the value and check that
+                tselect holds what they wrote.
+
+                No triggers are implemented, so tselect reads as all ones whatever is written, and
+                tdata1 reads as type 0, i.e. there is no trigger at this tselect.
+            */
+            TSELECT => Ok(!0),
+            TDATA1..=TDATA3 => Ok(0),
             _ => Ok(self.csrs[src]),
         }
     }
@@ -25,28 +51,232 @@ impl Cpu {
     pub fn csrw(&mut self, dst: usize, imm: u32) -> Result<(), Exception> {
         // TODO: Check imm
         match dst {
-            SSTATUS | USTATUS => {
-                self.csrs[MSTATUS] = imm;
-            }
-            SIP | UIP => {
-                self.csrs[MIP] = imm;
-            }
-            SIE | UIE => {
-                self.csrs[MIE] = imm;
-            }
+            MSTATUS => self.write_mstatus(imm, MSTATUS_MASK),
+            SSTATUS => self.write_mstatus(imm, SSTATUS_MASK & MSTATUS_MASK),
+            USTATUS => self.write_mstatus(imm, USTATUS_MASK),
+            MIP => self.write_masked(MIP, imm, SIP_MASK),
+            // Only the software interrupt bits are writable through sip and uip. (4.1.3)
+            SIP => self.write_masked(MIP, imm, (MIP_USIP | MIP_SSIP) & self.s_interrupts()),
+            UIP => self.write_masked(MIP, imm, MIP_USIP & self.u_interrupts()),
+            MIE => self.write_masked(MIE, imm, MIE_MASK),
+            SIE => self.write_masked(MIE, imm, self.s_interrupts()),
+            UIE => self.write_masked(MIE, imm, self.u_interrupts()),
+            MIDELEG => self.write_masked(MIDELEG, imm, SIP_MASK),
+            SIDELEG => self.write_masked(SIDELEG, imm, UIP_MASK),
+            MISA => self.write_misa(imm),
+            // mepc[0] is always zero.
+            MEPC | SEPC | UEPC => self.csrs[dst] = imm & !0b1,
+            SATP => self.write_satp(imm),
+            PMPCFG0..=PMPCFG3 => self.write_pmpcfg(dst, imm),
+            PMPADDR0..=PMPADDR15 => self.write_pmpaddr(dst, imm),
             FCSR => unsafe {
-                fpu::FCSR = imm;
+                self.set_fs_dirty();
+                fpu::FCSR = imm & 0xFF;
             },
             FFLAGS => unsafe {
+                self.set_fs_dirty();
                 fpu::FCSR &= !0x1F;
                 fpu::FCSR |= imm & 0x1F;
             },
             FRM => unsafe {
+                self.set_fs_dirty();
                 fpu::FCSR &= !0xE0;
-                fpu::FCSR |= imm & 0xE0;
+                fpu::FCSR |= (imm & 0b111) << 5;
             },
+            MCYCLE | MINSTRET | MCYCLEH | MINSTRETH => self.write_counter(dst, imm),
+            TSELECT..=TDATA3 => {}
             _ => self.csrs[dst] = imm,
         }
         Ok(())
     }
+
+    fn write_masked(&mut self, dst: usize, imm: u32, mask: u32) {
+        self.csrs[dst] = self.csrs[dst] & !mask | imm & mask;
+    }
+
+    /*
+        (3.1.11) Machine Hardware Performance Monitor
+
+        The mcycle CSR counts the number of clock cycles executed by the processor core on which the
+        hart is running. The minstret CSR counts the number of instructions the hart has retired.
+        The cycle and instret CSRs are read-only shadows of mcycle and minstret.
+
+        Each instruction takes one cycle, so both count the retired instructions. The CSRs hold the
+        offset of the counter from instret, which a write moves.
+    */
+    fn counter(&self, csr: usize) -> u64 {
+        let lo = MCYCLE + (csr & 0x7F);
+        let offset = (self.csrs[lo + 0x80] as u64) << 32 | self.csrs[lo] as u64;
+        self.instret.wrapping_add(offset)
+    }
+
+    fn write_counter(&mut self, csr: usize, imm: u32) {
+        let val = self.counter(csr);
+        let val = if csr & 0x80 == 0 {
+            val & !0xFFFF_FFFF | imm as u64
+        } else {
+            val & 0xFFFF_FFFF | (imm as u64) << 32
+        };
+        // The write is done instead of the increment by the writing instruction. (9.1)
+        let offset = val.wrapping_sub(self.instret + 1);
+        let lo = MCYCLE + (csr & 0x7F);
+        self.csrs[lo] = offset as u32;
+        self.csrs[lo + 0x80] = (offset >> 32) as u32;
+    }
+
+    /*
+        (4.1.3) Supervisor Interrupt Registers
+
+        Restricted views of the mip and mie registers appear as the sip and sie registers.
+        The bits of an interrupt that is not delegated via mideleg are read-only zero in sip and sie.
+        In the same way, uip and uie only show the interrupts delegated to U-mode via sideleg.
+    */
+    fn s_interrupts(&self) -> u32 {
+        self.csrs[MIDELEG] & SIP_MASK
+    }
+
+    fn u_interrupts(&self) -> u32 {
+        self.csrs[MIDELEG] & self.csrs[SIDELEG] & UIP_MASK
+    }
+
+    /*
+        (2.1) CSR Address Mapping Conventions
+
+        The top two bits (csr[11:10]) indicate whether the register is read/write (00, 01, or 10) or read-only (11).
+        The next two bits (csr[9:8]) encode the lowest privilege level that can access the CSR.
+
+        Attempts to access a non-existent CSR raise an illegal instruction exception.
+        Attempts to access a CSR without appropriate privilege level or to write a read-only register
+        also raise illegal instruction exceptions.
+    */
+    pub fn csr_accessible(&self, csr: usize, write: bool) -> bool {
+        let read_only = read_bits(csr as u32, 10..11) == 0b11;
+        let privilege = read_bits(csr as u32, 8..9);
+        self.csr_implemented(csr)
+            && privilege <= self.mode as u32
+            && !(write && read_only)
+            // (3.1.6.4) Accessing satp in S-mode is illegal when TVM=1.
+            && !(csr == SATP && self.trap_vm())
+            && self.counter_enabled(csr)
+    }
+
+    /*
+        (3.1.12) Counter-Enable Registers
+
+        When the CY, TM, IR, or HPMn bit in the mcounteren register is clear, attempts to read the
+        cycle, time, instret, or hpmcounter n register while executing in S-mode or U-mode will cause
+        an illegal instruction exception. scounteren controls the access in U-mode in the same way.
+    */
+    fn counter_enabled(&self, csr: usize) -> bool {
+        let bit = match csr {
+            CYCLE..=HPMCOUNTER31 => csr - CYCLE,
+            CYCLEH..=HPMCOUNTER31H => csr - CYCLEH,
+            _ => return true,
+        };
+        let enabled = |counteren: usize| self.csrs[counteren] & (1 << bit) != 0;
+        match self.mode {
+            Mode::Machine => true,
+            Mode::Supervisor => enabled(MCOUNTEREN),
+            Mode::User => enabled(MCOUNTEREN) && enabled(SCOUNTEREN),
+        }
+    }
+
+    fn csr_implemented(&self, csr: usize) -> bool {
+        match csr {
+            USTATUS | UIE | UTVEC | USCRATCH..=UIP => true,
+            // The floating-point CSRs are not present when the F extension is disabled.
+            FFLAGS..=FCSR => self.has_extension(MISA_F) && self.fs_enabled(),
+            CYCLE..=HPMCOUNTER31 | CYCLEH..=HPMCOUNTER31H => true,
+            SSTATUS | SEDELEG..=SCOUNTEREN | SSCRATCH..=SIP | SATP => true,
+            MVENDORID..=MCONFIGPTR => true,
+            MSTATUS..=MCOUNTEREN | MCOUNTINHIBIT | MHPMEVENT3..=MHPMEVENT31 | MSCRATCH..=MIP => {
+                true
+            }
+            PMPCFG0..=PMPCFG3 | PMPADDR0..=PMPADDR15 => true,
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => true,
+            MCYCLEH | MINSTRETH | MHPMCOUNTER3H..=MHPMCOUNTER31H => true,
+            TSELECT..=TDATA3 => true,
+            _ => false,
+        }
+    }
+
+    pub fn has_extension(&self, ext: u32) -> bool {
+        self.csrs[MISA] & ext != 0
+    }
+
+    // Selects the implemented extensions, which are all enabled at reset.
+    pub fn set_isa(&mut self, isa: u32) {
+        self.isa = isa;
+        self.csrs[MISA] = isa;
+        self.decode_cache.flush();
+    }
+
+    // The low bits of instruction addresses which must be zero. (IALIGN is 16 with the C extension, otherwise 32.)
+    pub fn ialign_mask(&self) -> u32 {
+        if self.has_extension(MISA_C) {
+            0b1
+        } else {
+            0b11
+        }
+    }
+
+    /*
+        (3.1.1) Only the implemented extensions can be enabled. D depends on F, so disabling F also disables D.
+
+        Writing misa may increase IALIGN, e.g., by disabling the C extension. If an instruction that
+        would write misa increases IALIGN, and the subsequent instruction’s address is not IALIGN-bit
+        aligned, the write to misa is suppressed, leaving misa unchanged.
+    */
+    fn write_misa(&mut self, imm: u32) {
+        if imm & MISA_C == 0 && self.pc & 0b11 != 0 {
+            return;
+        }
+        self.write_masked(MISA, imm, MISA_WRITABLE & self.isa);
+        if !self.has_extension(MISA_F) {
+            self.csrs[MISA] &= !MISA_D;
+        }
+        // Instructions of the disabled extensions are no longer decoded.
+        self.decode_cache.flush();
+    }
+
+    // Whether TVM intercepts the virtual-memory management operations of the current mode. (3.1.6.4)
+    pub fn trap_vm(&self) -> bool {
+        self.mode == Mode::Supervisor && read_bit(self.csrs[MSTATUS], MSTATUS_TVM) != 0
+    }
+
+    /*
+        (3.1.6.5) When the FS field is set to Off, any instruction that attempts to read or write
+        the floating-point state will cause an illegal instruction exception. Instructions which
+        modify the floating-point state set FS to Dirty.
+    */
+    pub fn fs_enabled(&self) -> bool {
+        read_bits(self.csrs[MSTATUS], MSTATUS_FS..MSTATUS_FS + 1) != 0
+    }
+
+    // FS is set to Dirty conservatively, even if the written value is the same.
+    pub fn set_fs_dirty(&mut self) {
+        write_bits(&mut self.csrs[MSTATUS], MSTATUS_FS..MSTATUS_FS + 1, 0b11);
+    }
+
+    // The SD bit is computed from FS and XS. (3.1.6.5)
+    fn read_mstatus(&self) -> u32 {
+        let mut mstatus = self.csrs[MSTATUS];
+        let fs = read_bits(mstatus, MSTATUS_FS..MSTATUS_FS + 1);
+        let xs = read_bits(mstatus, MSTATUS_XS..MSTATUS_XS + 1);
+        write_bit(&mut mstatus, MSTATUS_SD, (fs == 0b11 || xs == 0b11) as u32);
+        mstatus
+    }
+
+    fn write_mstatus(&mut self, imm: u32, mask: u32) {
+        let prev_mpp = read_bits(self.csrs[MSTATUS], MSTATUS_MPP..MSTATUS_MPP + 1);
+        self.write_masked(MSTATUS, imm, mask);
+        // MPP is WARL. The reserved encoding 0b10 keeps the previous mode.
+        if read_bits(self.csrs[MSTATUS], MSTATUS_MPP..MSTATUS_MPP + 1) == 0b10 {
+            write_bits(
+                &mut self.csrs[MSTATUS],
+                MSTATUS_MPP..MSTATUS_MPP + 1,
+                prev_mpp,
+            );
+        }
+    }
 }
@@ -84,6 +84,8 @@ pub const MARCHID: usize = 0xF12;
 pub const MIMPID: usize = 0xF13;
 // Hardware thread ID.
 pub const MHARTID: usize = 0xF14;
+// Pointer to configuration data structure.
+pub const MCONFIGPTR: usize = 0xF15;
 
 // Machine Trap Setup
 // Machine status register.
@@ -110,6 +112,15 @@ pub const MIE_MTIE: u32 = 0b1 << 7;
 pub const MIE_UEIE: u32 = 0b1 << 8;
 pub const MIE_SEIE: u32 = 0b1 << 9;
 pub const MIE_MEIE: u32 = 0b1 << 11;
+pub const MIE_MASK: u32 = MIE_USIE
+    | MIE_SSIE
+    | MIE_MSIE
+    | MIE_UTIE
+    | MIE_STIE
+    | MIE_MTIE
+    | MIE_UEIE
+    | MIE_SEIE
+    | MIE_MEIE;
 /*
     (3.1.7) Machine trap-handler base address.
 
@@ -187,6 +198,10 @@ pub const MIP_MTIP: u32 = 0b1 << 7;
 pub const MIP_UEIP: u32 = 0b1 << 8;
 pub const MIP_SEIP: u32 = 0b1 << 9;
 pub const MIP_MEIP: u32 = 0b1 << 11;
+// Interrupts of lower privilege modes. These are the writable bits of mip and mideleg.
+pub const SIP_MASK: u32 = MIP_USIP | MIP_SSIP | MIP_UTIP | MIP_STIP | MIP_UEIP | MIP_SEIP;
+// User-level interrupts. These are the writable bits of sideleg.
+pub const UIP_MASK: u32 = MIP_USIP | MIP_UTIP | MIP_UEIP;
 
 // Machine Memory Protection
 // Physical memory protection configration
@@ -0,0 +1,100 @@
+/*
+    (3.1.1) Machine ISA Register misa
+
+    The misa CSR is a WARL read-write register reporting the ISA supported by the hart.
+
+    31  30 29   26 25          0
+    | MXL | WLRL |  Extensions  |
+
+    The MXL field encodes the native base integer ISA width. (1: 32, 2: 64, 3: 128)
+    The Extensions field encodes the presence of the standard extensions, with a single bit per letter
+    of the alphabet. (bit 0 encodes presence of extension "A", bit 1 encodes presence of extension "B", ...)
+
+    The Extensions field is a WARL field that can contain writable bits where the implementation allows
+    the supported ISA to be modified. At reset, the Extensions field should contain the maximal set of
+    supported extensions.
+    If an ISA feature x depends on feature y, then attempting to enable feature x but disable feature y
+    results in both features being disabled. For example, setting "F"=0 and "D"=1 results in both
+    "F" and "D" being cleared.
+*/
+pub const MISA_MXL_32: u32 = 0b01 << 30;
+// Atomic extension
+pub const MISA_A: u32 = 0b1;
+// Compressed extension
+pub const MISA_C: u32 = 0b1 << 2;
+// Double-precision floating-point extension
+pub const MISA_D: u32 = 0b1 << 3;
+// Single-precision floating-point extension
+pub const MISA_F: u32 = 0b1 << 5;
+// RV32I base ISA
+pub const MISA_I: u32 = 0b1 << 8;
+// Integer Multiply/Divide extension
+pub const MISA_M: u32 = 0b1 << 12;
+// Supervisor mode implemented
+pub const MISA_S: u32 = 0b1 << 18;
+// User mode implemented
+pub const MISA_U: u32 = 0b1 << 20;
+
+// RV32IMAFDCSU
+pub const MISA_RESET: u32 =
+    MISA_MXL_32 | MISA_I | MISA_M | MISA_A | MISA_F | MISA_D | MISA_C | MISA_S | MISA_U;
+// Extensions which can be disabled and enabled again by writing misa.
+pub const MISA_WRITABLE: u32 = MISA_M | MISA_A | MISA_F | MISA_D | MISA_C;
+
+/*
+    Parses an ISA string such as "rv32imac" or "rv32gc" into the misa value of the implemented ISA.
+    "g" is shorthand for "imafd". Zicsr and Zifencei are always implemented, so they may be given as
+    multi-letter extensions separated by "_". S-mode and U-mode are always implemented.
+*/
+pub fn parse_isa(isa: &str) -> Result<u32, String> {
+    let lower = isa.to_ascii_lowercase();
+    let mut parts = lower.split('_');
+    let base = parts.next().unwrap_or_default();
+    let exts = base
+        .strip_prefix("rv32")
+        .ok_or_else(|| format!("{}: only RV32 is supported", isa))?;
+
+    let mut chars = exts.chars();
+    let mut misa = MISA_MXL_32 | MISA_S | MISA_U;
+    misa |= match chars.next() {
+        Some('i') => MISA_I,
+        Some('g') => MISA_I | MISA_M | MISA_A | MISA_F | MISA_D,
+        _ => return Err(format!("{}: the base ISA must be \"i\" or \"g\"", isa)),
+    };
+    for c in chars {
+        misa |= match c {
+            'm' => MISA_M,
+            'a' => MISA_A,
+            'f' => MISA_F,
+            'd' => MISA_D,
+            'c' => MISA_C,
+            _ => return Err(format!("{}: unsupported extension \"{}\"", isa, c)),
+        };
+    }
+    for ext in parts {
+        if ext != "zicsr" && ext != "zifencei" {
+            return Err(format!("{}: unsupported extension \"{}\"", isa, ext));
+        }
+    }
+    if misa & MISA_D != 0 && misa & MISA_F == 0 {
+        return Err(format!("{}: the D extension requires F", isa));
+    }
+    Ok(misa)
+}
+
+// Formats misa as an ISA string in canonical order, e.g. "rv32imafdc".
+pub fn isa_string(misa: u32) -> String {
+    let mut isa = String::from("rv32i");
+    for (ext, c) in [
+        (MISA_M, 'm'),
+        (MISA_A, 'a'),
+        (MISA_F, 'f'),
+        (MISA_D, 'd'),
+        (MISA_C, 'c'),
+    ] {
+        if misa & ext != 0 {
+            isa.push(c);
+        }
+    }
+    isa
+}
@@ -103,3 +103,39 @@ pub const MSTATUS_TSR: u32 = 22;
 pub const MSTATUS_FS: u32 = 13; // WARL
 pub const MSTATUS_XS: u32 = 15; // read-only
 pub const MSTATUS_SD: u32 = 31; // read-only
+
+/*
+    (3.1.6, 4.1.1) Restricted views of mstatus
+
+    The sstatus register is a subset of the mstatus register.
+    In a straightforward implementation, reading or writing any field in sstatus is equivalent to
+    reading or writing the homonymous field in mstatus.
+    The ustatus register is a subset of the sstatus register in the same way. (N extension)
+*/
+// Writable fields of mstatus. XS and SD are read-only.
+pub const MSTATUS_MASK: u32 = 1 << MSTATUS_UIE
+    | 1 << MSTATUS_SIE
+    | 1 << MSTATUS_MIE
+    | 1 << MSTATUS_UPIE
+    | 1 << MSTATUS_SPIE
+    | 1 << MSTATUS_MPIE
+    | 1 << MSTATUS_SPP
+    | 0b11 << MSTATUS_MPP
+    | 0b11 << MSTATUS_FS
+    | 1 << MSTATUS_MPRV
+    | 1 << MSTATUS_SUM
+    | 1 << MSTATUS_MXR
+    | 1 << MSTATUS_TVM
+    | 1 << MSTATUS_TW
+    | 1 << MSTATUS_TSR;
+pub const SSTATUS_MASK: u32 = 1 << MSTATUS_UIE
+    | 1 << MSTATUS_SIE
+    | 1 << MSTATUS_UPIE
+    | 1 << MSTATUS_SPIE
+    | 1 << MSTATUS_SPP
+    | 0b11 << MSTATUS_FS
+    | 0b11 << MSTATUS_XS
+    | 1 << MSTATUS_SUM
+    | 1 << MSTATUS_MXR
+    | 1 << MSTATUS_SD;
+pub const USTATUS_MASK: u32 = 1 << MSTATUS_UIE | 1 << MSTATUS_UPIE;
@@ -0,0 +1,718 @@
+use super::compressed::expand;
+use super::csr::{MISA, MISA_C, MISA_M};
+use crate::bits::*;
+use crate::cpu::Cpu;
+use crate::exception::Exception;
+use crate::memory::{MemOps, DRAM_BASE, MEMORY_SIZE};
+
+/*
+    Decoded-instruction cache
+
+    Instructions are decoded once into an Inst whose operands are already extracted, and kept by
+    physical address: each page of DRAM which has been executed from has a table of the decoded
+    instructions at its 16-bit parcels. Compressed instructions are decoded from their expansion.
+
+    Only the instructions common in integer code are decoded. The others are Inst::Other, which is
+    executed from the instruction word, or Inst::Plain for the F, D and A extensions, which are
+    executed in the same way but cannot change the translation or the privilege mode. Decoding
+    depends on the C and M bits of misa, so writing misa empties the cache. An instruction whose
+    parcels are on two pages is never cached.
+
+    (3.1) Zifencei: FENCE.I ensures that a subsequent instruction fetch on a RISC-V hart will see
+    any previous data stores already visible to the same RISC-V hart.
+
+    Stores by the hart invalidate the instructions they overlap right away, and FENCE.I empties
+    the cache, which makes the writes of devices to DRAM visible as well.
+
+    The decoded instructions are executed by basic blocks: the straight-line code from an address
+    up to a branch, a jump, an Inst::Other or the end of the page. The instructions of a block are
+    fetched without translation, and an exception in the middle of a block is still precise, as
+    pc and inst_pc are updated by every instruction.
+*/
+const PAGE_SIZE: u32 = 4096;
+const PARCELS: usize = PAGE_SIZE as usize / 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Inst {
+    // Not decoded yet.
+    Undecoded,
+    // Executed by Cpu::execute.
+    Other,
+    // Executed by Cpu::execute, and only accesses registers and memory: LOAD-FP, STORE-FP, OP-FP,
+    // the fused multiply-add instructions and AMO.
+    Plain,
+    Lui { rd: u8, imm: u32 },
+    Auipc { rd: u8, imm: u32 },
+    Jal { rd: u8, imm: u32 },
+    Jalr { rd: u8, rs1: u8, imm: u32 },
+    Beq { rs1: u8, rs2: u8, imm: u32 },
+    Bne { rs1: u8, rs2: u8, imm: u32 },
+    Blt { rs1: u8, rs2: u8, imm: u32 },
+    Bge { rs1: u8, rs2: u8, imm: u32 },
+    Bltu { rs1: u8, rs2: u8, imm: u32 },
+    Bgeu { rs1: u8, rs2: u8, imm: u32 },
+    Lb { rd: u8, rs1: u8, imm: u32 },
+    Lh { rd: u8, rs1: u8, imm: u32 },
+    Lw { rd: u8, rs1: u8, imm: u32 },
+    Lbu { rd: u8, rs1: u8, imm: u32 },
+    Lhu { rd: u8, rs1: u8, imm: u32 },
+    Sb { rs1: u8, rs2: u8, imm: u32 },
+    Sh { rs1: u8, rs2: u8, imm: u32 },
+    Sw { rs1: u8, rs2: u8, imm: u32 },
+    Addi { rd: u8, rs1: u8, imm: u32 },
+    Slti { rd: u8, rs1: u8, imm: u32 },
+    Sltiu { rd: u8, rs1: u8, imm: u32 },
+    Xori { rd: u8, rs1: u8, imm: u32 },
+    Ori { rd: u8, rs1: u8, imm: u32 },
+    Andi { rd: u8, rs1: u8, imm: u32 },
+    Slli { rd: u8, rs1: u8, shamt: u32 },
+    Srli { rd: u8, rs1: u8, shamt: u32 },
+    Srai { rd: u8, rs1: u8, shamt: u32 },
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Slt { rd: u8, rs1: u8, rs2: u8 },
+    Sltu { rd: u8, rs1: u8, rs2: u8 },
+    Xor { rd: u8, rs1: u8, rs2: u8 },
+    Srl { rd: u8, rs1: u8, rs2: u8 },
+    Sra { rd: u8, rs1: u8, rs2: u8 },
+    Or { rd: u8, rs1: u8, rs2: u8 },
+    And { rd: u8, rs1: u8, rs2: u8 },
+    Mul { rd: u8, rs1: u8, rs2: u8 },
+    Mulh { rd: u8, rs1: u8, rs2: u8 },
+    Mulhsu { rd: u8, rs1: u8, rs2: u8 },
+    Mulhu { rd: u8, rs1: u8, rs2: u8 },
+    Div { rd: u8, rs1: u8, rs2: u8 },
+    Divu { rd: u8, rs1: u8, rs2: u8 },
+    Rem { rd: u8, rs1: u8, rs2: u8 },
+    Remu { rd: u8, rs1: u8, rs2: u8 },
+}
+
+// Decodes an instruction word, which is a compressed instruction if the lowest two bits are not 11.
+pub fn decode(raw: u32, misa: u32) -> Inst {
+    let inst = if raw & 0b11 == 0b11 {
+        raw
+    } else if misa & MISA_C == 0 {
+        return Inst::Other;
+    } else {
+        match expand(raw) {
+            Ok(inst) => inst,
+            Err(_) => return Inst::Other,
+        }
+    };
+    let rd = read_bits(inst, 7..11) as u8;
+    let funct3 = read_bits(inst, 12..14);
+    let rs1 = read_bits(inst, 15..19) as u8;
+    let rs2 = read_bits(inst, 20..24) as u8;
+    let funct7 = read_bits(inst, 25..31);
+    let i_imm = ((inst as i32) >> 20) as u32;
+    let s_imm = (((inst & 0xfe00_0000) as i32 >> 25) as u32) << 5 | read_bits(inst, 7..11);
+    let sign = ((inst & 0x8000_0000) as i32 >> 31) as u32;
+    let b_imm = sign << 12
+        | read_bits(inst, 7..7) << 11
+        | read_bits(inst, 25..30) << 5
+        | read_bits(inst, 8..11) << 1;
+    let j_imm = sign << 20
+        | read_bits(inst, 12..19) << 12
+        | read_bits(inst, 20..20) << 11
+        | read_bits(inst, 21..30) << 1;
+    let shamt = rs2 as u32;
+
+    match read_bits(inst, 0..6) {
+        0b011_0111 => Inst::Lui {
+            rd,
+            imm: inst & 0xFFFF_F000,
+        },
+        0b001_0111 => Inst::Auipc {
+            rd,
+            imm: inst & 0xFFFF_F000,
+        },
+        0b110_1111 => Inst::Jal { rd, imm: j_imm },
+        0b110_0111 if funct3 == 0 => Inst::Jalr {
+            rd,
+            rs1,
+            imm: i_imm,
+        },
+        0b110_0011 => {
+            let imm = b_imm;
+            match funct3 {
+                0x0 => Inst::Beq { rs1, rs2, imm },
+                0x1 => Inst::Bne { rs1, rs2, imm },
+                0x4 => Inst::Blt { rs1, rs2, imm },
+                0x5 => Inst::Bge { rs1, rs2, imm },
+                0x6 => Inst::Bltu { rs1, rs2, imm },
+                0x7 => Inst::Bgeu { rs1, rs2, imm },
+                _ => Inst::Other,
+            }
+        }
+        0b000_0011 => {
+            let imm = i_imm;
+            match funct3 {
+                0x0 => Inst::Lb { rd, rs1, imm },
+                0x1 => Inst::Lh { rd, rs1, imm },
+                0x2 => Inst::Lw { rd, rs1, imm },
+                0x4 => Inst::Lbu { rd, rs1, imm },
+                0x5 => Inst::Lhu { rd, rs1, imm },
+                _ => Inst::Other,
+            }
+        }
+        0b010_0011 => {
+            let imm = s_imm;
+            match funct3 {
+                0x0 => Inst::Sb { rs1, rs2, imm },
+                0x1 => Inst::Sh { rs1, rs2, imm },
+                0x2 => Inst::Sw { rs1, rs2, imm },
+                _ => Inst::Other,
+            }
+        }
+        0b001_0011 => {
+            let imm = i_imm;
+            match (funct3, funct7) {
+                (0x0, _) => Inst::Addi { rd, rs1, imm },
+                (0x2, _) => Inst::Slti { rd, rs1, imm },
+                (0x3, _) => Inst::Sltiu { rd, rs1, imm },
+                (0x4, _) => Inst::Xori { rd, rs1, imm },
+                (0x6, _) => Inst::Ori { rd, rs1, imm },
+                (0x7, _) => Inst::Andi { rd, rs1, imm },
+                (0x1, 0x00) => Inst::Slli { rd, rs1, shamt },
+                (0x5, 0x00) => Inst::Srli { rd, rs1, shamt },
+                (0x5, 0x20) => Inst::Srai { rd, rs1, shamt },
+                _ => Inst::Other,
+            }
+        }
+        0b011_0011 if funct7 == 0x01 && misa & MISA_M == 0 => Inst::Other,
+        0b011_0011 => match (funct3, funct7) {
+            (0x0, 0x00) => Inst::Add { rd, rs1, rs2 },
+            (0x0, 0x20) => Inst::Sub { rd, rs1, rs2 },
+            (0x1, 0x00) => Inst::Sll { rd, rs1, rs2 },
+            (0x2, 0x00) => Inst::Slt { rd, rs1, rs2 },
+            (0x3, 0x00) => Inst::Sltu { rd, rs1, rs2 },
+            (0x4, 0x00) => Inst::Xor { rd, rs1, rs2 },
+            (0x5, 0x00) => Inst::Srl { rd, rs1, rs2 },
+            (0x5, 0x20) => Inst::Sra { rd, rs1, rs2 },
+            (0x6, 0x00) => Inst::Or { rd, rs1, rs2 },
+            (0x7, 0x00) => Inst::And { rd, rs1, rs2 },
+            (0x0, 0x01) => Inst::Mul { rd, rs1, rs2 },
+            (0x1, 0x01) => Inst::Mulh { rd, rs1, rs2 },
+            (0x2, 0x01) => Inst::Mulhsu { rd, rs1, rs2 },
+            (0x3, 0x01) => Inst::Mulhu { rd, rs1, rs2 },
+            (0x4, 0x01) => Inst::Div { rd, rs1, rs2 },
+            (0x5, 0x01) => Inst::Divu { rd, rs1, rs2 },
+            (0x6, 0x01) => Inst::Rem { rd, rs1, rs2 },
+            (0x7, 0x01) => Inst::Remu { rd, rs1, rs2 },
+            _ => Inst::Other,
+        },
+        0b000_0111 | 0b010_0111 | 0b101_0011 | 0b100_0011 | 0b100_0111 | 0b100_1011
+        | 0b100_1111 | 0b010_1111 => Inst::Plain,
+        _ => Inst::Other,
+    }
+}
+
+impl Inst {
+    // Whether the instruction ends a basic block: a branch, a jump, or an instruction executed by
+    // Cpu::execute, which may trap or change the translation of the next instructions.
+    fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Inst::Other
+                | Inst::Jal { .. }
+                | Inst::Jalr { .. }
+                | Inst::Beq { .. }
+                | Inst::Bne { .. }
+                | Inst::Blt { .. }
+                | Inst::Bge { .. }
+                | Inst::Bltu { .. }
+                | Inst::Bgeu { .. }
+        )
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Entry {
+    pub inst: Inst,
+    // The instruction word, which is needed for its length and for mtval.
+    pub raw: u32,
+}
+
+const UNDECODED: Entry = Entry {
+    inst: Inst::Undecoded,
+    raw: 0,
+};
+
+struct Page {
+    // The decoded instruction at each parcel.
+    entries: Box<[Entry]>,
+    // The number of instructions and the length in bytes of the block starting at each parcel, or
+    // 0 if no block has been formed there.
+    blocks: Box<[(u16, u16)]>,
+}
+
+impl Page {
+    fn new() -> Self {
+        Self {
+            entries: vec![UNDECODED; PARCELS].into_boxed_slice(),
+            blocks: vec![(0, 0); PARCELS].into_boxed_slice(),
+        }
+    }
+}
+
+// What is executed next: `count` instructions of a block from the parcel of a page, or a single
+// instruction which is not cached.
+pub enum Fetched {
+    Block {
+        page: usize,
+        parcel: usize,
+        count: usize,
+        bytes: u32,
+    },
+    Single(Inst, u32),
+}
+
+pub struct DecodeCache {
+    // The pages of DRAM which have been executed from.
+    pages: Vec<Option<Page>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            pages: (0..MEMORY_SIZE / PAGE_SIZE).map(|_| None).collect(),
+        }
+    }
+
+    // Returns the page and the parcel of a physical address in DRAM.
+    fn index(pa: u32) -> Option<(usize, usize)> {
+        let offset = pa.checked_sub(DRAM_BASE).filter(|&o| o < MEMORY_SIZE)?;
+        Some((
+            (offset / PAGE_SIZE) as usize,
+            (offset % PAGE_SIZE) as usize / 2,
+        ))
+    }
+
+    pub fn entry(&self, page: usize, parcel: usize) -> Entry {
+        self.pages[page]
+            .as_ref()
+            .map_or(UNDECODED, |p| p.entries[parcel])
+    }
+
+    /*
+        Returns the block at a physical address in DRAM, which is formed on the first execution by
+        decoding the instructions up to the end of the block or of the page. A 32-bit instruction
+        whose parcels are on two pages is not part of any block.
+    */
+    fn block(&mut self, pa: u32, ram: &[u8], misa: u32) -> Option<Fetched> {
+        let (page, parcel) = Self::index(pa)?;
+        let p = self.pages[page].get_or_insert_with(Page::new);
+        if p.blocks[parcel].0 == 0 {
+            let base = page * PAGE_SIZE as usize;
+            let parcel_at =
+                |q: usize| u16::from_le_bytes([ram[base + q * 2], ram[base + q * 2 + 1]]);
+            let mut q = parcel;
+            let mut count = 0;
+            while q < PARCELS {
+                let mut entry = p.entries[q];
+                if entry.inst == Inst::Undecoded {
+                    let low = parcel_at(q) as u32;
+                    let raw = if low & 0b11 != 0b11 {
+                        low
+                    } else if q + 1 < PARCELS {
+                        (parcel_at(q + 1) as u32) << 16 | low
+                    } else {
+                        break;
+                    };
+                    entry = Entry {
+                        inst: decode(raw, misa),
+                        raw,
+                    };
+                    p.entries[q] = entry;
+                }
+                count += 1;
+                q += if entry.raw & 0b11 == 0b11 { 2 } else { 1 };
+                if entry.inst.ends_block() {
+                    break;
+                }
+            }
+            p.blocks[parcel] = (count, ((q - parcel) * 2) as u16);
+        }
+        let (count, bytes) = p.blocks[parcel];
+        (count > 0).then_some(Fetched::Block {
+            page,
+            parcel,
+            count: count as usize,
+            bytes: bytes as u32,
+        })
+    }
+
+    // Invalidates the instructions overlapping a store of `size` bytes, including a 32-bit
+    // instruction which starts at the previous parcel, and the blocks of the page.
+    pub fn invalidate(&mut self, pa: u32, size: u32) {
+        let Some((page, parcel)) = Self::index(pa) else {
+            return;
+        };
+        if let Some(p) = &mut self.pages[page] {
+            let last = (parcel + (size as usize).div_ceil(2)).min(PARCELS);
+            let mut stale = false;
+            for entry in &mut p.entries[parcel.saturating_sub(1)..last] {
+                stale |= entry.inst != Inst::Undecoded;
+                *entry = UNDECODED;
+            }
+            if stale {
+                p.blocks.fill((0, 0));
+            }
+        }
+    }
+
+    // Invalidates the instructions overlapping a write by a device, which may span pages.
+    pub fn invalidate_range(&mut self, pa: u32, size: u32) {
+        let end = pa as u64 + size as u64;
+        let mut addr = pa as u64;
+        while addr < end {
+            let next = (addr / PAGE_SIZE as u64 + 1) * PAGE_SIZE as u64;
+            self.invalidate(addr as u32, (next.min(end) - addr) as u32);
+            addr = next;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.pages.iter_mut().for_each(|page| *page = None);
+    }
+}
+
+impl Cpu {
+    /*
+        Fetches the block at pc. The first block of a page is fetched with the translation and
+        the PMP check of pc, and if the whole page may be executed, the page becomes the window,
+        from which the next blocks are fetched without translation. Blocks are chained in this way
+        until an interrupt or an instruction which may change the translation, i.e. Inst::Other.
+        Without a window, the other parcels of the block are checked against PMP at once, or the
+        instruction is executed alone.
+
+        The window is the virtual address of the page and the physical address.
+    */
+    pub fn fetch_block(&mut self, window: &mut Option<(u32, u32)>) -> Result<Fetched, Exception> {
+        let offset = self.pc % PAGE_SIZE;
+        let pa = match *window {
+            Some((va, pa)) if va == self.pc - offset => pa + offset,
+            _ => {
+                let pa = self.translate(self.pc, 2, MemOps::Fetch)?;
+                let page = pa - offset;
+                *window = self
+                    .pmp_check(page, PAGE_SIZE, MemOps::Fetch, self.mode)
+                    .is_ok()
+                    .then_some((self.pc - offset, page));
+                pa
+            }
+        };
+        let misa = self.csrs[MISA];
+        match self.decode_cache.block(pa, &self.ram.ram, misa) {
+            Some(Fetched::Block {
+                page,
+                parcel,
+                bytes,
+                ..
+            }) if window.is_none()
+                && self.pmp_check(pa, bytes, MemOps::Fetch, self.mode).is_err() =>
+            {
+                // Only the first parcel has been checked. The upper parcel of a 32-bit instruction
+                // is checked like Cpu::fetch_at does.
+                if self.decode_cache.entry(page, parcel).raw & 0b11 == 0b11 {
+                    self.translate(self.pc.wrapping_add(2), 2, MemOps::Fetch)?;
+                }
+                Ok(Fetched::Block {
+                    page,
+                    parcel,
+                    count: 1,
+                    bytes,
+                })
+            }
+            Some(block) => Ok(block),
+            None => {
+                let raw = self.fetch_at(self.pc, pa)?;
+                Ok(Fetched::Single(decode(raw, misa), raw))
+            }
+        }
+    }
+
+    fn x(&self, reg: u8) -> u32 {
+        self.xregs[reg as usize]
+    }
+
+    fn set_x(&mut self, reg: u8, val: u32) {
+        self.xregs[reg as usize] = val;
+    }
+
+    fn branch(&mut self, taken: bool, imm: u32) -> Result<(), Exception> {
+        if taken {
+            self.jump(self.inst_pc.wrapping_add(imm))?;
+        }
+        Ok(())
+    }
+
+    // Executes a decoded instruction in the same way as Cpu::execute, and advances pc past it.
+    pub fn execute_decoded(&mut self, inst: Inst, raw: u32) -> Result<(), Exception> {
+        // (16.1) The lowest two bits of 32-bit instructions are 11, the others are compressed instructions.
+        let compressed = raw & 0b11 != 0b11;
+        self.pc += if compressed { 2 } else { 4 };
+
+        match inst {
+            Inst::Undecoded | Inst::Other | Inst::Plain if compressed => {
+                return self.execute_compressed(raw)
+            }
+            Inst::Undecoded | Inst::Other | Inst::Plain => return self.execute(raw),
+            Inst::Lui { rd, imm } => self.set_x(rd, imm),
+            Inst::Auipc { rd, imm } => self.set_x(rd, self.inst_pc.wrapping_add(imm)),
+            Inst::Jal { rd, imm } => {
+                let link = self.pc;
+                self.jump(self.inst_pc.wrapping_add(imm))?;
+                self.set_x(rd, link);
+            }
+            Inst::Jalr { rd, rs1, imm } => {
+                // The target address is obtained by setting the least-significant bit of rs1+imm to zero. (2.5)
+                let target = self.x(rs1).wrapping_add(imm) & !0b1;
+                let link = self.pc;
+                self.jump(target)?;
+                self.set_x(rd, link);
+            }
+            Inst::Beq { rs1, rs2, imm } => self.branch(self.x(rs1) == self.x(rs2), imm)?,
+            Inst::Bne { rs1, rs2, imm } => self.branch(self.x(rs1) != self.x(rs2), imm)?,
+            Inst::Blt { rs1, rs2, imm } => {
+                self.branch((self.x(rs1) as i32) < (self.x(rs2) as i32), imm)?
+            }
+            Inst::Bge { rs1, rs2, imm } => {
+                self.branch((self.x(rs1) as i32) >= (self.x(rs2) as i32), imm)?
+            }
+            Inst::Bltu { rs1, rs2, imm } => self.branch(self.x(rs1) < self.x(rs2), imm)?,
+            Inst::Bgeu { rs1, rs2, imm } => self.branch(self.x(rs1) >= self.x(rs2), imm)?,
+            Inst::Lb { rd, rs1, imm } => {
+                let val = self.vm_read8(self.x(rs1).wrapping_add(imm))?;
+                self.set_x(rd, val as i8 as i32 as u32);
+            }
+            Inst::Lh { rd, rs1, imm } => {
+                let val = self.vm_read16(self.x(rs1).wrapping_add(imm))?;
+                self.set_x(rd, val as i16 as i32 as u32);
+            }
+            Inst::Lw { rd, rs1, imm } => {
+                let val = self.vm_read32(self.x(rs1).wrapping_add(imm))?;
+                self.set_x(rd, val);
+            }
+            Inst::Lbu { rd, rs1, imm } => {
+                let val = self.vm_read8(self.x(rs1).wrapping_add(imm))?;
+                self.set_x(rd, val);
+            }
+            Inst::Lhu { rd, rs1, imm } => {
+                let val = self.vm_read16(self.x(rs1).wrapping_add(imm))?;
+                self.set_x(rd, val);
+            }
+            Inst::Sb { rs1, rs2, imm } => {
+                self.vm_write8(self.x(rs1).wrapping_add(imm), self.x(rs2) as u8)?
+            }
+            Inst::Sh { rs1, rs2, imm } => {
+                self.vm_write16(self.x(rs1).wrapping_add(imm), self.x(rs2) as u16)?
+            }
+            Inst::Sw { rs1, rs2, imm } => {
+                self.vm_write32(self.x(rs1).wrapping_add(imm), self.x(rs2))?
+            }
+            Inst::Addi { rd, rs1, imm } => self.set_x(rd, self.x(rs1).wrapping_add(imm)),
+            Inst::Slti { rd, rs1, imm } => {
+                self.set_x(rd, ((self.x(rs1) as i32) < (imm as i32)) as u32)
+            }
+            Inst::Sltiu { rd, rs1, imm } => self.set_x(rd, (self.x(rs1) < imm) as u32),
+            Inst::Xori { rd, rs1, imm } => self.set_x(rd, self.x(rs1) ^ imm),
+            Inst::Ori { rd, rs1, imm } => self.set_x(rd, self.x(rs1) | imm),
+            Inst::Andi { rd, rs1, imm } => self.set_x(rd, self.x(rs1) & imm),
+            Inst::Slli { rd, rs1, shamt } => self.set_x(rd, self.x(rs1).wrapping_shl(shamt)),
+            Inst::Srli { rd, rs1, shamt } => self.set_x(rd, self.x(rs1).wrapping_shr(shamt)),
+            Inst::Srai { rd, rs1, shamt } => {
+                self.set_x(rd, (self.x(rs1) as i32).wrapping_shr(shamt) as u32)
+            }
+            Inst::Add { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1).wrapping_add(self.x(rs2))),
+            Inst::Sub { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1).wrapping_sub(self.x(rs2))),
+            // The shift amount is held in the lower 5 bits of rs2. (1.2.4)
+            Inst::Sll { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x(rs1).wrapping_shl(self.x(rs2) & 0b1_1111))
+            }
+            Inst::Srl { rd, rs1, rs2 } => {
+                self.set_x(rd, self.x(rs1).wrapping_shr(self.x(rs2) & 0b1_1111))
+            }
+            Inst::Sra { rd, rs1, rs2 } => self.set_x(
+                rd,
+                (self.x(rs1) as i32).wrapping_shr(self.x(rs2) & 0b1_1111) as u32,
+            ),
+            Inst::Slt { rd, rs1, rs2 } => {
+                self.set_x(rd, ((self.x(rs1) as i32) < (self.x(rs2) as i32)) as u32)
+            }
+            Inst::Sltu { rd, rs1, rs2 } => self.set_x(rd, (self.x(rs1) < self.x(rs2)) as u32),
+            Inst::Xor { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1) ^ self.x(rs2)),
+            Inst::Or { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1) | self.x(rs2)),
+            Inst::And { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1) & self.x(rs2)),
+            Inst::Mul { rd, rs1, rs2 } => self.set_x(rd, self.x(rs1).wrapping_mul(self.x(rs2))),
+            Inst::Mulh { rd, rs1, rs2 } => self.set_x(
+                rd,
+                ((self.x(rs1) as i32 as i64).wrapping_mul(self.x(rs2) as i32 as i64) >> 32) as u32,
+            ),
+            Inst::Mulhsu { rd, rs1, rs2 } => self.set_x(
+                rd,
+                ((self.x(rs1) as i32 as i64).wrapping_mul(self.x(rs2) as u64 as i64) >> 32) as u32,
+            ),
+            Inst::Mulhu { rd, rs1, rs2 } => self.set_x(
+                rd,
+                ((self.x(rs1) as u64).wrapping_mul(self.x(rs2) as u64) >> 32) as u32,
+            ),
+            // Division by zero returns all ones for the quotient and the dividend for the
+            // remainder, and the overflow case is handled by wrapping_*. (1.7.2)
+            Inst::Div { rd, rs1, rs2 } => self.set_x(
+                rd,
+                match self.x(rs2) {
+                    0 => 0xFFFF_FFFF,
+                    divisor => (self.x(rs1) as i32).wrapping_div(divisor as i32) as u32,
+                },
+            ),
+            Inst::Divu { rd, rs1, rs2 } => self.set_x(
+                rd,
+                self.x(rs1).checked_div(self.x(rs2)).unwrap_or(0xFFFF_FFFF),
+            ),
+            Inst::Rem { rd, rs1, rs2 } => self.set_x(
+                rd,
+                match self.x(rs2) {
+                    0 => self.x(rs1),
+                    divisor => (self.x(rs1) as i32).wrapping_rem(divisor as i32) as u32,
+                },
+            ),
+            Inst::Remu { rd, rs1, rs2 } => self.set_x(
+                rd,
+                self.x(rs1).checked_rem(self.x(rs2)).unwrap_or(self.x(rs1)),
+            ),
+        }
+        // Register x0 is hardwired with all bits equal to 0. (1.2.1)
+        self.xregs[0] = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::csr::{PMPADDR0, PMPADDR1, PMPCFG0};
+
+    // addi x1, x0, 1
+    const ADDI: u32 = 0x0010_0093;
+    // addi x1, x1, 1
+    const INC: u32 = 0x0010_8093;
+
+    // Returns a hart which executes the code from the start of DRAM.
+    fn cpu_with(code: &[u32]) -> Cpu {
+        let mut cpu = Cpu::new();
+        for (i, &inst) in code.iter().enumerate() {
+            cpu.ram.write32(DRAM_BASE + 4 * i as u32, inst).unwrap();
+        }
+        cpu
+    }
+
+    fn entry(cpu: &Cpu, pa: u32) -> Entry {
+        let (page, parcel) = DecodeCache::index(pa).unwrap();
+        cpu.decode_cache.entry(page, parcel)
+    }
+
+    #[test]
+    fn store_invalidates_decoded_instructions() {
+        let mut cpu = cpu_with(&[INC, INC, INC]);
+        assert!(matches!(
+            cpu.fetch_block(&mut None),
+            Ok(Fetched::Block { .. })
+        ));
+        assert!(entry(&cpu, DRAM_BASE + 4).inst != Inst::Undecoded);
+
+        // A store to the upper parcel of an instruction invalidates the instruction.
+        cpu.vm_write16(DRAM_BASE + 6, 0).unwrap();
+        assert!(entry(&cpu, DRAM_BASE).inst != Inst::Undecoded);
+        assert!(entry(&cpu, DRAM_BASE + 4).inst == Inst::Undecoded);
+        assert!(entry(&cpu, DRAM_BASE + 8).inst != Inst::Undecoded);
+    }
+
+    #[test]
+    fn self_modifying_code_in_a_block() {
+        let mut cpu = cpu_with(&[
+            // auipc x3, 0
+            0x0000_0197,
+            // li x2, 0x00200093, which is addi x1, x0, 2
+            0x0020_0137,
+            0x0931_0113,
+            // sw x2, 16(x3)
+            0x0021_a823,
+            ADDI,
+        ]);
+        cpu.run(DRAM_BASE + 20).unwrap();
+        // The instruction overwritten in the block which is being executed is decoded again.
+        assert_eq!(cpu.xregs[1], 2);
+    }
+
+    #[test]
+    fn upper_parcel_outside_pmp_region_faults() {
+        let mut cpu = Cpu::new();
+        // [0, DRAM_BASE + 4) is executable and the rest of DRAM is not, even in M-mode.
+        cpu.csrw(PMPADDR0, (DRAM_BASE + 4) >> 2).unwrap();
+        cpu.csrw(PMPADDR1, (DRAM_BASE + 0x1000) >> 2).unwrap();
+        cpu.csrw(PMPCFG0, 0x8d | 0x89 << 8).unwrap();
+        // A 32-bit instruction straddling the end of the executable region.
+        cpu.ram.write16(DRAM_BASE + 2, ADDI as u16).unwrap();
+        cpu.ram.write16(DRAM_BASE + 4, (ADDI >> 16) as u16).unwrap();
+        cpu.pc = DRAM_BASE + 2;
+
+        let fetched = cpu.fetch_block(&mut None);
+        assert!(matches!(fetched, Err(Exception::InstructionAccessFault)));
+        assert_eq!(cpu.tval, DRAM_BASE + 4);
+    }
+
+    // beq x0, x0, 8
+    const BEQ: u32 = 0x0000_0463;
+    // ecall
+    const ECALL: u32 = 0x0000_0073;
+    // fadd.s f0, f0, f0
+    const FADD: u32 = 0x0000_0053;
+    // lw x4, 0(x0)
+    const LW: u32 = 0x0000_2203;
+
+    fn block(cpu: &mut Cpu) -> (usize, u32) {
+        match cpu.fetch_block(&mut None) {
+            Ok(Fetched::Block { count, bytes, .. }) => (count, bytes),
+            _ => panic!("no block at {:#x}", cpu.pc),
+        }
+    }
+
+    #[test]
+    fn blocks_end_at_branches() {
+        let mut cpu = cpu_with(&[INC, INC, BEQ, INC, INC, BEQ]);
+        assert_eq!(block(&mut cpu), (3, 12));
+        // A block may start in the middle of another one.
+        cpu.pc = DRAM_BASE + 4;
+        assert_eq!(block(&mut cpu), (2, 8));
+        cpu.pc = DRAM_BASE + 12;
+        assert_eq!(block(&mut cpu), (3, 12));
+    }
+
+    #[test]
+    fn blocks_end_at_system_instructions() {
+        let mut cpu = cpu_with(&[INC, ECALL, INC, BEQ]);
+        assert_eq!(block(&mut cpu), (2, 8));
+    }
+
+    #[test]
+    fn blocks_run_across_fp_instructions() {
+        let mut cpu = cpu_with(&[INC, FADD, INC, BEQ]);
+        assert_eq!(block(&mut cpu), (4, 16));
+    }
+
+    #[test]
+    fn trap_in_a_block() {
+        let mut cpu = cpu_with(&[INC, LW, INC, BEQ]);
+        assert_eq!(block(&mut cpu), (4, 16));
+        // The load faults, and the rest of the block is not executed.
+        assert!(matches!(
+            cpu.run(DRAM_BASE + 16),
+            Err(Exception::LoadAccessFault)
+        ));
+        assert_eq!(cpu.xregs[1], 1);
+        assert_eq!(cpu.inst_pc, DRAM_BASE + 4);
+        assert_eq!(cpu.instret, 1);
+    }
+}
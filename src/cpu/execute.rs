@@ -8,6 +8,22 @@ impl Cpu {
     pub fn execute(&mut self, inst: u32) -> Result<(), Exception> {
         let opcode = read_bits(inst, 0..6);
 
+        // LOAD-FP, STORE-FP, OP-FP and the fused multiply-add instructions
+        let fp = matches!(
+            opcode,
+            0b000_0111
+                | 0b010_0111
+                | 0b101_0011
+                | 0b100_0011
+                | 0b100_0111
+                | 0b100_1011
+                | 0b100_1111
+        );
+        if fp && !self.fs_enabled() {
+            return Err(Exception::IllegalInstruction);
+        }
+        let fcsr = unsafe { fpu::FCSR };
+
         match opcode {
             0b011_0011 => {
                 // R-type
@@ -16,6 +32,9 @@ impl Cpu {
                 let rs1 = read_bits(inst, 15..19) as usize;
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let funct7 = read_bits(inst, 25..31);
+                if funct7 == 0x01 && !self.has_extension(csr::MISA_M) {
+                    return Err(Exception::IllegalInstruction);
+                }
 
                 match (funct3, funct7) {
                     (0x0, 0x00) => {
@@ -157,7 +176,7 @@ impl Cpu {
                             self.xregs[rs1].wrapping_rem(self.xregs[rs2])
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -191,7 +210,7 @@ impl Cpu {
                         // andi
                         self.xregs[rd] = self.xregs[rs1] & imm;
                     }
-                    0x1 => {
+                    0x1 if funct7 == 0x00 => {
                         // slli
                         self.xregs[rd] = self.xregs[rs1].wrapping_shl(shamt);
                     }
@@ -218,10 +237,10 @@ impl Cpu {
                                 self.xregs[rd] =
                                     (self.xregs[rs1] as i32).wrapping_shr(shamt) as u32;
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -259,7 +278,7 @@ impl Cpu {
                         let val = self.vm_read16(addr)?;
                         self.xregs[rd] = val;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -286,7 +305,7 @@ impl Cpu {
                         // sw
                         self.vm_write32(addr, self.xregs[rs2])?;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -306,40 +325,40 @@ impl Cpu {
                     0x0 => {
                         // beq
                         if self.xregs[rs1] == self.xregs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
                     0x1 => {
                         // bne
                         if self.xregs[rs1] != self.xregs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
                     0x4 => {
                         // blt
                         if (self.xregs[rs1] as i32) < (self.xregs[rs2] as i32) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
                     0x5 => {
                         // bge
                         if (self.xregs[rs1] as i32) >= (self.xregs[rs2] as i32) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
                     0x6 => {
                         // bltu
                         if self.xregs[rs1] < self.xregs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
                     0x7 => {
                         // bgeu
                         if self.xregs[rs1] >= self.xregs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4)
-                        };
+                            self.jump(self.inst_pc.wrapping_add(imm))?;
+                        }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -354,8 +373,9 @@ impl Cpu {
                 // Jumps can therefore target a ±1 MiB range (1.2.5)
                 let imm = imm20 << 20 | imm19 << 12 | imm11 << 11 | imm10 << 1;
 
-                self.xregs[rd] = self.pc;
-                self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                let link = self.pc;
+                self.jump(self.inst_pc.wrapping_add(imm))?;
+                self.xregs[rd] = link;
             }
 
             0b110_0111 => {
@@ -365,11 +385,14 @@ impl Cpu {
                 let funct3 = read_bits(inst, 12..14);
                 let rs1 = read_bits(inst, 15..19) as usize;
                 let imm = ((inst as i32) >> 20) as u32;
-                let addr = self.xregs[rs1].wrapping_add(imm);
-                if funct3 == 0 {
-                    self.xregs[rd] = self.pc;
-                    self.pc = addr;
+                // The target address is obtained by setting the least-significant bit of rs1+imm to zero. (2.5)
+                let addr = self.xregs[rs1].wrapping_add(imm) & !0b1;
+                if funct3 != 0 {
+                    return Err(Exception::IllegalInstruction);
                 }
+                let link = self.pc;
+                self.jump(addr)?;
+                self.xregs[rd] = link;
             }
 
             0b011_0111 => {
@@ -384,7 +407,7 @@ impl Cpu {
                 // auipc
                 let rd = read_bits(inst, 7..11) as usize;
                 let imm = inst & 0xFFFF_F000;
-                self.xregs[rd] = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.xregs[rd] = self.inst_pc.wrapping_add(imm);
             }
 
             // RV32 Zicsr + ecall/ebreak
@@ -398,6 +421,10 @@ impl Cpu {
 
                 match funct3 {
                     0b000 => {
+                        // Only SFENCE.VMA has a source register, and none of these instructions has rd.
+                        if rd != 0 || (rs1 != 0 && funct12 >> 5 != 0b000_1001) {
+                            return Err(Exception::IllegalInstruction);
+                        }
                         match funct12 {
                             0x0 => {
                                 // ecall
@@ -417,12 +444,16 @@ impl Cpu {
                             }
                             0x002 => {
                                 // uret
+                                // The N extension is not implemented (misa.N is 0).
+                                return Err(Exception::IllegalInstruction);
                             }
                             0x102 => {
                                 // sret
                                 // (3.1.6.4) SRET should also raise an illegal instruction exception when TSR=1 in mstatus
                                 let mut mstatus = self.csrr(csr::MSTATUS)?;
-                                if read_bit(mstatus, csr::MSTATUS_TSR) != 0 {
+                                if self.mode == Mode::Supervisor
+                                    && read_bit(mstatus, csr::MSTATUS_TSR) != 0
+                                {
                                     return Err(Exception::IllegalInstruction);
                                 }
                                 if self.mode == Mode::User {
@@ -440,6 +471,9 @@ impl Cpu {
                                 write_bit(&mut mstatus, csr::MSTATUS_SIE, spie);
                                 write_bit(&mut mstatus, csr::MSTATUS_SPIE, 1);
                                 write_bit(&mut mstatus, csr::MSTATUS_SPP, Mode::User as u32);
+                                // SRET always returns to a less-privileged mode than M, so MPRV is cleared.
+                                write_bit(&mut mstatus, csr::MSTATUS_MPRV, 0);
+                                self.csrw(csr::MSTATUS, mstatus)?;
                             }
                             0x302 => {
                                 // mret
@@ -467,33 +501,71 @@ impl Cpu {
                                     csr::MSTATUS_MPP..csr::MSTATUS_MPP + 1,
                                     Mode::User as u32,
                                 );
+                                // If MRET returns to a less-privileged mode, MPRV is cleared.
+                                if self.mode != Mode::Machine {
+                                    write_bit(&mut mstatus, csr::MSTATUS_MPRV, 0);
+                                }
                                 self.csrw(csr::MSTATUS, mstatus)?;
                             }
+                            // (3.1.6.4) WFI completes immediately, so the time limit of TW is always 0.
+                            0x105
+                                if self.mode != Mode::Machine
+                                    && read_bit(self.csrs[csr::MSTATUS], csr::MSTATUS_TW) != 0 =>
+                            {
+                                return Err(Exception::IllegalInstruction);
+                            }
                             0x105 => {
                                 // wfi
                             }
-                            _ => {}
+                            _ if funct12 >> 5 == 0b000_1001 => {
+                                // sfence.vma
+                                // (4.2.1) SFENCE.VMA is illegal in U-mode, and in S-mode when TVM=1.
+                                if self.mode == Mode::User || self.trap_vm() {
+                                    return Err(Exception::IllegalInstruction);
+                                }
+                                let rs2 = read_bits(inst, 20..24) as usize;
+                                let va = if rs1 != 0 {
+                                    Some(self.xregs[rs1])
+                                } else {
+                                    None
+                                };
+                                let asid = if rs2 != 0 {
+                                    Some(self.xregs[rs2] & 0x1FF)
+                                } else {
+                                    None
+                                };
+                                self.tlb.flush(va, asid);
+                            }
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
+                    // csrrw and csrrwi always write the CSR, the others only if rs1 (uimm) is not zero.
+                    _ if !self.csr_accessible(csr, funct3 & 0b11 == 0b01 || rs1 != 0) => {
+                        return Err(Exception::IllegalInstruction);
+                    }
                     0b001 => {
                         // csrrw
+                        // rs1 is read before rd is written, since they may be the same register.
+                        let src = self.xregs[rs1];
                         if rd != 0 {
                             self.xregs[rd] = self.csrr(csr)?;
                         }
-                        self.csrw(csr, self.xregs[rs1])?;
+                        self.csrw(csr, src)?;
                     }
                     0b010 => {
                         // csrrs
+                        let src = self.xregs[rs1];
                         self.xregs[rd] = self.csrr(csr)?;
                         if rs1 != 0 {
-                            self.csrw(csr, self.xregs[rd] | self.xregs[rs1])?;
+                            self.csrw(csr, self.xregs[rd] | src)?;
                         }
                     }
                     0b011 => {
                         // csrrc
+                        let src = self.xregs[rs1];
                         self.xregs[rd] = self.csrr(csr)?;
                         if rs1 != 0 {
-                            self.csrw(csr, self.xregs[rd] & !self.xregs[rs1])?;
+                            self.csrw(csr, self.xregs[rd] & !src)?;
                         }
                     }
                     0b101 => {
@@ -517,7 +589,7 @@ impl Cpu {
                             self.csrw(csr, self.xregs[rd] & !imm)?;
                         }
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -538,18 +610,34 @@ impl Cpu {
                 let _rl = read_bits(inst, 25..25); // release
                 let _aq = read_bits(inst, 26..26); // acquire
                 let funct5 = read_bits(inst, 27..31);
+                if !self.has_extension(csr::MISA_A) {
+                    return Err(Exception::IllegalInstruction);
+                }
 
                 match (funct3, funct5) {
-                    (0x2, 0x02) => {
-                        // TODO: implement set reserve
+                    /*
+                        LR.W loads a word from the address in rs1, places the sign-extended value in rd,
+                        and registers a reservation set. SC.W conditionally writes a word in rs2 to the
+                        address in rs1: the SC.W succeeds only if the reservation is still valid and the
+                        reservation set contains the bytes being written. If the SC.W fails, the
+                        instruction does not write to memory, and it writes a nonzero value to rd.
+                        Regardless of success or failure, executing an SC.W instruction invalidates any
+                        reservation held by this hart. (8.2)
+                    */
+                    (0x2, 0x02) if rs2 == 0 => {
                         // lr.w
                         self.xregs[rd] = self.vm_read32(self.xregs[rs1])?;
+                        self.reservation = Some(self.xregs[rs1]);
                     }
                     (0x2, 0x03) => {
-                        // TODO: implement check reserve
                         // sc.w
-                        self.vm_write32(self.xregs[rs1], self.xregs[rs2])?;
-                        self.xregs[rd] = 0;
+                        let addr = self.xregs[rs1];
+                        if self.reservation.take() == Some(addr) {
+                            self.vm_write32(addr, self.xregs[rs2])?;
+                            self.xregs[rd] = 0;
+                        } else {
+                            self.xregs[rd] = 1;
+                        }
                     }
                     (0x2, 0x01) => {
                         // amoswap.w
@@ -588,7 +676,7 @@ impl Cpu {
                         self.xregs[rd] = self.vm_read32(self.xregs[rs1])?;
                         self.vm_write32(self.xregs[rs1], self.xregs[rd] & self.xregs[rs2])?;
                     }
-                    (0x2, 0x0A) => {
+                    (0x2, 0x08) => {
                         // amoor.w
                         self.xregs[rd] = self.vm_read32(self.xregs[rs1])?;
                         self.vm_write32(self.xregs[rs1], self.xregs[rd] | self.xregs[rs2])?;
@@ -625,7 +713,7 @@ impl Cpu {
                             std::cmp::max(self.xregs[rd], self.xregs[rs2]),
                         )?;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -637,10 +725,11 @@ impl Cpu {
                     0x0 => {
                         // fence
                     }
-                    0x7 => {
+                    0x1 => {
                         // fence.i
+                        self.decode_cache.flush();
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -653,6 +742,7 @@ impl Cpu {
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let fmt = read_bits(inst, 25..26);
                 let funct5 = read_bits(inst, 27..31);
+                self.check_fp_fmt(fmt)?;
 
                 /*
                     A value of 111 in the instruction’s rm field selects the dynamic rounding mode held in frm.
@@ -663,11 +753,11 @@ impl Cpu {
                     0x00 => {
                         if fmt == fpu::FP32 {
                             // fadd.s
-                            self.fregs[rd] = fpu::fadd_32(
-                                self.fregs[rs1] as f32,
-                                self.fregs[rs2] as f32,
+                            self.fregs[rd] = fpu::nan_box(fpu::fadd_32(
+                                fpu::unbox(self.fregs[rs1]),
+                                fpu::unbox(self.fregs[rs2]),
                                 funct3,
-                            )? as f64;
+                            )?);
                         } else if fmt == fpu::FP64 {
                             // fadd.d
                             self.fregs[rd] =
@@ -677,11 +767,11 @@ impl Cpu {
                     0x01 => {
                         if fmt == fpu::FP32 {
                             // fsub.s
-                            self.fregs[rd] = fpu::fsub_32(
-                                self.fregs[rs1] as f32,
-                                self.fregs[rs2] as f32,
+                            self.fregs[rd] = fpu::nan_box(fpu::fsub_32(
+                                fpu::unbox(self.fregs[rs1]),
+                                fpu::unbox(self.fregs[rs2]),
                                 funct3,
-                            )? as f64;
+                            )?);
                         } else if fmt == fpu::FP64 {
                             // fsub.d
                             self.fregs[rd] =
@@ -691,11 +781,11 @@ impl Cpu {
                     0x02 => {
                         if fmt == fpu::FP32 {
                             // fmul.s
-                            self.fregs[rd] = fpu::fmul_32(
-                                self.fregs[rs1] as f32,
-                                self.fregs[rs2] as f32,
+                            self.fregs[rd] = fpu::nan_box(fpu::fmul_32(
+                                fpu::unbox(self.fregs[rs1]),
+                                fpu::unbox(self.fregs[rs2]),
                                 funct3,
-                            )? as f64;
+                            )?);
                         } else if fmt == fpu::FP64 {
                             // fmul.d
                             self.fregs[rd] =
@@ -705,11 +795,11 @@ impl Cpu {
                     0x03 => {
                         if fmt == fpu::FP32 {
                             // fdiv.s
-                            self.fregs[rd] = fpu::fdiv_32(
-                                self.fregs[rs1] as f32,
-                                self.fregs[rs2] as f32,
+                            self.fregs[rd] = fpu::nan_box(fpu::fdiv_32(
+                                fpu::unbox(self.fregs[rs1]),
+                                fpu::unbox(self.fregs[rs2]),
                                 funct3,
-                            )? as f64;
+                            )?);
                         } else if fmt == fpu::FP64 {
                             // fdiv.d
                             self.fregs[rd] =
@@ -721,11 +811,11 @@ impl Cpu {
                         // fsgnjn
                         // fsgnjx
                         if fmt == fpu::FP32 {
-                            self.fregs[rd] = fpu::fsgnj_32(
-                                self.fregs[rs1] as f32,
-                                self.fregs[rs2] as f32,
+                            self.fregs[rd] = fpu::nan_box(fpu::fsgnj_32(
+                                fpu::unbox(self.fregs[rs1]),
+                                fpu::unbox(self.fregs[rs2]),
                                 funct3,
-                            )? as f64;
+                            )?);
                         } else if fmt == fpu::FP64 {
                             self.fregs[rd] =
                                 fpu::fsgnj_64(self.fregs[rs1], self.fregs[rs2], funct3)?;
@@ -736,46 +826,53 @@ impl Cpu {
                             0b000 => {
                                 // fmin
                                 if fmt == fpu::FP32 {
-                                    self.fregs[rd] =
-                                        fpu::fmin(self.fregs[rs1] as f32, self.fregs[rs2] as f32)
-                                            as f64;
+                                    self.fregs[rd] = fpu::nan_box(fpu::fmin_32(
+                                        fpu::unbox(self.fregs[rs1]),
+                                        fpu::unbox(self.fregs[rs2]),
+                                    ));
                                 } else if fmt == fpu::FP64 {
-                                    self.fregs[rd] = fpu::fmin(self.fregs[rs1], self.fregs[rs2]);
+                                    self.fregs[rd] = fpu::fmin_64(self.fregs[rs1], self.fregs[rs2]);
                                 }
                             }
                             0b001 => {
                                 // fmax
                                 if fmt == fpu::FP32 {
-                                    self.fregs[rd] =
-                                        fpu::fmax(self.fregs[rs1] as f32, self.fregs[rs2] as f32)
-                                            as f64;
+                                    self.fregs[rd] = fpu::nan_box(fpu::fmax_32(
+                                        fpu::unbox(self.fregs[rs1]),
+                                        fpu::unbox(self.fregs[rs2]),
+                                    ));
                                 } else if fmt == fpu::FP64 {
-                                    self.fregs[rd] = fpu::fmax(self.fregs[rs1], self.fregs[rs2]);
+                                    self.fregs[rd] = fpu::fmax_64(self.fregs[rs1], self.fregs[rs2]);
                                 }
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
-                    0x0B => {
+                    0x0B if rs2 == 0 => {
                         if fmt == fpu::FP32 {
                             // fsqrt.s
-                            self.fregs[rd] = fpu::fsqrt_32(self.fregs[rs1] as f32, funct3)? as f64;
+                            self.fregs[rd] =
+                                fpu::nan_box(fpu::fsqrt_32(fpu::unbox(self.fregs[rs1]), funct3)?);
                         } else if fmt == fpu::FP64 {
                             // fsqrt.d
                             self.fregs[rd] = fpu::fsqrt_64(self.fregs[rs1], funct3)?;
                         }
                     }
                     0x08 => {
+                        // Conversions between single and double precision require the D extension.
+                        self.check_fp_fmt(fpu::FP64)?;
                         match rs2 {
-                            0x0 => {
+                            0x0 if fmt == fpu::FP64 => {
                                 // fcvt.d.s
-                                self.fregs[rd] = self.fregs[rs1];
+                                self.fregs[rd] =
+                                    fpu::fcvt_d_s(fpu::unbox(self.fregs[rs1]), funct3)?;
                             }
-                            0x1 => {
+                            0x1 if fmt == fpu::FP32 => {
                                 // fcvt.s.d
-                                self.fregs[rd] = fpu::fcvt_s_d(self.fregs[rs1], funct3)? as f64;
+                                self.fregs[rd] =
+                                    fpu::nan_box(fpu::fcvt_s_d(self.fregs[rs1], funct3)?);
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
                     0x18 => {
@@ -784,7 +881,7 @@ impl Cpu {
                                 if fmt == fpu::FP32 {
                                     // fcvt.w.s
                                     self.xregs[rd] =
-                                        fpu::fcvt_w_s(self.fregs[rs1] as f32, funct3)? as u32;
+                                        fpu::fcvt_w_s(fpu::unbox(self.fregs[rs1]), funct3)? as u32;
                                 } else if fmt == fpu::FP64 {
                                     // fcvt.w.d
                                     self.xregs[rd] = fpu::fcvt_w_d(self.fregs[rs1], funct3)? as u32;
@@ -794,13 +891,13 @@ impl Cpu {
                                 if fmt == fpu::FP32 {
                                     // fcvt.wu.s
                                     self.xregs[rd] =
-                                        fpu::fcvt_wu_s(self.fregs[rs1] as f32, funct3)?;
+                                        fpu::fcvt_wu_s(fpu::unbox(self.fregs[rs1]), funct3)?;
                                 } else if fmt == fpu::FP64 {
                                     // fcvt.wu.d
                                     self.xregs[rd] = fpu::fcvt_wu_d(self.fregs[rs1], funct3)?;
                                 }
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
                     0x1A => {
@@ -808,8 +905,10 @@ impl Cpu {
                             0x0 => {
                                 if fmt == fpu::FP32 {
                                     // fcvt.s.w
-                                    self.fregs[rd] =
-                                        fpu::fcvt_s_w(self.xregs[rs1] as i32, funct3)? as f64;
+                                    self.fregs[rd] = fpu::nan_box(fpu::fcvt_s_w(
+                                        self.xregs[rs1] as i32,
+                                        funct3,
+                                    )?);
                                 } else if fmt == fpu::FP64 {
                                     // fcvt.d.w
                                     self.fregs[rd] = fpu::fcvt_d_w(self.xregs[rs1] as i32, funct3)?;
@@ -819,13 +918,13 @@ impl Cpu {
                                 if fmt == fpu::FP32 {
                                     // fcvt.s.wu
                                     self.fregs[rd] =
-                                        fpu::fcvt_s_wu(self.xregs[rs1], funct3)? as f64;
+                                        fpu::nan_box(fpu::fcvt_s_wu(self.xregs[rs1], funct3)?);
                                 } else if fmt == fpu::FP64 {
                                     // fcvt.d.wu
                                     self.fregs[rd] = fpu::fcvt_d_wu(self.xregs[rs1], funct3)?;
                                 }
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
                     0x14 => {
@@ -833,56 +932,62 @@ impl Cpu {
                             0b000 => {
                                 // fle
                                 if fmt == fpu::FP32 {
-                                    self.xregs[rd] =
-                                        fpu::fle(self.fregs[rs1] as f32, self.fregs[rs2] as f32);
+                                    self.xregs[rd] = fpu::fle_32(
+                                        fpu::unbox(self.fregs[rs1]),
+                                        fpu::unbox(self.fregs[rs2]),
+                                    );
                                 } else if fmt == fpu::FP64 {
-                                    self.xregs[rd] = fpu::fle(self.fregs[rs1], self.fregs[rs2]);
+                                    self.xregs[rd] = fpu::fle_64(self.fregs[rs1], self.fregs[rs2]);
                                 }
                             }
                             0b001 => {
                                 // flt
                                 if fmt == fpu::FP32 {
-                                    self.xregs[rd] =
-                                        fpu::flt(self.fregs[rs1] as f32, self.fregs[rs2] as f32);
+                                    self.xregs[rd] = fpu::flt_32(
+                                        fpu::unbox(self.fregs[rs1]),
+                                        fpu::unbox(self.fregs[rs2]),
+                                    );
                                 } else if fmt == fpu::FP64 {
-                                    self.xregs[rd] = fpu::flt(self.fregs[rs1], self.fregs[rs2]);
+                                    self.xregs[rd] = fpu::flt_64(self.fregs[rs1], self.fregs[rs2]);
                                 }
                             }
                             0b010 => {
                                 // feq
                                 if fmt == fpu::FP32 {
-                                    self.xregs[rd] =
-                                        fpu::feq(self.fregs[rs1] as f32, self.fregs[rs2] as f32);
+                                    self.xregs[rd] = fpu::feq_32(
+                                        fpu::unbox(self.fregs[rs1]),
+                                        fpu::unbox(self.fregs[rs2]),
+                                    );
                                 } else if fmt == fpu::FP64 {
-                                    self.xregs[rd] = fpu::feq(self.fregs[rs1], self.fregs[rs2]);
+                                    self.xregs[rd] = fpu::feq_64(self.fregs[rs1], self.fregs[rs2]);
                                 }
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
-                    0x1C => {
+                    0x1C if rs2 == 0 => {
                         match funct3 {
-                            0b000 => {
+                            0b000 if fmt == fpu::FP32 => {
                                 // fmv.x.w
-                                self.xregs[rd] = (self.fregs[rs1] as f32).to_bits();
+                                self.xregs[rd] = self.fregs[rs1].to_bits() as u32;
                             }
                             0b001 => {
                                 // fclass
                                 if fmt == fpu::FP32 {
-                                    self.xregs[rd] = fpu::fclass_32(self.fregs[rs1] as f32);
+                                    self.xregs[rd] = fpu::fclass_32(fpu::unbox(self.fregs[rs1]));
                                 } else if fmt == fpu::FP64 {
                                     self.xregs[rd] = fpu::fclass_64(self.fregs[rs1]);
                                 }
                             }
-                            _ => {}
+                            _ => return Err(Exception::IllegalInstruction),
                         }
                     }
-                    0x1E => {
+                    0x1E if funct3 == 0 && rs2 == 0 && fmt == fpu::FP32 => {
                         // fmv.w.x
-                        self.fregs[rd] = f32::from_bits(self.xregs[rs1]) as f64;
+                        self.fregs[rd] = fpu::nan_box(f32::from_bits(self.xregs[rs1]));
                     }
 
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -894,15 +999,16 @@ impl Cpu {
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let fmt = read_bits(inst, 25..26);
                 let rs3 = read_bits(inst, 27..31) as usize;
+                self.check_fp_fmt(fmt)?;
 
                 if fmt == fpu::FP32 {
                     // fmadd.s
-                    self.fregs[rd] = fpu::fmadd_32(
-                        self.fregs[rs1] as f32,
-                        self.fregs[rs2] as f32,
-                        self.fregs[rs3] as f32,
+                    self.fregs[rd] = fpu::nan_box(fpu::fmadd_32(
+                        fpu::unbox(self.fregs[rs1]),
+                        fpu::unbox(self.fregs[rs2]),
+                        fpu::unbox(self.fregs[rs3]),
                         funct3,
-                    )? as f64;
+                    )?);
                 } else if fmt == fpu::FP64 {
                     // fmadd.d
                     self.fregs[rd] =
@@ -918,15 +1024,16 @@ impl Cpu {
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let fmt = read_bits(inst, 25..26);
                 let rs3 = read_bits(inst, 27..31) as usize;
+                self.check_fp_fmt(fmt)?;
 
                 if fmt == fpu::FP32 {
                     // fmsub.s
-                    self.fregs[rd] = fpu::fmadd_32(
-                        self.fregs[rs1] as f32,
-                        self.fregs[rs2] as f32,
-                        -self.fregs[rs3] as f32,
+                    self.fregs[rd] = fpu::nan_box(fpu::fmadd_32(
+                        fpu::unbox(self.fregs[rs1]),
+                        fpu::unbox(self.fregs[rs2]),
+                        -fpu::unbox(self.fregs[rs3]),
                         funct3,
-                    )? as f64;
+                    )?);
                 } else if fmt == fpu::FP64 {
                     // fmsub.d
                     self.fregs[rd] =
@@ -942,15 +1049,16 @@ impl Cpu {
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let fmt = read_bits(inst, 25..26);
                 let rs3 = read_bits(inst, 27..31) as usize;
+                self.check_fp_fmt(fmt)?;
 
                 if fmt == fpu::FP32 {
                     // fnmsub.s
-                    self.fregs[rd] = fpu::fmadd_32(
-                        -self.fregs[rs1] as f32,
-                        self.fregs[rs2] as f32,
-                        self.fregs[rs3] as f32,
+                    self.fregs[rd] = fpu::nan_box(fpu::fmadd_32(
+                        -fpu::unbox(self.fregs[rs1]),
+                        fpu::unbox(self.fregs[rs2]),
+                        fpu::unbox(self.fregs[rs3]),
                         funct3,
-                    )? as f64;
+                    )?);
                 } else if fmt == fpu::FP64 {
                     // fnmsub.d
                     self.fregs[rd] =
@@ -966,15 +1074,16 @@ impl Cpu {
                 let rs2 = read_bits(inst, 20..24) as usize;
                 let fmt = read_bits(inst, 25..26);
                 let rs3 = read_bits(inst, 27..31) as usize;
+                self.check_fp_fmt(fmt)?;
 
                 if fmt == fpu::FP32 {
                     // fnmadd.s
-                    self.fregs[rd] = fpu::fmadd_32(
-                        -self.fregs[rs1] as f32,
-                        self.fregs[rs2] as f32,
-                        -self.fregs[rs3] as f32,
+                    self.fregs[rd] = fpu::nan_box(fpu::fmadd_32(
+                        -fpu::unbox(self.fregs[rs1]),
+                        fpu::unbox(self.fregs[rs2]),
+                        -fpu::unbox(self.fregs[rs3]),
                         funct3,
-                    )? as f64;
+                    )?);
                 } else if fmt == fpu::FP64 {
                     // fnmadd.d
                     self.fregs[rd] =
@@ -987,20 +1096,20 @@ impl Cpu {
                 let rd = read_bits(inst, 7..11) as usize;
                 let funct3 = read_bits(inst, 12..14);
                 let rs1 = read_bits(inst, 15..19) as usize;
-                let offset = read_bits(inst, 20..31);
+                let imm = ((inst as i32) >> 20) as u32;
+                let addr = self.xregs[rs1].wrapping_add(imm);
                 match funct3 {
                     0b010 => {
                         // flw
-                        self.fregs[rd] =
-                            f32::from_bits(self.vm_read32(self.xregs[rs1].wrapping_add(offset))?)
-                                as f64;
+                        self.check_fp_fmt(fpu::FP32)?;
+                        self.fregs[rd] = fpu::nan_box(f32::from_bits(self.vm_read32(addr)?));
                     }
                     0b011 => {
                         // fld
-                        self.fregs[rd] =
-                            f64::from_bits(self.vm_read64(self.xregs[rs1].wrapping_add(offset))?);
+                        self.check_fp_fmt(fpu::FP64)?;
+                        self.fregs[rd] = f64::from_bits(self.vm_read64(addr)?);
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -1011,19 +1120,22 @@ impl Cpu {
                 let funct3 = read_bits(inst, 12..14);
                 let rs1 = read_bits(inst, 15..19) as usize;
                 let rs2 = read_bits(inst, 20..24) as usize;
-                let imm2 = read_bits(inst, 25..31);
-                let imm = imm2 << 5 | imm1;
+                let imm2 = ((inst & 0xfe00_0000) as i32 >> 25) as u32;
+                let imm = (imm2 << 5) | imm1;
+                let addr = self.xregs[rs1].wrapping_add(imm);
 
                 match funct3 {
                     0b010 => {
                         // fsw
-                        self.vm_write32(self.xregs[rs1] + imm, (self.fregs[rs2] as f32).to_bits())?;
+                        self.check_fp_fmt(fpu::FP32)?;
+                        self.vm_write32(addr, self.fregs[rs2].to_bits() as u32)?;
                     }
                     0b011 => {
                         // fsd
-                        self.vm_write64(self.xregs[rs1] + imm, self.fregs[rs2].to_bits())?;
+                        self.check_fp_fmt(fpu::FP64)?;
+                        self.vm_write64(addr, self.fregs[rs2].to_bits())?;
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction),
                 }
             }
 
@@ -1031,8 +1143,51 @@ impl Cpu {
                 return Err(Exception::IllegalInstruction);
             }
         }
+        // FS is set to Dirty only after an instruction which wrote the f registers or fcsr. Stores,
+        // comparisons, fcvt.w, fmv.x.w and fclass only write fcsr, if any, through the flags.
+        if fp {
+            let writes_fregs = match opcode {
+                0b010_0111 => false,
+                0b101_0011 => !matches!(read_bits(inst, 27..31), 0x14 | 0x18 | 0x1C),
+                _ => true,
+            };
+            if writes_fregs || unsafe { fpu::FCSR } != fcsr {
+                self.set_fs_dirty();
+            }
+        }
         // Register x0 is hardwired with all bits equal to 0. (1.2.1)
         self.xregs[0] = 0;
         Ok(())
     }
+
+    /*
+        (2.5) Control Transfer Instructions
+
+        The instruction-address-misaligned exception is reported on the branch or jump instruction,
+        not on the target instruction. No exception is raised for a conditional branch that is not taken.
+    */
+    pub fn jump(&mut self, target: u32) -> Result<(), Exception> {
+        if target & self.ialign_mask() != 0 {
+            self.tval = target;
+            return Err(Exception::InstructionAddressMisaligned);
+        }
+        self.pc = target;
+        Ok(())
+    }
+
+    /*
+        The fmt field of floating-point instructions selects the precision. (1.11.6, 1.12)
+        Instructions of an unsupported format or of a disabled extension are illegal.
+    */
+    fn check_fp_fmt(&self, fmt: u32) -> Result<(), Exception> {
+        let ext = match fmt {
+            fpu::FP32 => csr::MISA_F,
+            fpu::FP64 => csr::MISA_D,
+            _ => return Err(Exception::IllegalInstruction),
+        };
+        if !self.has_extension(ext) {
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
 }
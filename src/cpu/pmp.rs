@@ -0,0 +1,301 @@
+use super::csr::*;
+use crate::bits::*;
+use crate::cpu::{Cpu, Mode};
+use crate::exception::Exception;
+use crate::memory::MemOps;
+
+/*
+    (3.6) Physical Memory Protection
+
+    PMP entries are described by an 8-bit configuration register and one 32-bit address register.
+    Up to 16 PMP entries are supported. The PMP configuration registers are densely packed into CSRs
+    to minimize context-switch time. For RV32, four CSRs, pmpcfg0–pmpcfg3, hold the configurations
+    pmp0cfg–pmp15cfg for the 16 PMP entries.
+
+    7   6 5 4   3 2 1 0
+    | L | 0 | A | X W R |
+*/
+pub const NPMP: usize = 16;
+
+const PMP_R: u32 = 0b1;
+const PMP_W: u32 = 0b1 << 1;
+const PMP_X: u32 = 0b1 << 2;
+const PMP_A: u32 = 3;
+const PMP_L: u32 = 0b1 << 7;
+
+// Address-Matching Mode (0 is OFF: Null region, disabled)
+// Top of range
+const PMP_TOR: u32 = 1;
+// Naturally aligned four-byte region
+const PMP_NA4: u32 = 2;
+// Naturally aligned power-of-two region, ≥8 bytes
+const PMP_NAPOT: u32 = 3;
+
+pub fn access_fault(ops: MemOps) -> Exception {
+    match ops {
+        MemOps::Load => Exception::LoadAccessFault,
+        MemOps::Store => Exception::StoreAMOAccessFault,
+        MemOps::Fetch => Exception::InstructionAccessFault,
+    }
+}
+
+impl Cpu {
+    fn pmpcfg(&self, i: usize) -> u32 {
+        let shift = (i as u32 % 4) * 8;
+        read_bits(self.csrs[PMPCFG0 + i / 4], shift..shift + 7)
+    }
+
+    /*
+        The pmpaddr CSRs encode bits 33–2 of a 34-bit physical address for RV32.
+        Returns the range [start, end) of bytes covered by the entry, or None if it is disabled.
+    */
+    fn pmp_range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.csrs[PMPADDR0 + i] as u64;
+        match read_bits(self.pmpcfg(i), PMP_A..PMP_A + 1) {
+            PMP_TOR => {
+                // If PMP entry 0’s A field is set to TOR, zero is used for the lower bound.
+                let start = if i == 0 {
+                    0
+                } else {
+                    (self.csrs[PMPADDR0 + i - 1] as u64) << 2
+                };
+                Some((start, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            PMP_NAPOT => {
+                // The number of trailing ones encodes the size of the region.
+                let size = 1u64 << ((!addr).trailing_zeros() + 3);
+                let start = (addr << 2) & !(size - 1);
+                Some((start, start + size))
+            }
+            // PMP_OFF
+            _ => None,
+        }
+    }
+
+    /*
+        (3.6.1) Priority and Matching Logic
+
+        PMP entries are statically prioritized. The lowest-numbered PMP entry that matches any byte of
+        an access determines whether that access succeeds or fails. The matching PMP entry must match
+        all bytes of an access, or the access fails, irrespective of the L, R, W, and X bits.
+
+        If no PMP entry matches an M-mode access, the access succeeds. If no PMP entry matches an
+        S-mode or U-mode access, but at least one PMP entry is implemented, the access fails.
+    */
+    pub fn pmp_check(
+        &self,
+        addr: u32,
+        size: u32,
+        ops: MemOps,
+        mode: Mode,
+    ) -> Result<(), Exception> {
+        let start = addr as u64;
+        let end = start + size as u64;
+
+        for i in 0..NPMP {
+            let (lo, hi) = match self.pmp_range(i) {
+                Some(range) => range,
+                None => continue,
+            };
+            // (3.7.1) If pmpaddr[i-1] >= pmpaddr[i] and pmpcfg[i].A=TOR, then PMP entry i
+            // matches no addresses.
+            if lo >= hi {
+                continue;
+            }
+            if end <= lo || hi <= start {
+                continue;
+            }
+            if start < lo || hi < end {
+                return Err(access_fault(ops));
+            }
+
+            let cfg = self.pmpcfg(i);
+            // When the L bit is clear, any M-mode access matching the PMP entry will succeed.
+            if mode == Mode::Machine && cfg & PMP_L == 0 {
+                return Ok(());
+            }
+            let perm = match ops {
+                MemOps::Load => PMP_R,
+                MemOps::Store => PMP_W,
+                MemOps::Fetch => PMP_X,
+            };
+            if cfg & perm == 0 {
+                return Err(access_fault(ops));
+            }
+            return Ok(());
+        }
+
+        if mode == Mode::Machine {
+            Ok(())
+        } else {
+            Err(access_fault(ops))
+        }
+    }
+
+    /*
+        (3.6.1) Locking
+
+        When the L bit is set, writes to the configuration register and associated address register
+        are ignored. If PMP entry i is locked, writes to pmpicfg and pmpaddri are ignored.
+        Additionally, if pmpicfg.A is set to TOR, writes to pmpaddri-1 are ignored.
+    */
+    pub fn write_pmpcfg(&mut self, dst: usize, imm: u32) {
+        let mut cfgs = self.csrs[dst];
+        for n in 0..4 {
+            let i = (dst - PMPCFG0) * 4 + n;
+            if self.pmpcfg(i) & PMP_L != 0 {
+                continue;
+            }
+            let shift = n as u32 * 8;
+            let mut cfg = read_bits(imm, shift..shift + 7);
+            // The combination R=0 and W=1 is reserved.
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            write_bits(&mut cfgs, shift..shift + 7, cfg & !0b0110_0000);
+        }
+        self.csrs[dst] = cfgs;
+    }
+
+    pub fn write_pmpaddr(&mut self, dst: usize, imm: u32) {
+        let i = dst - PMPADDR0;
+        if self.pmpcfg(i) & PMP_L != 0 {
+            return;
+        }
+        if i + 1 < NPMP {
+            let next = self.pmpcfg(i + 1);
+            if next & PMP_L != 0 && read_bits(next, PMP_A..PMP_A + 1) == PMP_TOR {
+                return;
+            }
+        }
+        self.csrs[dst] = imm;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U: Mode = Mode::User;
+    const M: Mode = Mode::Machine;
+
+    fn a(mode: u32) -> u32 {
+        mode << PMP_A
+    }
+
+    fn load(cpu: &Cpu, addr: u32, size: u32, mode: Mode) -> bool {
+        cpu.pmp_check(addr, size, MemOps::Load, mode).is_ok()
+    }
+
+    fn store(cpu: &Cpu, addr: u32, size: u32, mode: Mode) -> bool {
+        cpu.pmp_check(addr, size, MemOps::Store, mode).is_ok()
+    }
+
+    #[test]
+    fn tor() {
+        let mut cpu = Cpu::new();
+        cpu.csrw(PMPADDR0, 0x1000 >> 2).unwrap();
+        cpu.csrw(PMPADDR1, 0x2000 >> 2).unwrap();
+        cpu.csrw(
+            PMPCFG0,
+            (a(PMP_TOR) | PMP_R) | (a(PMP_TOR) | PMP_R | PMP_W) << 8,
+        )
+        .unwrap();
+        // Entry 0 is [0, 0x1000) and entry 1 is [0x1000, 0x2000).
+        assert!(load(&cpu, 0, 4, U));
+        assert!(load(&cpu, 0xffc, 4, U));
+        assert!(!store(&cpu, 0xffc, 4, U));
+        assert!(store(&cpu, 0x1000, 4, U));
+        assert!(store(&cpu, 0x1ffc, 4, U));
+        // No entry matches.
+        assert!(!load(&cpu, 0x2000, 4, U));
+        assert!(load(&cpu, 0x2000, 4, M));
+    }
+
+    #[test]
+    fn na4() {
+        let mut cpu = Cpu::new();
+        cpu.csrw(PMPADDR0, 0x3000 >> 2).unwrap();
+        cpu.csrw(PMPCFG0, a(PMP_NA4) | PMP_R).unwrap();
+        assert!(load(&cpu, 0x3000, 4, U));
+        assert!(!load(&cpu, 0x2ffc, 4, U));
+        assert!(!load(&cpu, 0x3004, 1, U));
+    }
+
+    #[test]
+    fn napot() {
+        let mut cpu = Cpu::new();
+        // A 4 KiB region at 0x4000.
+        cpu.csrw(PMPADDR0, 0x4000 >> 2 | (0x1000 / 8 - 1)).unwrap();
+        cpu.csrw(PMPCFG0, a(PMP_NAPOT) | PMP_R).unwrap();
+        assert!(load(&cpu, 0x4000, 4, U));
+        assert!(load(&cpu, 0x4ffc, 4, U));
+        assert!(!load(&cpu, 0x3ffc, 4, U));
+        assert!(!load(&cpu, 0x5000, 4, U));
+
+        // The smallest region is 8 bytes.
+        cpu.csrw(PMPADDR0, 0x4000 >> 2).unwrap();
+        assert!(load(&cpu, 0x4004, 4, U));
+        assert!(!load(&cpu, 0x4008, 4, U));
+    }
+
+    #[test]
+    fn empty_tor_matches_nothing() {
+        let mut cpu = Cpu::new();
+        cpu.csrw(PMPADDR0, 0x2000 >> 2).unwrap();
+        cpu.csrw(PMPADDR1, 0x1000 >> 2).unwrap();
+        // Entry 0 is off, and entry 1 is locked without any permission.
+        cpu.csrw(PMPCFG0, (a(PMP_TOR) | PMP_L) << 8).unwrap();
+        assert!(load(&cpu, 0x1800, 4, M));
+        assert!(store(&cpu, 0x1000, 4, M));
+        assert!(!load(&cpu, 0x1800, 4, U));
+    }
+
+    #[test]
+    fn partial_match_faults() {
+        let mut cpu = Cpu::new();
+        cpu.csrw(PMPADDR0, 0x3000 >> 2).unwrap();
+        cpu.csrw(PMPADDR1, 0x4000 >> 2).unwrap();
+        cpu.csrw(PMPCFG0, (a(PMP_NA4) | PMP_R) | (a(PMP_TOR) | PMP_R) << 8)
+            .unwrap();
+        assert!(load(&cpu, 0x3000, 4, U));
+        // The access starts in entry 0 and ends in entry 1, so entry 0 does not match all bytes.
+        assert!(!load(&cpu, 0x3002, 4, U));
+        // The access fails even in M-mode, whose accesses are not checked against unlocked entries.
+        assert!(!load(&cpu, 0x2ffc, 8, M));
+        assert!(matches!(
+            cpu.pmp_check(0x2ffc, 8, MemOps::Fetch, M),
+            Err(Exception::InstructionAccessFault)
+        ));
+    }
+
+    #[test]
+    fn locked_entries() {
+        let mut cpu = Cpu::new();
+        cpu.csrw(PMPADDR0, 0x1000 >> 2).unwrap();
+        cpu.csrw(PMPADDR1, 0x2000 >> 2).unwrap();
+        cpu.csrw(
+            PMPCFG0,
+            (a(PMP_TOR) | PMP_R) | (a(PMP_TOR) | PMP_R | PMP_L) << 8,
+        )
+        .unwrap();
+        // The locked entry applies to M-mode.
+        assert!(load(&cpu, 0x1000, 4, M));
+        assert!(!store(&cpu, 0x1000, 4, M));
+        // The unlocked entry does not.
+        assert!(store(&cpu, 0, 4, M));
+        assert!(!store(&cpu, 0, 4, U));
+
+        // The locked entry and the address of the unlocked entry below it cannot be changed.
+        cpu.csrw(PMPCFG0, PMP_R | PMP_W | (PMP_R | PMP_W) << 8)
+            .unwrap();
+        cpu.csrw(PMPADDR0, 0).unwrap();
+        cpu.csrw(PMPADDR1, 0).unwrap();
+        assert_eq!(cpu.pmpcfg(0), PMP_R | PMP_W);
+        assert_eq!(cpu.pmpcfg(1), a(PMP_TOR) | PMP_R | PMP_L);
+        assert_eq!(cpu.csrs[PMPADDR0], 0x1000 >> 2);
+        assert_eq!(cpu.csrs[PMPADDR1], 0x2000 >> 2);
+        assert!(!store(&cpu, 0x1000, 4, M));
+    }
+}
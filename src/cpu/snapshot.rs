@@ -0,0 +1,71 @@
+use std::fs;
+use std::io;
+
+use super::{Cpu, Mode};
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
+
+/*
+    The hart is saved before the bus. The TLB is not saved as it only caches the page tables in
+    memory, and it is flushed on restore.
+*/
+impl Snapshot for Cpu {
+    fn save(&self, w: &mut Writer) {
+        w.u32s(&self.xregs);
+        for f in &self.fregs {
+            w.u64(f.to_bits());
+        }
+        w.u32s(&self.csrs);
+        w.u32(unsafe { crate::fpu::FCSR });
+        w.u32(self.pc);
+        w.u8(self.mode as u8);
+        w.bool(self.svadu);
+        w.u32(self.isa);
+        w.bool(self.reservation.is_some());
+        w.u32(self.reservation.unwrap_or(0));
+        w.u64(self.instret);
+        self.ram.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.u32s(&mut self.xregs)?;
+        for f in self.fregs.iter_mut() {
+            *f = f64::from_bits(r.u64()?);
+        }
+        r.u32s(&mut self.csrs)?;
+        unsafe { crate::fpu::FCSR = r.u32()? };
+        self.pc = r.u32()?;
+        self.mode = match r.u8()? {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            0b11 => Mode::Machine,
+            _ => return Err(invalid("invalid privilege mode")),
+        };
+        self.svadu = r.bool()?;
+        self.isa = r.u32()?;
+        let reserved = r.bool()?;
+        let addr = r.u32()?;
+        self.reservation = reserved.then_some(addr);
+        self.instret = r.u64()?;
+        self.tval = 0;
+        self.tlb.flush(None, None);
+        self.decode_cache.flush();
+        self.ram.restore(r)
+    }
+}
+
+impl Cpu {
+    // Saves the whole machine to a file.
+    pub fn save_snapshot(&self, path: &str) -> io::Result<()> {
+        let mut w = Writer::new();
+        self.save(&mut w);
+        fs::write(path, w.finish())
+    }
+
+    // Restores the machine from a file saved with the same configuration.
+    pub fn restore_snapshot(&mut self, path: &str) -> io::Result<()> {
+        let data = fs::read(path)?;
+        let mut r = Reader::new(&data)?;
+        self.restore(&mut r)?;
+        r.finish()
+    }
+}
@@ -0,0 +1,196 @@
+/*
+    Translation lookaside buffer
+
+    A direct-mapped cache of leaf PTEs indexed by virtual page number and tagged with the ASID.
+    Megapages are cached as the 4 KiB page fragments that were actually accessed.
+
+    Entries hold the leaf PTE itself, and the permissions are checked again on every hit.
+    So changes of the privilege mode or mstatus.SUM/MXR never require a flush.
+*/
+pub const VPN_SHIFT: u32 = 12;
+const MEGAPAGE_SHIFT: u32 = 10;
+
+#[derive(Copy, Clone, Default)]
+pub struct TlbEntry {
+    pub valid: bool,
+    pub vpn: u32,
+    pub asid: u32,
+    pub global: bool,
+    pub megapage: bool,
+    // Physical page number of the 4 KiB page
+    pub ppn: u32,
+    // Leaf PTE
+    pub pte: u32,
+}
+
+#[derive(Default)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+}
+
+pub struct Tlb {
+    data: Vec<TlbEntry>,
+    // Empty if instructions and data share one TLB.
+    inst: Vec<TlbEntry>,
+    pub stats: TlbStats,
+}
+
+impl Tlb {
+    // Returns a TLB of `size` entries in total. A size of 0 disables the TLB.
+    pub fn new(size: usize, split: bool) -> Self {
+        let (inst, data) = if split {
+            (size / 2, size - size / 2)
+        } else {
+            (0, size)
+        };
+        Tlb {
+            data: vec![TlbEntry::default(); data],
+            inst: vec![TlbEntry::default(); inst],
+            stats: TlbStats::default(),
+        }
+    }
+
+    fn bank(&mut self, fetch: bool) -> &mut Vec<TlbEntry> {
+        if fetch && !self.inst.is_empty() {
+            &mut self.inst
+        } else {
+            &mut self.data
+        }
+    }
+
+    pub fn lookup(&mut self, va: u32, asid: u32, fetch: bool) -> Option<TlbEntry> {
+        let vpn = va >> VPN_SHIFT;
+        let bank = self.bank(fetch);
+        if bank.is_empty() {
+            return None;
+        }
+        let e = bank[vpn as usize % bank.len()];
+        if e.valid && e.vpn == vpn && (e.global || e.asid == asid) {
+            self.stats.hits += 1;
+            Some(e)
+        } else {
+            self.stats.misses += 1;
+            None
+        }
+    }
+
+    pub fn insert(&mut self, e: TlbEntry, fetch: bool) {
+        let bank = self.bank(fetch);
+        if bank.is_empty() {
+            return;
+        }
+        let len = bank.len();
+        bank[e.vpn as usize % len] = e;
+    }
+
+    /*
+        Invalidates the entries selected by the operands of SFENCE.VMA.
+        If `va` is None, all addresses are flushed. If `asid` is None, all address spaces are flushed,
+        otherwise the entries of global mappings are kept.
+    */
+    pub fn flush(&mut self, va: Option<u32>, asid: Option<u32>) {
+        self.stats.flushes += 1;
+        for e in self.inst.iter_mut().chain(self.data.iter_mut()) {
+            let hit_va = match va {
+                Some(va) => {
+                    let vpn = va >> VPN_SHIFT;
+                    e.vpn == vpn || (e.megapage && e.vpn >> MEGAPAGE_SHIFT == vpn >> MEGAPAGE_SHIFT)
+                }
+                None => true,
+            };
+            let hit_asid = match asid {
+                Some(asid) => !e.global && e.asid == asid,
+                None => true,
+            };
+            if hit_va && hit_asid {
+                e.valid = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(va: u32, asid: u32, global: bool) -> TlbEntry {
+        TlbEntry {
+            valid: true,
+            vpn: va >> VPN_SHIFT,
+            asid,
+            global,
+            megapage: false,
+            ppn: 0x80000 + (va >> VPN_SHIFT),
+            pte: 0,
+        }
+    }
+
+    fn cached(tlb: &mut Tlb, va: u32, asid: u32) -> bool {
+        tlb.lookup(va, asid, false).is_some()
+    }
+
+    #[test]
+    fn lookup_is_tagged_with_the_asid() {
+        let mut tlb = Tlb::new(64, false);
+        tlb.insert(entry(0x1000, 1, false), false);
+        tlb.insert(entry(0x2000, 1, true), false);
+        assert!(cached(&mut tlb, 0x1fff, 1));
+        assert!(!cached(&mut tlb, 0x1000, 2));
+        // Global mappings are in all address spaces.
+        assert!(cached(&mut tlb, 0x2000, 2));
+    }
+
+    #[test]
+    fn flush_by_va() {
+        let mut tlb = Tlb::new(64, false);
+        tlb.insert(entry(0x1000, 1, false), false);
+        tlb.insert(entry(0x2000, 1, true), false);
+        tlb.flush(Some(0x2abc), None);
+        assert!(cached(&mut tlb, 0x1000, 1));
+        assert!(!cached(&mut tlb, 0x2000, 1));
+    }
+
+    #[test]
+    fn flush_by_va_hits_the_whole_megapage() {
+        let mut tlb = Tlb::new(64, false);
+        tlb.insert(
+            TlbEntry {
+                megapage: true,
+                ..entry(0x40_1000, 1, false)
+            },
+            false,
+        );
+        tlb.insert(entry(0x80_1000, 1, false), false);
+        tlb.flush(Some(0x40_5000), Some(1));
+        assert!(!cached(&mut tlb, 0x40_1000, 1));
+        assert!(cached(&mut tlb, 0x80_1000, 1));
+    }
+
+    #[test]
+    fn flush_by_asid_keeps_global_entries() {
+        let mut tlb = Tlb::new(64, false);
+        tlb.insert(entry(0x1000, 1, false), false);
+        tlb.insert(entry(0x2000, 1, true), false);
+        tlb.insert(entry(0x3000, 2, false), false);
+        tlb.flush(None, Some(1));
+        assert!(!cached(&mut tlb, 0x1000, 1));
+        assert!(cached(&mut tlb, 0x2000, 1));
+        assert!(cached(&mut tlb, 0x3000, 2));
+
+        tlb.flush(None, None);
+        assert!(!cached(&mut tlb, 0x2000, 1));
+        assert!(!cached(&mut tlb, 0x3000, 2));
+    }
+
+    #[test]
+    fn split_tlb() {
+        let mut tlb = Tlb::new(64, true);
+        tlb.insert(entry(0x1000, 1, false), true);
+        assert!(tlb.lookup(0x1000, 1, true).is_some());
+        assert!(tlb.lookup(0x1000, 1, false).is_none());
+        tlb.flush(Some(0x1000), Some(1));
+        assert!(tlb.lookup(0x1000, 1, true).is_none());
+    }
+}
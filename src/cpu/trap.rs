@@ -3,9 +3,43 @@ use crate::bits::*;
 use crate::cpu::{Cpu, Mode};
 use crate::exception::*;
 
+/*
+    (3.1.9) Multiple simultaneous interrupts destined for M-mode are handled in the following
+    decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI, UEI, USI, UTI.
+*/
+const INTERRUPT_PRIORITY: [Interrupt; 9] = [
+    Interrupt::MachineExternalInterrupt,
+    Interrupt::MachineSoftwareInterrupt,
+    Interrupt::MachineTimerInterrupt,
+    Interrupt::SupervisorExternalInterrupt,
+    Interrupt::SupervisorSoftwareInterrupt,
+    Interrupt::SupervisorTimerInterrupt,
+    Interrupt::UserExternalInterrupt,
+    Interrupt::UserSoftwareInterrupt,
+    Interrupt::UserTimerInterrupt,
+];
+
+// mcause has the Interrupt bit set when the trap was caused by an interrupt.
+const CAUSE_INTERRUPT: u32 = 0b1 << 31;
+
 impl Cpu {
     pub fn trap(&mut self, e: Exception) {
         let ecode = e.exception_code();
+        /*
+            (3.1.17) Machine Trap Value Register
+
+            When a hardware breakpoint is triggered, or an instruction-fetch, load, or store
+            address-misaligned, access, or page-fault exception occurs, mtval is written with the faulting
+            virtual address. On an illegal instruction trap, mtval is written with the faulting instruction.
+            For other exceptions, mtval is set to zero.
+        */
+        let tval = match e {
+            Exception::Breakpoint => self.inst_pc,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+            _ => self.tval,
+        };
 
         let mode = if self.mode == Mode::User {
             if self.csrs[MEDELEG] & (1 << ecode) != 0 {
@@ -27,17 +61,86 @@ impl Cpu {
             Mode::Machine
         };
 
+        self.enter_trap(mode, ecode, tval);
+    }
+
+    /*
+        (3.1.6.1) Interrupts for higher-privilege modes are always globally enabled, and interrupts
+        for lower-privilege modes are always globally disabled. Interrupts for the current mode are
+        enabled by the xIE bit of mstatus.
+
+        (3.1.8) An interrupt delegated by mideleg (and sideleg) traps to the lower mode instead.
+    */
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csrs[MIP] & self.csrs[MIE];
+        if pending == 0 {
+            return None;
+        }
+        let mstatus = self.csrs[MSTATUS];
+        let m_enabled = self.mode < Mode::Machine || read_bit(mstatus, MSTATUS_MIE) != 0;
+        let s_enabled = self.mode < Mode::Supervisor
+            || (self.mode == Mode::Supervisor && read_bit(mstatus, MSTATUS_SIE) != 0);
+        let u_enabled = self.mode == Mode::User && read_bit(mstatus, MSTATUS_UIE) != 0;
+
+        let mideleg = self.csrs[MIDELEG];
+        let sideleg = self.csrs[SIDELEG];
+        let m_pending = pending & !mideleg;
+        let s_pending = pending & mideleg & !sideleg;
+        let u_pending = pending & mideleg & sideleg;
+
+        // Interrupts for higher-privilege modes are taken first.
+        let pending = if m_enabled && m_pending != 0 {
+            m_pending
+        } else if s_enabled && s_pending != 0 {
+            s_pending
+        } else if u_enabled && u_pending != 0 {
+            u_pending
+        } else {
+            return None;
+        };
+        INTERRUPT_PRIORITY
+            .iter()
+            .find(|i| pending & (1 << i.exception_code()) != 0)
+            .copied()
+    }
+
+    // Takes the interrupt before executing the instruction at pc.
+    pub fn interrupt(&mut self, i: Interrupt) {
+        let code = i.exception_code();
+        let mode = if self.csrs[MIDELEG] & (1 << code) == 0 {
+            Mode::Machine
+        } else if self.csrs[SIDELEG] & (1 << code) == 0 {
+            Mode::Supervisor
+        } else {
+            Mode::User
+        };
+        self.inst_pc = self.pc;
+        self.enter_trap(mode, CAUSE_INTERRUPT | code, 0);
+    }
+
+    /*
+        (3.1.7) Machine Trap-Vector Base-Address Register
+
+        When MODE=Direct, all traps into machine mode cause the pc to be set to the address in the
+        BASE field. When MODE=Vectored, all synchronous exceptions into machine mode cause the pc to
+        be set to the address in the BASE field, whereas interrupts cause the pc to be set to the
+        address in the BASE field plus four times the interrupt cause number.
+    */
+    fn trap_vector(tvec: u32, cause: u32) -> u32 {
+        let base = tvec & !0b11;
+        match tvec & 0b11 {
+            1 if cause & CAUSE_INTERRUPT != 0 => base + 4 * (cause & !CAUSE_INTERRUPT),
+            _ => base,
+        }
+    }
+
+    fn enter_trap(&mut self, mode: Mode, cause: u32, tval: u32) {
         match mode {
             Mode::Machine => {
-                self.csrs[MEPC] = self.pc - 4;
-                self.pc = match self.csrs[MTVEC] & 0b11 {
-                    0 => self.csrs[MTVEC] & !0b11,
-                    1 => (self.csrs[MTVEC] & !0b11) + 4 * ecode,
-                    _ => panic!("unknown CSR.mtvec MODE"),
-                };
-                self.csrs[MCAUSE] = ecode;
-                // TODO: Set a correct value to mtval
-                self.csrs[MTVAL] = 0;
+                self.csrs[MEPC] = self.inst_pc;
+                self.pc = Self::trap_vector(self.csrs[MTVEC], cause);
+                self.csrs[MCAUSE] = cause;
+                self.csrs[MTVAL] = tval;
                 let mpie = read_bit(self.csrs[MSTATUS], MSTATUS_MIE);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_MIE, 0);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_MPIE, mpie);
@@ -49,15 +152,10 @@ impl Cpu {
             }
 
             Mode::Supervisor => {
-                self.csrs[SEPC] = self.pc - 4;
-                self.pc = match self.csrs[STVEC] & 0b11 {
-                    0 => self.csrs[STVEC] & !0b11,
-                    1 => (self.csrs[STVEC] & !0b11) + 4 * ecode,
-                    _ => panic!("unknown CSR.stvec MODE"),
-                };
-                self.csrs[SCAUSE] = ecode;
-                // TODO: Set a correct value to stval
-                self.csrs[STVAL] = 0;
+                self.csrs[SEPC] = self.inst_pc;
+                self.pc = Self::trap_vector(self.csrs[STVEC], cause);
+                self.csrs[SCAUSE] = cause;
+                self.csrs[STVAL] = tval;
                 let spie = read_bit(self.csrs[MSTATUS], MSTATUS_SIE);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_SIE, 0);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_SPIE, spie);
@@ -65,15 +163,10 @@ impl Cpu {
             }
 
             Mode::User => {
-                self.csrs[UEPC] = self.pc - 4;
-                self.pc = match self.csrs[UTVEC] & 0b11 {
-                    0 => self.csrs[UTVEC] & !0b11,
-                    1 => (self.csrs[UTVEC] & !0b11) + 4 * ecode,
-                    _ => panic!("unknown CSR.utvec MODE"),
-                };
-                self.csrs[UCAUSE] = ecode;
-                // TODO: Set a correct value to utval
-                self.csrs[UTVAL] = 0;
+                self.csrs[UEPC] = self.inst_pc;
+                self.pc = Self::trap_vector(self.csrs[UTVEC], cause);
+                self.csrs[UCAUSE] = cause;
+                self.csrs[UTVAL] = tval;
                 let upie = read_bit(self.csrs[MSTATUS], MSTATUS_UIE);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_UIE, 0);
                 write_bit(&mut self.csrs[MSTATUS], MSTATUS_UPIE, upie);
@@ -1,10 +1,13 @@
 use super::csr::*;
+use super::pmp::access_fault;
+use super::tlb::{TlbEntry, VPN_SHIFT};
 use crate::bits::*;
 use crate::cpu::{Cpu, Mode};
 use crate::exception::Exception;
 use crate::memory::MemOps;
 
 const SATP_SV32: u32 = 0x8000_0000;
+const SATP_ASID: u32 = 0x7FC0_0000;
 const SATP_PPN: u32 = 0x003F_FFFF;
 
 const PTE_V: u32 = 0x0000_00001;
@@ -12,10 +15,37 @@ const PTE_R: u32 = 0x0000_00002;
 const PTE_W: u32 = 0x0000_00004;
 const PTE_X: u32 = 0x0000_00008;
 const PTE_U: u32 = 0x0000_00010;
-// const PTE_G: u32 = 0x0000_00020;
-// const PTE_A: u32 = 0x0000_00040;
-// const PTE_D: u32 = 0x0000_00080;
+const PTE_G: u32 = 0x0000_00020;
+const PTE_A: u32 = 0x0000_00040;
+const PTE_D: u32 = 0x0000_00080;
 const PTE_PPN: u32 = 0xFFFF_FC00;
+const PTE_PPN0: u32 = 0x000F_FC00;
+const PTE_PPN1: u32 = 0xFFF0_0000;
+
+// Sv32
+const PAGESIZE: u64 = 4096;
+const LEVELS: u32 = 2;
+const PTESIZE: u64 = 4;
+
+fn vpn(va: u32, i: u32) -> u32 {
+    read_bits(va, 12 + 10 * i..21 + 10 * i)
+}
+
+// Sv32 produces 34-bit physical addresses, but only the lower 4 GiB can be accessed.
+fn phys_addr(pa: u64, ops: MemOps) -> Result<u32, Exception> {
+    if pa > u32::MAX as u64 {
+        return Err(access_fault(ops));
+    }
+    Ok(pa as u32)
+}
+
+// The PTE bits which must be set to access the page without updating the PTE
+fn accessed_bits(ops: MemOps) -> u32 {
+    match ops {
+        MemOps::Store => PTE_A | PTE_D,
+        _ => PTE_A,
+    }
+}
 
 fn page_fault(ops: MemOps) -> Exception {
     match ops {
@@ -26,153 +56,451 @@ fn page_fault(ops: MemOps) -> Exception {
 }
 
 impl Cpu {
-    fn walkpgdir(&self, satp: u32, va: u32, ops: MemOps) -> Result<u32, Exception> {
-        let pde_pa = (satp & SATP_PPN).wrapping_shr(12) + read_bits(va, 22..31).wrapping_shr(2);
-        let pde = self.ram.read32(pde_pa)?;
-        if pde & PTE_V == 0 {
-            return Err(page_fault(ops));
-        }
-        // 4.3.1
-        // When all R/W/X are zero, the PTE is a pointer to the next level of the page table.
-        if pde & (PTE_R | PTE_W | PTE_X) != 0 {
-            return Err(page_fault(ops));
-        }
+    /*
+        (3.6.2) Physical Memory Protection and Paging
+
+        Implicit memory accesses for page-table walks are checked by PMP as S-mode loads.
+        A PMP violation raises an access-fault exception corresponding to the original access type.
+    */
+    fn read_pte(&mut self, pa: u32, ops: MemOps) -> Result<u32, Exception> {
+        self.pmp_check(pa, 4, MemOps::Load, Mode::Supervisor)
+            .map_err(|_| access_fault(ops))?;
+        self.ram.read32(pa).map_err(|_| access_fault(ops))
+    }
 
-        let pte_pa = (pde & PTE_PPN).wrapping_shr(2) + read_bits(va, 12..21).wrapping_shr(2);
-        let pte = self.ram.read32(pte_pa)?;
+    fn write_pte(&mut self, pa: u32, pte: u32, ops: MemOps) -> Result<(), Exception> {
+        self.pmp_check(pa, 4, MemOps::Store, Mode::Supervisor)
+            .map_err(|_| access_fault(ops))?;
+        self.decode_cache.invalidate(pa, 4);
+        self.ram.write32(pa, pte).map_err(|_| access_fault(ops))
+    }
 
-        if pte & PTE_V == 0 {
-            return Err(page_fault(ops));
-        }
-        // 4.3.1
-        // U-mode software may only access the page when U=1.
-        // If the SUM bit in the sstatus register is set, supervisor mode software may also access pages with U=1.
-        if pte & PTE_U != 0
-            && self.mode == Mode::Supervisor
-            && self.csrr(SSTATUS)? & MSTATUS_SUM == 0
-        {
-            return Err(page_fault(ops));
-        }
-        if pte & PTE_U == 0 && self.mode == Mode::User {
-            return Err(page_fault(ops));
-        }
-        match ops {
-            MemOps::Load => {
-                if pte & PTE_R == 0 {
-                    return Err(page_fault(ops));
-                }
+    /*
+        (4.3.2) Virtual Address Translation Process
+    */
+    fn walkpgdir(&mut self, satp: u32, va: u32, ops: MemOps, mode: Mode) -> Result<u32, Exception> {
+        // 1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1.
+        let mut a = (satp & SATP_PPN) as u64 * PAGESIZE;
+        let mut i = LEVELS - 1;
+        // The G bit of a non-leaf PTE applies to all subsequent levels.
+        let mut global = false;
+        let (pte, pte_pa) = loop {
+            // 2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE.
+            let pte_pa = phys_addr(a + vpn(va, i) as u64 * PTESIZE, ops)?;
+            let pte = self.read_pte(pte_pa, ops)?;
+            // 3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault exception.
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(page_fault(ops));
             }
-            MemOps::Store => {
-                if pte & PTE_W == 0 {
-                    return Err(page_fault(ops));
-                }
+            global |= pte & PTE_G != 0;
+            // 4. If pte.r = 1 or pte.x = 1, go to step 5.
+            if pte & (PTE_R | PTE_X) != 0 {
+                break (pte, pte_pa);
             }
-            MemOps::Fetch => {
-                if pte & PTE_R == 0 || pte & PTE_X == 0 {
-                    return Err(page_fault(ops));
-                }
+            // Otherwise, this PTE is a pointer to the next level of the page table.
+            // Let i = i − 1. If i < 0, stop and raise a page-fault exception.
+            // Otherwise, let a = pte.ppn × PAGESIZE and go to step 2.
+            if i == 0 {
+                return Err(page_fault(ops));
             }
+            i -= 1;
+            a = (pte >> 10) as u64 * PAGESIZE;
+        };
+
+        // 5. A leaf PTE has been found.
+        self.check_permission(pte, ops, mode)?;
+
+        // 6. If i > 0 and pte.ppn[i−1:0] != 0, this is a misaligned superpage.
+        if i > 0 && pte & PTE_PPN0 != 0 {
+            return Err(page_fault(ops));
         }
 
-        Ok((pte & PTE_PPN).wrapping_shr(2) + read_bits(va, 0..11).wrapping_shr(2))
-    }
+        /*
+            7. (4.3.1) Each leaf PTE contains an accessed (A) and dirty (D) bit.
 
-    pub fn vm_fetch(&self, addr: u32) -> Result<u32, Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Fetch)?;
-            return self.ram.fetch(pa);
+            Two schemes to manage the A and D bits are permitted:
+            - When a virtual page is accessed and the A bit is clear, or is written and the D bit is clear,
+              a page-fault exception is raised.
+            - When a virtual page is accessed and the A bit is clear, or is written and the D bit is clear,
+              the implementation sets the corresponding bit(s) in the PTE.
+              The PTE update must be atomic with respect to other accesses to the PTE.
+        */
+        let accessed = accessed_bits(ops);
+        let pte = if pte & accessed != accessed {
+            if !self.svadu {
+                return Err(page_fault(ops));
+            }
+            self.write_pte(pte_pa, pte | accessed, ops)?;
+            pte | accessed
         } else {
-            return self.ram.fetch(addr);
+            pte
+        };
+
+        // 8. The translation is successful.
+        // If i > 0, then this is a superpage translation and pa.ppn[i−1:0] = va.vpn[i−1:0].
+        let pa = if i > 0 {
+            ((pte & PTE_PPN1) as u64) << 2 | read_bits(va, 0..21) as u64
+        } else {
+            ((pte & PTE_PPN) as u64) << 2 | read_bits(va, 0..11) as u64
+        };
+        let pa = phys_addr(pa, ops)?;
+
+        self.tlb.insert(
+            TlbEntry {
+                valid: true,
+                vpn: va >> VPN_SHIFT,
+                asid: (satp & SATP_ASID) >> 22,
+                global,
+                megapage: i > 0,
+                ppn: pa >> VPN_SHIFT,
+                pte,
+            },
+            matches!(ops, MemOps::Fetch),
+        );
+        Ok(pa)
+    }
+
+    /*
+        (4.3.1) Page permissions
+
+        U-mode software may only access the page when U=1.
+        If the SUM bit in the sstatus register is set, supervisor mode software may also access pages with U=1.
+        Irrespective of SUM, the supervisor may not execute code on pages with U=1.
+
+        When MXR=1, loads from pages marked either readable or executable (R=1 or X=1) will succeed.
+    */
+    fn check_permission(&self, pte: u32, ops: MemOps, mode: Mode) -> Result<(), Exception> {
+        let mstatus = self.csrr(MSTATUS)?;
+        let user_page = pte & PTE_U != 0;
+        let allowed_mode = match mode {
+            Mode::User => user_page,
+            Mode::Supervisor => {
+                !user_page || (!matches!(ops, MemOps::Fetch) && read_bit(mstatus, MSTATUS_SUM) != 0)
+            }
+            Mode::Machine => true,
+        };
+        let allowed_ops = match ops {
+            MemOps::Load => {
+                pte & PTE_R != 0 || (read_bit(mstatus, MSTATUS_MXR) != 0 && pte & PTE_X != 0)
+            }
+            MemOps::Store => pte & PTE_W != 0,
+            MemOps::Fetch => pte & PTE_X != 0,
+        };
+        if !allowed_mode || !allowed_ops {
+            return Err(page_fault(ops));
         }
+        Ok(())
     }
 
-    pub fn vm_read8(&self, addr: u32) -> Result<u32, Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Load)?;
-            return self.ram.read8(pa);
-        } else {
-            return self.ram.read8(addr);
+    /*
+        The ASID tags the TLB entries, so switching to another address space does not require a flush.
+        Otherwise the new root page table may be used with the same ASID, and all entries are flushed.
+    */
+    pub fn write_satp(&mut self, imm: u32) {
+        if (self.csrs[SATP] ^ imm) & SATP_ASID == 0 {
+            self.tlb.flush(None, None);
         }
+        self.csrs[SATP] = imm;
     }
 
-    pub fn vm_read16(&self, addr: u32) -> Result<u32, Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Load)?;
-            return self.ram.read16(pa);
-        } else {
-            return self.ram.read16(addr);
+    /*
+        (3.1.6.3) Memory Privilege
+
+        When MPRV=1, load and store memory addresses are translated and protected as though
+        the current privilege mode were set to MPP.
+        Instruction address-translation and protection are unaffected by the setting of MPRV.
+    */
+    fn effective_mode(&self, ops: MemOps) -> Mode {
+        let mstatus = self.csrs[MSTATUS];
+        if matches!(ops, MemOps::Fetch) || read_bit(mstatus, MSTATUS_MPRV) == 0 {
+            return self.mode;
+        }
+        match read_bits(mstatus, MSTATUS_MPP..MSTATUS_MPP + 1) {
+            0x0 => Mode::User,
+            0x1 => Mode::Supervisor,
+            _ => Mode::Machine,
         }
     }
 
-    pub fn vm_read32(&self, addr: u32) -> Result<u32, Exception> {
+    /*
+        Translates a virtual address and checks the physical address against PMP.
+    */
+    pub fn translate(&mut self, va: u32, size: u32, ops: MemOps) -> Result<u32, Exception> {
+        // The faulting virtual address is written to xtval if this access traps.
+        self.tval = va;
+        let mode = self.effective_mode(ops);
         let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
+        // Page-based virtual memory is in effect only in S-mode and U-mode. (4.1.11)
+        let pa = if satp & SATP_SV32 != 0 && mode != Mode::Machine {
             // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Load)?;
-            return self.ram.read32(pa);
+            let asid = (satp & SATP_ASID) >> 22;
+            match self.tlb.lookup(va, asid, matches!(ops, MemOps::Fetch)) {
+                // A cached PTE whose A/D bits would have to be updated is walked again.
+                Some(e) if e.pte & accessed_bits(ops) == accessed_bits(ops) => {
+                    self.check_permission(e.pte, ops, mode)?;
+                    e.ppn << VPN_SHIFT | read_bits(va, 0..11)
+                }
+                _ => self.walkpgdir(satp, va, ops, mode)?,
+            }
         } else {
-            return self.ram.read32(addr);
+            va
+        };
+        self.pmp_check(pa, size, ops, mode)?;
+        Ok(pa)
+    }
+
+    /*
+        Translates a virtual address for the debugger as the hart would in its current mode. Unlike
+        an access by the hart, the permissions are not checked, and the TLB, the A/D bits and xtval
+        are unchanged.
+    */
+    pub fn debug_translate(&self, va: u32) -> Option<u32> {
+        let satp = self.csrs[SATP];
+        if satp & SATP_SV32 == 0 || self.mode == Mode::Machine {
+            return Some(va);
+        }
+        let mut a = (satp & SATP_PPN) as u64 * PAGESIZE;
+        for i in (0..LEVELS).rev() {
+            let pte_pa = phys_addr(a + vpn(va, i) as u64 * PTESIZE, MemOps::Load).ok()?;
+            let b = self.ram.peek(pte_pa, 4)?;
+            let pte = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return None;
+            }
+            if pte & (PTE_R | PTE_X) != 0 {
+                let pa = if i > 0 {
+                    ((pte & PTE_PPN1) as u64) << 2 | read_bits(va, 0..21) as u64
+                } else {
+                    ((pte & PTE_PPN) as u64) << 2 | read_bits(va, 0..11) as u64
+                };
+                return phys_addr(pa, MemOps::Load).ok();
+            }
+            a = (pte >> 10) as u64 * PAGESIZE;
         }
+        None
     }
 
-    pub fn vm_read64(&self, addr: u32) -> Result<u64, Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Load)?;
-            return self.ram.read64(pa);
-        } else {
-            return self.ram.read64(addr);
+    /*
+        (1.5) Base Instruction-Length Encoding
+
+        The lowest two bits of a 32-bit instruction are 11, so the first 16-bit parcel tells whether
+        the second one has to be fetched. The two parcels of a 32-bit instruction may be on different pages.
+
+        The first parcel at addr is already translated to pa.
+    */
+    pub fn fetch_at(&mut self, addr: u32, pa: u32) -> Result<u32, Exception> {
+        let low = self.ram.fetch(pa)?;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
         }
+        let pa = self.translate(addr.wrapping_add(2), 2, MemOps::Fetch)?;
+        Ok(self.ram.fetch(pa)? << 16 | low)
+    }
+
+    pub fn vm_read8(&mut self, addr: u32) -> Result<u32, Exception> {
+        let pa = self.translate(addr, 1, MemOps::Load)?;
+        self.ram.read8(pa)
+    }
+
+    pub fn vm_read16(&mut self, addr: u32) -> Result<u32, Exception> {
+        let pa = self.translate(addr, 2, MemOps::Load)?;
+        self.ram.read16(pa)
+    }
+
+    pub fn vm_read32(&mut self, addr: u32) -> Result<u32, Exception> {
+        let pa = self.translate(addr, 4, MemOps::Load)?;
+        self.ram.read32(pa)
+    }
+
+    pub fn vm_read64(&mut self, addr: u32) -> Result<u64, Exception> {
+        let pa = self.translate(addr, 8, MemOps::Load)?;
+        self.ram.read64(pa)
     }
 
     pub fn vm_write8(&mut self, addr: u32, val: u8) -> Result<(), Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Store)?;
-            return self.ram.write8(pa, val);
-        } else {
-            return self.ram.write8(addr, val);
-        }
+        let pa = self.translate(addr, 1, MemOps::Store)?;
+        self.decode_cache.invalidate(pa, 1);
+        self.ram.write8(pa, val)
     }
 
     pub fn vm_write16(&mut self, addr: u32, val: u16) -> Result<(), Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Store)?;
-            return self.ram.write16(pa, val);
-        } else {
-            return self.ram.write16(addr, val);
-        }
+        let pa = self.translate(addr, 2, MemOps::Store)?;
+        self.decode_cache.invalidate(pa, 2);
+        self.ram.write16(pa, val)
     }
 
     pub fn vm_write32(&mut self, addr: u32, val: u32) -> Result<(), Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Store)?;
-            return self.ram.write32(pa, val);
-        } else {
-            return self.ram.write32(addr, val);
-        }
+        let pa = self.translate(addr, 4, MemOps::Store)?;
+        self.decode_cache.invalidate(pa, 4);
+        self.ram.write32(pa, val)
     }
 
     pub fn vm_write64(&mut self, addr: u32, val: u64) -> Result<(), Exception> {
-        let satp = self.csrr(SATP)?;
-        if satp & SATP_SV32 != 0 {
-            // paging on
-            let pa = self.walkpgdir(satp, addr, MemOps::Store)?;
-            return self.ram.write64(pa, val);
-        } else {
-            return self.ram.write64(addr, val);
-        }
+        let pa = self.translate(addr, 8, MemOps::Store)?;
+        self.decode_cache.invalidate(pa, 8);
+        self.ram.write64(pa, val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::DRAM_BASE;
+
+    const ROOT: u32 = DRAM_BASE + 0x10000;
+    const TABLE: u32 = DRAM_BASE + 0x11000;
+    const PAGE: u32 = DRAM_BASE + 0x20000;
+    // vpn[1] = 0x100, vpn[0] = 1
+    const VA: u32 = 0x4000_1000;
+
+    fn pte(pa: u32, flags: u32) -> u32 {
+        (pa >> 12) << 10 | flags
+    }
+
+    // A hart in S-mode whose page table maps VA to PAGE with the flags of the leaf PTE.
+    fn setup(flags: u32) -> Cpu {
+        let mut cpu = Cpu::new();
+        // PMP allows any access, or none is allowed in S-mode.
+        cpu.csrw(PMPADDR0, !0).unwrap();
+        cpu.csrw(PMPCFG0, 0x1f).unwrap();
+        cpu.csrw(SATP, SATP_SV32 | ROOT >> 12).unwrap();
+        cpu.mode = Mode::Supervisor;
+        cpu.ram
+            .write32(ROOT + vpn(VA, 1) * 4, pte(TABLE, PTE_V))
+            .unwrap();
+        cpu.ram
+            .write32(TABLE + vpn(VA, 0) * 4, pte(PAGE, flags))
+            .unwrap();
+        cpu
+    }
+
+    fn leaf(cpu: &mut Cpu) -> u32 {
+        cpu.ram.read32(TABLE + vpn(VA, 0) * 4).unwrap()
+    }
+
+    #[test]
+    fn clear_accessed_bits_fault_without_svadu() {
+        let mut cpu = setup(PTE_V | PTE_R | PTE_W);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
+
+        let mut cpu = setup(PTE_V | PTE_R | PTE_W | PTE_A);
+        assert_eq!(cpu.translate(VA + 4, 4, MemOps::Load).unwrap(), PAGE + 4);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Store),
+            Err(Exception::StoreAMOPageFault)
+        ));
+        assert_eq!(leaf(&mut cpu), pte(PAGE, PTE_V | PTE_R | PTE_W | PTE_A));
+    }
+
+    #[test]
+    fn svadu_sets_accessed_bits() {
+        let mut cpu = setup(PTE_V | PTE_R | PTE_W);
+        cpu.svadu = true;
+        assert_eq!(cpu.translate(VA, 4, MemOps::Load).unwrap(), PAGE);
+        assert_eq!(leaf(&mut cpu), pte(PAGE, PTE_V | PTE_R | PTE_W | PTE_A));
+
+        // The TLB entry cached by the load does not have D, so the store walks the table again.
+        assert_eq!(cpu.translate(VA, 4, MemOps::Store).unwrap(), PAGE);
+        assert_eq!(
+            leaf(&mut cpu),
+            pte(PAGE, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D)
+        );
+    }
+
+    // Maps VA with a leaf PTE in the root table, i.e. a 4 MiB megapage.
+    fn setup_megapage(pa: u32) -> Cpu {
+        let mut cpu = setup(0);
+        cpu.ram
+            .write32(ROOT + vpn(VA, 1) * 4, pte(pa, PTE_V | PTE_R | PTE_A))
+            .unwrap();
+        cpu
+    }
+
+    #[test]
+    fn megapage() {
+        let mega = DRAM_BASE + 0x40_0000;
+        let mut cpu = setup_megapage(mega);
+        assert_eq!(
+            cpu.translate(VA + 0x1234, 4, MemOps::Load).unwrap(),
+            mega + (VA & 0x3f_ffff) + 0x1234
+        );
+        assert_eq!(
+            cpu.translate(VA + 0x10_0000, 4, MemOps::Load).unwrap(),
+            mega + (VA & 0x3f_ffff) + 0x10_0000
+        );
+    }
+
+    #[test]
+    fn misaligned_megapage_faults() {
+        let mut cpu = setup_megapage(DRAM_BASE + 0x40_1000);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
+    }
+
+    #[test]
+    fn write_without_read_faults() {
+        let mut cpu = setup(PTE_V | PTE_W | PTE_A | PTE_D);
+        cpu.svadu = true;
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Store),
+            Err(Exception::StoreAMOPageFault)
+        ));
+        // The reserved encoding is also a fault in a non-leaf PTE.
+        let mut cpu = setup(PTE_V | PTE_R | PTE_A);
+        cpu.ram
+            .write32(ROOT + vpn(VA, 1) * 4, pte(TABLE, PTE_V | PTE_W))
+            .unwrap();
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
+    }
+
+    #[test]
+    fn mxr_makes_executable_pages_readable() {
+        let mut cpu = setup(PTE_V | PTE_X | PTE_A);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
+        cpu.csrw(MSTATUS, 1 << MSTATUS_MXR).unwrap();
+        assert_eq!(cpu.translate(VA, 4, MemOps::Load).unwrap(), PAGE);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Store),
+            Err(Exception::StoreAMOPageFault)
+        ));
+    }
+
+    #[test]
+    fn sum_permits_supervisor_access_to_user_pages() {
+        let mut cpu = setup(PTE_V | PTE_R | PTE_X | PTE_U | PTE_A);
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
+        cpu.csrw(MSTATUS, 1 << MSTATUS_SUM).unwrap();
+        assert_eq!(cpu.translate(VA, 4, MemOps::Load).unwrap(), PAGE);
+        // The supervisor may never execute code on user pages.
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Fetch),
+            Err(Exception::InstructionPageFault)
+        ));
+        cpu.mode = Mode::User;
+        assert_eq!(cpu.translate(VA, 4, MemOps::Fetch).unwrap(), PAGE);
+    }
+
+    #[test]
+    fn user_mode_cannot_access_supervisor_pages() {
+        let mut cpu = setup(PTE_V | PTE_R | PTE_A);
+        cpu.mode = Mode::User;
+        assert!(matches!(
+            cpu.translate(VA, 4, MemOps::Load),
+            Err(Exception::LoadPageFault)
+        ));
     }
 }
@@ -0,0 +1,28 @@
+mod chardev;
+mod clint;
+mod framebuffer;
+mod netdev;
+mod plic;
+mod rtc;
+mod sifive_test;
+mod uart;
+mod usernet;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_net;
+mod virtio_rng;
+
+pub use chardev::Chardev;
+pub use clint::Clint;
+pub use framebuffer::{Framebuffer, PixelFormat};
+pub use netdev::Netdev;
+pub use plic::{Plic, NUM_SOURCES as PLIC_NUM_SOURCES};
+pub use rtc::Rtc;
+pub use sifive_test::{Finish, SifiveTest};
+pub use uart::Uart;
+pub use virtio::{VirtioDevice, VirtioMmio};
+pub use virtio_blk::VirtioBlk;
+pub use virtio_console::VirtioConsole;
+pub use virtio_net::VirtioNet;
+pub use virtio_rng::VirtioRng;
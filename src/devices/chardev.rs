@@ -0,0 +1,116 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::replay::{self, Source};
+
+/*
+    Host backend of a character device such as a console.
+
+    stdio          stdin and stdout
+    file:<path>    Output is written to the file. There is no input.
+    pipe:<path>    Input is read from <path>.in and output is written to <path>.out, which are
+                   usually named pipes (FIFOs).
+    unix:<path>    Listens on a Unix domain socket. Output is discarded while no client is connected.
+
+    Input is read by a background thread, so reading never blocks the emulation.
+*/
+pub struct Chardev {
+    input: Option<Receiver<u8>>,
+    output: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+// Sends everything read from the reader to the channel until EOF.
+fn forward<R: Read>(mut reader: R, tx: &Sender<u8>) {
+    let mut buf = [0; 64];
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 || buf[..n].iter().any(|&b| tx.send(b).is_err()) {
+            break;
+        }
+    }
+}
+
+impl Chardev {
+    fn new(output: Option<Box<dyn Write + Send>>) -> Self {
+        Self {
+            input: None,
+            output: Arc::new(Mutex::new(output)),
+        }
+    }
+
+    pub fn stdio() -> Self {
+        let mut chardev = Self::new(Some(Box::new(io::stdout())));
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || forward(io::stdin(), &tx));
+        chardev.input = Some(rx);
+        chardev
+    }
+
+    pub fn open(spec: &str) -> io::Result<Self> {
+        let (kind, path) = spec.split_once(':').unwrap_or((spec, ""));
+        match kind {
+            "stdio" => Ok(Self::stdio()),
+            "file" => Ok(Self::new(Some(Box::new(File::create(path)?)))),
+            "pipe" => {
+                // Opening a FIFO blocks until the other end is opened, so the input is opened
+                // by the reader thread.
+                let output = OpenOptions::new()
+                    .write(true)
+                    .open(format!("{}.out", path))?;
+                let mut chardev = Self::new(Some(Box::new(output)));
+                let input = format!("{}.in", path);
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    if let Ok(file) = File::open(input) {
+                        forward(file, &tx);
+                    }
+                });
+                chardev.input = Some(rx);
+                Ok(chardev)
+            }
+            "unix" => {
+                let listener = UnixListener::bind(path)?;
+                let mut chardev = Self::new(None);
+                let output = chardev.output.clone();
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    // Serves one client at a time.
+                    for stream in listener.incoming().flatten() {
+                        if let Ok(writer) = stream.try_clone() {
+                            *output.lock().unwrap() = Some(Box::new(writer));
+                        }
+                        forward(stream, &tx);
+                        *output.lock().unwrap() = None;
+                    }
+                });
+                chardev.input = Some(rx);
+                Ok(chardev)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown character device: {}", spec),
+            )),
+        }
+    }
+
+    // Returns the input received so far.
+    pub fn read(&self) -> Vec<u8> {
+        replay::bytes(Source::Chardev, || match &self.input {
+            Some(input) => input.try_iter().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        if replay::muted() {
+            return;
+        }
+        if let Some(output) = self.output.lock().unwrap().as_mut() {
+            output.write_all(data).ok();
+            output.flush().ok();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+use std::time::Instant;
+
+use crate::dtb::TIMEBASE_FREQUENCY;
+use crate::replay::{self, Source};
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    Core Local Interruptor (SiFive CLINT)
+
+    0x0000  msip      Machine software interrupt pending of hart 0 (bit 0)
+    0x4000  mtimecmp  Timer compare register of hart 0 (64 bits)
+    0xBFF8  mtime     Real-time counter (64 bits)
+
+    A machine timer interrupt is pending while mtime >= mtimecmp.
+
+    mtime follows the host clock, or in deterministic mode, advances by one every instruction, as
+    if the hart ran 10 million instructions per second.
+*/
+const MSIP: u32 = 0x0;
+const MTIMECMP: u32 = 0x4000;
+const MTIMECMP_HI: u32 = 0x4004;
+const MTIME: u32 = 0xBFF8;
+const MTIME_HI: u32 = 0xBFFC;
+
+// mtime is updated from the host clock every this many ticks.
+const CLOCK_INTERVAL: u32 = 256;
+
+pub struct Clint {
+    pub msip: u32,
+    pub mtimecmp: u64,
+    pub mtime: u64,
+    start: Instant,
+    // mtime at `start`. Writing mtime moves it.
+    base: u64,
+    ticks: u32,
+    pub deterministic: bool,
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: 0,
+            mtimecmp: u64::MAX,
+            mtime: 0,
+            start: Instant::now(),
+            base: 0,
+            ticks: 0,
+            deterministic: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self {
+            deterministic: self.deterministic,
+            ..Self::new()
+        };
+    }
+
+    // Called once per instruction.
+    pub fn tick(&mut self) {
+        if self.deterministic {
+            self.mtime = self.mtime.wrapping_add(1);
+            return;
+        }
+        self.ticks += 1;
+        if self.ticks == CLOCK_INTERVAL {
+            self.ticks = 0;
+            self.update_mtime();
+        }
+    }
+
+    fn update_mtime(&mut self) {
+        if self.deterministic {
+            return;
+        }
+        let replaying = replay::replaying();
+        let mtime = replay::value(Source::Clock, || {
+            let elapsed = self.start.elapsed().as_nanos() as u64;
+            self.base + elapsed * TIMEBASE_FREQUENCY as u64 / 1_000_000_000
+        });
+        // The host clock continues from the recorded time after the end of the log.
+        if replaying {
+            self.set_mtime(mtime);
+        } else {
+            self.mtime = mtime;
+        }
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.start = Instant::now();
+        self.base = mtime;
+        self.mtime = mtime;
+    }
+
+    pub fn software_interrupt(&self) -> bool {
+        self.msip & 1 != 0
+    }
+
+    pub fn timer_interrupt(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            MSIP => self.msip,
+            MTIMECMP => self.mtimecmp as u32,
+            MTIMECMP_HI => (self.mtimecmp >> 32) as u32,
+            MTIME => {
+                self.update_mtime();
+                self.mtime as u32
+            }
+            MTIME_HI => (self.mtime >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, val: u32) {
+        match offset {
+            MSIP => self.msip = val & 1,
+            MTIMECMP => self.mtimecmp = self.mtimecmp & !0xFFFF_FFFF | val as u64,
+            MTIMECMP_HI => self.mtimecmp = self.mtimecmp & 0xFFFF_FFFF | (val as u64) << 32,
+            MTIME => self.set_mtime(self.mtime & !0xFFFF_FFFF | val as u64),
+            MTIME_HI => self.set_mtime(self.mtime & 0xFFFF_FFFF | (val as u64) << 32),
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for Clint {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.msip);
+        w.u64(self.mtimecmp);
+        w.u64(self.mtime);
+        w.u32(self.ticks);
+    }
+
+    // mtime continues from the saved value.
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.msip = r.u32()?;
+        self.mtimecmp = r.u64()?;
+        self.set_mtime(r.u64()?);
+        self.ticks = r.u32()?;
+        Ok(())
+    }
+}
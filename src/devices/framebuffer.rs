@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::dtb::TIMEBASE_FREQUENCY;
+use crate::png;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    Linear framebuffer
+
+    The pixels are in a separate memory region (VRAM) which is described by a simple-framebuffer
+    node in the device tree. The control registers are:
+
+    0x00  WIDTH       Width in pixels (read-only)
+    0x04  HEIGHT      Height in pixels (read-only)
+    0x08  STRIDE      Bytes per line (read-only)
+    0x0c  FORMAT      Bytes per pixel (read-only)
+    0x10  FRAME       Number of frames displayed so far (read-only)
+    0x14  SCREENSHOT  Writing any value saves a screenshot of the current frame.
+
+    There is no host display. The frames are counted at FRAME_RATE per second of emulated time
+    (mtime), and screenshots are written as PPM or PNG files.
+*/
+const WIDTH: u32 = 0x00;
+const HEIGHT: u32 = 0x04;
+const STRIDE: u32 = 0x08;
+const FORMAT: u32 = 0x0c;
+const FRAME: u32 = 0x10;
+const SCREENSHOT: u32 = 0x14;
+
+const FRAME_RATE: u64 = 60;
+const FRAME_INTERVAL: u64 = TIMEBASE_FREQUENCY as u64 / FRAME_RATE;
+
+// The frame is checked every this many ticks.
+const POLL_INTERVAL: u32 = 1024;
+
+// The pixel formats of simple-framebuffer. The components are listed from the most significant
+// bits of a little-endian pixel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "r5g6b5" => Some(Self::R5G6B5),
+            "r8g8b8" => Some(Self::R8G8B8),
+            "x8r8g8b8" => Some(Self::X8R8G8B8),
+            "a8r8g8b8" => Some(Self::A8R8G8B8),
+            "a8b8g8r8" => Some(Self::A8B8G8R8),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::R5G6B5 => "r5g6b5",
+            Self::R8G8B8 => "r8g8b8",
+            Self::X8R8G8B8 => "x8r8g8b8",
+            Self::A8R8G8B8 => "a8r8g8b8",
+            Self::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        match self {
+            Self::R5G6B5 => 2,
+            Self::R8G8B8 => 3,
+            Self::X8R8G8B8 | Self::A8R8G8B8 | Self::A8B8G8R8 => 4,
+        }
+    }
+
+    fn rgb(&self, p: &[u8]) -> [u8; 3] {
+        match self {
+            Self::R5G6B5 => {
+                let p = u16::from_le_bytes([p[0], p[1]]);
+                // Expands the components to 8 bits by repeating the high bits.
+                let (r, g, b) = ((p >> 11) as u8, (p >> 5 & 0x3F) as u8, (p & 0x1F) as u8);
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+            Self::R8G8B8 | Self::X8R8G8B8 | Self::A8R8G8B8 => [p[2], p[1], p[0]],
+            Self::A8B8G8R8 => [p[0], p[1], p[2]],
+        }
+    }
+}
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub vram: Vec<u8>,
+    // Screenshots are written to this file at exit, and numbered ones next to it, e.g.
+    // screen-000060.png for screen.png.
+    pub output: Option<String>,
+    // Saves a screenshot every this many frames, or never if 0.
+    pub every: u32,
+    frame: u32,
+    // mtime at which the next frame starts.
+    next_frame: u64,
+    ticks: u32,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        let size = (width * height * format.bytes_per_pixel()) as usize;
+        Self {
+            width,
+            height,
+            format,
+            vram: vec![0; size],
+            output: None,
+            every: 0,
+            frame: 0,
+            next_frame: FRAME_INTERVAL,
+            ticks: 0,
+        }
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    // mtime restarts from 0. The frame count and the pixels are kept.
+    pub fn reset(&mut self) {
+        self.next_frame = FRAME_INTERVAL;
+    }
+
+    // Called once per instruction with the current mtime.
+    pub fn tick(&mut self, mtime: u64) {
+        self.ticks += 1;
+        if self.ticks < POLL_INTERVAL {
+            return;
+        }
+        self.ticks = 0;
+        let mut save = false;
+        while mtime >= self.next_frame {
+            self.frame += 1;
+            self.next_frame += FRAME_INTERVAL;
+            save |= self.every != 0 && self.frame.is_multiple_of(self.every);
+        }
+        if save {
+            self.save_frame();
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        match offset {
+            WIDTH => self.width,
+            HEIGHT => self.height,
+            STRIDE => self.stride(),
+            FORMAT => self.format.bytes_per_pixel(),
+            FRAME => self.frame,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, _val: u32) {
+        if offset == SCREENSHOT {
+            self.save_frame();
+        }
+    }
+
+    // The pixels in 8-bit RGB.
+    fn rgb(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel() as usize;
+        self.vram
+            .chunks(bpp)
+            .flat_map(|p| self.format.rgb(p))
+            .collect()
+    }
+
+    // Writes a PNG file if the path ends with .png, otherwise a binary PPM (P6) file.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let rgb = self.rgb();
+        let image = if path.ends_with(".png") {
+            png::encode(self.width, self.height, &rgb)
+        } else {
+            let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+            ppm.extend_from_slice(&rgb);
+            ppm
+        };
+        fs::write(path, image)
+    }
+
+    // Saves a screenshot numbered by the frame next to the output file.
+    fn save_frame(&self) {
+        let output = match &self.output {
+            Some(output) => Path::new(output),
+            None => return,
+        };
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match output.extension() {
+            Some(ext) => format!("{}-{:06}.{}", stem, self.frame, ext.to_string_lossy()),
+            None => format!("{}-{:06}", stem, self.frame),
+        };
+        let path = output.with_file_name(name);
+        if let Err(e) = self.save(&path.to_string_lossy()) {
+            eprintln!("{}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Snapshot for Framebuffer {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.frame);
+        w.u64(self.next_frame);
+        w.u32(self.ticks);
+        w.sparse(&self.vram);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.frame = r.u32()?;
+        self.next_frame = r.u64()?;
+        self.ticks = r.u32()?;
+        r.sparse(&mut self.vram)
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::usernet::UserNet;
+use crate::replay::{self, Source};
+
+/*
+    Host backend of a network device, given as <backend>[,pcap=<file>].
+
+    user           The built-in user-mode network stack (see usernet.rs).
+    unix:<path>    Listens on a Unix domain socket. Each frame is preceded by its length (be32),
+                   like the stream netdev of QEMU. Frames are dropped while no peer is connected.
+    pcap:<path>    Writes the frames from the guest to a pcap file. Nothing is received.
+
+    With pcap=<file>, the frames in both directions are also captured to the file.
+*/
+enum Backend {
+    User(UserNet),
+    Unix {
+        input: Receiver<Vec<u8>>,
+        output: Arc<Mutex<Option<UnixStream>>>,
+    },
+    Sink,
+}
+
+pub struct Netdev {
+    backend: Backend,
+    capture: Option<Pcap>,
+}
+
+impl Netdev {
+    pub fn open(spec: &str) -> io::Result<Self> {
+        let mut options = spec.split(',');
+        let backend = options.next().unwrap_or_default();
+        let mut capture = None;
+        for option in options {
+            match option.split_once('=') {
+                Some(("pcap", path)) => capture = Some(Pcap::create(path)?),
+                _ => return Err(invalid(spec)),
+            }
+        }
+        let (kind, path) = backend.split_once(':').unwrap_or((backend, ""));
+        let backend = match kind {
+            "user" => Backend::User(UserNet::new()),
+            "unix" => listen(path)?,
+            "pcap" => {
+                capture = Some(Pcap::create(path)?);
+                Backend::Sink
+            }
+            _ => return Err(invalid(spec)),
+        };
+        Ok(Self { backend, capture })
+    }
+
+    // Sends a frame from the guest.
+    pub fn send(&mut self, frame: &[u8]) {
+        if replay::muted() {
+            return;
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.write(frame);
+        }
+        match &mut self.backend {
+            Backend::User(net) => net.send(frame),
+            Backend::Unix { output, .. } => {
+                let mut output = output.lock().unwrap();
+                if let Some(stream) = output.as_mut() {
+                    let len = (frame.len() as u32).to_be_bytes();
+                    if stream.write_all(&len).and(stream.write_all(frame)).is_err() {
+                        *output = None;
+                    }
+                }
+            }
+            Backend::Sink => {}
+        }
+    }
+
+    // Returns the frames received so far.
+    pub fn recv(&mut self) -> Vec<Vec<u8>> {
+        replay::packets(Source::Net, || {
+            let frames = match &mut self.backend {
+                Backend::User(net) => net.recv(),
+                Backend::Unix { input, .. } => input.try_iter().collect(),
+                Backend::Sink => Vec::new(),
+            };
+            if let Some(capture) = &mut self.capture {
+                for frame in &frames {
+                    capture.write(frame);
+                }
+            }
+            frames
+        })
+    }
+}
+
+fn invalid(spec: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unknown network backend: {}", spec),
+    )
+}
+
+fn listen(path: &str) -> io::Result<Backend> {
+    let listener = UnixListener::bind(path)?;
+    let output = Arc::new(Mutex::new(None));
+    let (tx, rx) = mpsc::channel();
+    let connected = output.clone();
+    thread::spawn(move || {
+        // Serves one peer at a time.
+        for mut stream in listener.incoming().flatten() {
+            *connected.lock().unwrap() = stream.try_clone().ok();
+            let mut len = [0; 4];
+            while stream.read_exact(&mut len).is_ok() {
+                let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut frame).is_err() || tx.send(frame).is_err() {
+                    break;
+                }
+            }
+            *connected.lock().unwrap() = None;
+        }
+    });
+    Ok(Backend::Unix { input: rx, output })
+}
+
+/*
+    A capture file in the libpcap format.
+
+    File header:   magic: 0xa1b2c3d4, version: 2.4, thiszone: 0, sigfigs: 0, snaplen, network
+    Packet header: ts_sec, ts_usec, incl_len, orig_len
+*/
+struct Pcap(File);
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+impl Pcap {
+    fn create(path: &str) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self(file))
+    }
+
+    fn write(&mut self, frame: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = frame.len() as u32;
+        let mut record = Vec::new();
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&len.min(PCAP_SNAPLEN).to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&frame[..len.min(PCAP_SNAPLEN) as usize]);
+        self.0.write_all(&record).ok();
+    }
+}
@@ -0,0 +1,219 @@
+use std::io;
+
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    Platform-Level Interrupt Controller (RISC-V PLIC Specification)
+
+    0x000000  Priority of source 1..NUM_SOURCES (source 0 does not exist)
+    0x001000  Pending bits
+    0x002000  Enable bits of context 0, and 0x80 bytes per context
+    0x200000  Priority threshold of context 0, and 0x1000 bytes per context
+    0x200004  Claim/complete of context 0
+
+    Context 0 is the M-mode and context 1 is the S-mode external interrupt of hart 0.
+
+    Sources are level-triggered. A source becomes pending while its line is high, and stays
+    claimed, without becoming pending again, until the handler writes its ID to claim/complete.
+*/
+pub const NUM_SOURCES: usize = 96;
+const NUM_CONTEXTS: usize = 2;
+const WORDS: usize = NUM_SOURCES / 32;
+
+const PRIORITY_BASE: u32 = 0x0;
+const PENDING_BASE: u32 = 0x1000;
+const ENABLE_BASE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT_BASE: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+// The maximum priority is 7.
+const PRIORITY_MASK: u32 = 0b111;
+
+pub struct Plic {
+    priority: [u32; NUM_SOURCES],
+    level: [u32; WORDS],
+    pending: [u32; WORDS],
+    claimed: [u32; WORDS],
+    enable: [[u32; WORDS]; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS],
+    // Whether each context has an interrupt to claim, recomputed on every change of the state.
+    notify: [bool; NUM_CONTEXTS],
+}
+
+fn get(bits: &[u32; WORDS], id: usize) -> bool {
+    bits[id / 32] & (1 << (id % 32)) != 0
+}
+
+fn set(bits: &mut [u32; WORDS], id: usize, val: bool) {
+    if val {
+        bits[id / 32] |= 1 << (id % 32);
+    } else {
+        bits[id / 32] &= !(1 << (id % 32));
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; NUM_SOURCES],
+            level: [0; WORDS],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            enable: [[0; WORDS]; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS],
+            notify: [false; NUM_CONTEXTS],
+        }
+    }
+
+    // Sets the interrupt line of the source.
+    pub fn set_irq(&mut self, id: usize, level: bool) {
+        if get(&self.level, id) == level {
+            return;
+        }
+        set(&mut self.level, id, level);
+        if level && !get(&self.claimed, id) {
+            set(&mut self.pending, id, true);
+        }
+        self.update();
+    }
+
+    // Whether the external interrupt of the context is pending.
+    pub fn interrupt(&self, context: usize) -> bool {
+        self.notify[context]
+    }
+
+    // The pending and enabled source with the highest priority above the threshold.
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best = None;
+        let mut max = self.threshold[context];
+        for id in 1..NUM_SOURCES {
+            if get(&self.pending, id) && get(&self.enable[context], id) && self.priority[id] > max {
+                max = self.priority[id];
+                best = Some(id);
+            }
+        }
+        best
+    }
+
+    fn update(&mut self) {
+        for context in 0..NUM_CONTEXTS {
+            self.notify[context] = self.best(context).is_some();
+        }
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(id) => {
+                set(&mut self.pending, id, false);
+                set(&mut self.claimed, id, true);
+                self.update();
+                id as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, id: usize) {
+        if id >= NUM_SOURCES {
+            return;
+        }
+        set(&mut self.claimed, id, false);
+        if get(&self.level, id) {
+            set(&mut self.pending, id, true);
+        }
+        self.update();
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            PRIORITY_BASE..=0xFFF => {
+                let id = (offset / 4) as usize;
+                self.priority.get(id).copied().unwrap_or(0)
+            }
+            PENDING_BASE..=0x1FFF => {
+                let word = ((offset - PENDING_BASE) / 4) as usize;
+                self.pending.get(word).copied().unwrap_or(0)
+            }
+            ENABLE_BASE..=0x1F_FFFF => {
+                let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+                match self.enable.get(context) {
+                    Some(enable) => enable.get(word).copied().unwrap_or(0),
+                    None => 0,
+                }
+            }
+            _ if offset >= CONTEXT_BASE => {
+                let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                if context >= NUM_CONTEXTS {
+                    return 0;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, val: u32) {
+        match offset {
+            PRIORITY_BASE..=0xFFF => {
+                let id = (offset / 4) as usize;
+                if id > 0 && id < NUM_SOURCES {
+                    self.priority[id] = val & PRIORITY_MASK;
+                }
+            }
+            ENABLE_BASE..=0x1F_FFFF => {
+                let context = ((offset - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE_BASE) % ENABLE_STRIDE / 4) as usize;
+                if context < NUM_CONTEXTS && word < WORDS {
+                    // Source 0 does not exist.
+                    self.enable[context][word] = if word == 0 { val & !1 } else { val };
+                }
+            }
+            _ if offset >= CONTEXT_BASE => {
+                let context = ((offset - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+                if context >= NUM_CONTEXTS {
+                    return;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = val & PRIORITY_MASK,
+                    4 => self.complete(val as usize),
+                    _ => {}
+                }
+            }
+            // The pending bits are read-only.
+            _ => {}
+        }
+        self.update();
+    }
+}
+
+impl Snapshot for Plic {
+    fn save(&self, w: &mut Writer) {
+        w.u32s(&self.priority);
+        w.u32s(&self.level);
+        w.u32s(&self.pending);
+        w.u32s(&self.claimed);
+        for enable in &self.enable {
+            w.u32s(enable);
+        }
+        w.u32s(&self.threshold);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.u32s(&mut self.priority)?;
+        r.u32s(&mut self.level)?;
+        r.u32s(&mut self.pending)?;
+        r.u32s(&mut self.claimed)?;
+        for enable in self.enable.iter_mut() {
+            r.u32s(enable)?;
+        }
+        r.u32s(&mut self.threshold)?;
+        self.update();
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dtb::TIMEBASE_FREQUENCY;
+use crate::replay::{self, Source};
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    Goldfish RTC (the real-time clock of QEMU virt)
+
+    0x00  TIME_LOW         Nanoseconds since the Unix epoch. Reading it latches TIME_HIGH.
+    0x04  TIME_HIGH
+    0x08  ALARM_LOW        Writing it arms the alarm at ALARM_HIGH:ALARM_LOW.
+    0x0c  ALARM_HIGH
+    0x10  IRQ_ENABLED
+    0x14  CLEAR_ALARM      Disarms the alarm.
+    0x18  ALARM_STATUS     Whether the alarm is armed.
+    0x1c  CLEAR_INTERRUPT
+
+    The clock is the host time, or a fixed epoch plus the emulated time elapsed, which is mtime.
+    Writing TIME_HIGH and then TIME_LOW sets the clock.
+*/
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// The alarm is checked every this many ticks.
+const POLL_INTERVAL: u32 = 1024;
+
+pub struct Rtc {
+    // The time at mtime 0 in nanoseconds, or None for the host time.
+    epoch: Option<u64>,
+    // Added to the clock when the driver sets the time.
+    offset: u64,
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+    ticks: u32,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            epoch: None,
+            offset: 0,
+            time_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+            ticks: 0,
+        }
+    }
+
+    // Starts the clock at the seconds since the Unix epoch and advances it by the emulated time,
+    // so the guest sees the same time on every run.
+    pub fn set_epoch(&mut self, seconds: u64) {
+        self.epoch = Some(seconds * NANOS_PER_SEC);
+    }
+
+    // The clock keeps running across a reset of the machine, which restarts mtime from 0. The
+    // elapsed time is added to the offset, which also holds the time set by the guest.
+    pub fn reset(&mut self, mtime: u64) {
+        if self.epoch.is_some() {
+            let elapsed = self.now(mtime).wrapping_sub(self.now(0));
+            self.offset = self.offset.wrapping_add(elapsed);
+        }
+        self.alarm = None;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+
+    fn now(&self, mtime: u64) -> u64 {
+        let time = match self.epoch {
+            Some(epoch) => {
+                let elapsed = mtime as u128 * NANOS_PER_SEC as u128 / TIMEBASE_FREQUENCY as u128;
+                epoch.wrapping_add(elapsed as u64)
+            }
+            None => replay::value(Source::Rtc, || {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64)
+            }),
+        };
+        time.wrapping_add(self.offset)
+    }
+
+    // Called once per instruction with the current mtime.
+    pub fn tick(&mut self, mtime: u64) {
+        self.ticks += 1;
+        if self.ticks < POLL_INTERVAL {
+            return;
+        }
+        self.ticks = 0;
+        self.check_alarm(mtime);
+    }
+
+    fn check_alarm(&mut self, mtime: u64) {
+        if let Some(alarm) = self.alarm {
+            if self.now(mtime) >= alarm {
+                self.alarm = None;
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    pub fn read(&mut self, offset: u32, mtime: u64) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let now = self.now(mtime);
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            ALARM_HIGH => self.alarm_high,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, val: u32, mtime: u64) {
+        match offset {
+            TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | val as u64;
+                let now = self.now(mtime).wrapping_sub(self.offset);
+                self.offset = time.wrapping_sub(now);
+            }
+            TIME_HIGH => self.time_high = val,
+            ALARM_LOW => {
+                // An alarm in the past fires immediately.
+                self.alarm = Some((self.alarm_high as u64) << 32 | val as u64);
+                self.check_alarm(mtime);
+            }
+            ALARM_HIGH => self.alarm_high = val,
+            IRQ_ENABLED => self.irq_enabled = val & 1 != 0,
+            CLEAR_ALARM => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {}
+        }
+    }
+}
+
+// The epoch comes from the command line. With the host time, the clock jumps to the current time.
+impl Snapshot for Rtc {
+    fn save(&self, w: &mut Writer) {
+        w.u64(self.offset);
+        w.u32(self.time_high);
+        w.u32(self.alarm_high);
+        w.option_u64(self.alarm);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_pending);
+        w.u32(self.ticks);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.offset = r.u64()?;
+        self.time_high = r.u32()?;
+        self.alarm_high = r.u32()?;
+        self.alarm = r.option_u64()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.ticks = r.u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+/*
+    SiFive test finisher (sifive_test of QEMU virt)
+
+    0x0  Writing FINISHER_PASS powers off the machine with success, FINISHER_FAIL with the exit
+         code in the upper 16 bits, and FINISHER_RESET resets the machine.
+
+    Linux uses it through the syscon-poweroff and syscon-reboot drivers.
+*/
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Finish {
+    Pass,
+    Fail(u16),
+    Reset,
+}
+
+pub struct SifiveTest {
+    // The request of the guest, which stops the hart.
+    pub finish: Option<Finish>,
+}
+
+impl SifiveTest {
+    pub fn new() -> Self {
+        Self { finish: None }
+    }
+
+    pub fn read(&self, _offset: u32) -> u32 {
+        0
+    }
+
+    pub fn write(&mut self, offset: u32, val: u32) {
+        if offset != 0 {
+            return;
+        }
+        self.finish = match val & 0xFFFF {
+            FINISHER_FAIL => Some(Finish::Fail((val >> 16) as u16)),
+            FINISHER_PASS => Some(Finish::Pass),
+            FINISHER_RESET => Some(Finish::Reset),
+            _ => return,
+        };
+    }
+}
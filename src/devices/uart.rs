@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::chardev::Chardev;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    NS16550A UART
+
+    0  RBR (read) / THR (write) / DLL (DLAB=1)  Receiver buffer / Transmitter holding register
+    1  IER / DLM (DLAB=1)                        Interrupt enable register
+    2  IIR (read) / FCR (write)                  Interrupt identification / FIFO control register
+    3  LCR                                       Line control register
+    4  MCR                                       Modem control register
+    5  LSR                                       Line status register
+    6  MSR                                       Modem status register
+    7  SCR                                       Scratch register
+
+    Transmitted and received bytes go through the connected character device, usually stdio.
+    Transmission finishes immediately, so the transmitter holding register is always empty.
+*/
+const RBR: u32 = 0;
+const THR: u32 = 0;
+const IER: u32 = 1;
+const IIR: u32 = 2;
+const FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+// Received data available interrupt
+const IER_ERBFI: u8 = 0b1;
+// Transmitter holding register empty interrupt
+const IER_ETBEI: u8 = 0b10;
+
+const IIR_NO_INTERRUPT: u8 = 0b0001;
+const IIR_THR_EMPTY: u8 = 0b0010;
+const IIR_RX_DATA: u8 = 0b0100;
+const IIR_FIFO_ENABLED: u8 = 0b1100_0000;
+
+const FCR_FIFO_ENABLE: u8 = 0b1;
+const FCR_RX_RESET: u8 = 0b10;
+
+const LCR_DLAB: u8 = 0b1000_0000;
+
+const LSR_DATA_READY: u8 = 0b1;
+const LSR_THR_EMPTY: u8 = 0b10_0000;
+const LSR_TX_EMPTY: u8 = 0b100_0000;
+
+// Carrier detect, data set ready and clear to send.
+const MSR_CONNECTED: u8 = 0b1011_0000;
+
+// The input is polled every this many ticks.
+const POLL_INTERVAL: u32 = 1024;
+
+pub struct Uart {
+    rx: VecDeque<u8>,
+    chardev: Option<Chardev>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    // The THR empty interrupt is cleared by reading IIR or writing THR.
+    thre_pending: bool,
+    ticks: u32,
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            chardev: None,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+            ticks: 0,
+        }
+    }
+
+    // Resets the registers. The character device stays connected.
+    pub fn reset(&mut self) {
+        *self = Self {
+            chardev: self.chardev.take(),
+            ..Self::new()
+        };
+    }
+
+    pub fn connect(&mut self, chardev: Chardev) {
+        self.chardev = Some(chardev);
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks < POLL_INTERVAL {
+            return;
+        }
+        self.ticks = 0;
+        if let Some(chardev) = &self.chardev {
+            self.rx.extend(chardev.read());
+        }
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_ERBFI != 0 && !self.rx.is_empty() {
+            IIR_RX_DATA
+        } else if self.ier & IER_ETBEI != 0 && self.thre_pending {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+
+    pub fn read(&mut self, offset: u32) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let val = match offset {
+            RBR if dlab => self.dll,
+            RBR => self.rx.pop_front().unwrap_or(0),
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let id = self.interrupt_id();
+                if id == IIR_THR_EMPTY {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                LSR_THR_EMPTY | LSR_TX_EMPTY | ready
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        };
+        val as u32
+    }
+
+    pub fn write(&mut self, offset: u32, val: u32) {
+        let val = val as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            THR if dlab => self.dll = val,
+            THR => {
+                if let Some(chardev) = &self.chardev {
+                    chardev.write(&[val]);
+                }
+                self.thre_pending = true;
+            }
+            IER if dlab => self.dlm = val,
+            IER => {
+                // Enabling the THR empty interrupt raises it as THR is empty.
+                if val & IER_ETBEI != 0 && self.ier & IER_ETBEI == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = val & 0x0F;
+            }
+            FCR => {
+                if val & FCR_RX_RESET != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = val;
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val,
+            SCR => self.scr = val,
+            _ => {}
+        }
+    }
+}
+
+// The received bytes which the driver has not read yet are saved as well.
+impl Snapshot for Uart {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.rx.iter().copied().collect::<Vec<u8>>());
+        for reg in [
+            self.ier, self.fcr, self.lcr, self.mcr, self.scr, self.dll, self.dlm,
+        ] {
+            w.u8(reg);
+        }
+        w.bool(self.thre_pending);
+        w.u32(self.ticks);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.rx = r.bytes()?.into();
+        for reg in [
+            &mut self.ier,
+            &mut self.fcr,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            *reg = r.u8()?;
+        }
+        self.thre_pending = r.bool()?;
+        self.ticks = r.u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,459 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/*
+    A minimal user-mode network stack, which plays the gateway of the guest like QEMU user
+    networking without a real network.
+
+    10.0.2.2      The gateway. It answers ARP requests and ICMP echo requests, echoes UDP
+                  datagrams sent to port 7 (RFC 862), and forwards TCP connections to the same
+                  port of 127.0.0.1 on the host.
+
+    The guest address isn't assigned by the stack, so the guest configures one itself, usually
+    10.0.2.15/24. Frames are never lost between the guest and the stack, so TCP doesn't
+    retransmit.
+*/
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPV4_HEADER_SIZE: usize = 20;
+// Don't fragment
+const IPV4_DF: u16 = 0x4000;
+// More fragments and the fragment offset
+const IPV4_FRAGMENT: u16 = 0x3FFF;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const UDP_ECHO_PORT: u16 = 7;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_HEADER_SIZE: usize = 20;
+// The maximum segment size for the 1500-byte MTU of Ethernet.
+const TCP_MSS: usize = 1460;
+// The receive window advertised to the guest, without window scaling.
+const TCP_WINDOW: u16 = 0xFFFF;
+const TCP_OPTION_MSS: u8 = 2;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+// (RFC 1071) The 16-bit ones' complement of the ones' complement sum.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for word in chunk.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Sequence numbers wrap around, so they are compared by their distance.
+fn seq_le(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) >= 0
+}
+
+fn ethernet(dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = dst.to_vec();
+    frame.extend_from_slice(&GATEWAY_MAC);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn ipv4(dst: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; IPV4_HEADER_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet[6..8].copy_from_slice(&IPV4_DF.to_be_bytes());
+    packet[8] = 64;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&GATEWAY_IP);
+    packet[16..20].copy_from_slice(&dst);
+    let sum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// The checksum of UDP and TCP, which covers a pseudo header of the IP addresses.
+fn transport_checksum(dst: [u8; 4], protocol: u8, segment: &[u8]) -> u16 {
+    let len = (segment.len() as u16).to_be_bytes();
+    let pseudo = [0, protocol, len[0], len[1]];
+    checksum(&[&GATEWAY_IP, &dst, &pseudo, segment])
+}
+
+// A TCP connection from the guest, forwarded to the host.
+struct TcpConnection {
+    guest_ip: [u8; 4],
+    guest_port: u16,
+    port: u16,
+    stream: TcpStream,
+    established: bool,
+    // The initial sequence number of the gateway.
+    iss: u32,
+    // The oldest unacknowledged and the next sequence number of the gateway.
+    snd_una: u32,
+    snd_nxt: u32,
+    // The next sequence number expected from the guest.
+    rcv_nxt: u32,
+    // The receive window of the guest.
+    window: u32,
+    fin_sent: bool,
+    fin_received: bool,
+}
+
+impl TcpConnection {
+    fn closed(&self) -> bool {
+        self.fin_sent && self.fin_received && self.snd_una == self.snd_nxt
+    }
+}
+
+pub struct UserNet {
+    guest_mac: [u8; 6],
+    connections: Vec<TcpConnection>,
+    // The initial sequence number of the next connection. It is deterministic like the rest of the
+    // stack.
+    next_iss: u32,
+    // Frames to the guest.
+    output: Vec<Vec<u8>>,
+}
+
+impl UserNet {
+    pub fn new() -> Self {
+        Self {
+            guest_mac: [0xFF; 6],
+            connections: Vec::new(),
+            next_iss: 0x1000_0000,
+            output: Vec::new(),
+        }
+    }
+
+    // Handles a frame from the guest.
+    pub fn send(&mut self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return;
+        }
+        self.guest_mac.copy_from_slice(&frame[6..12]);
+        let payload = &frame[ETHERNET_HEADER_SIZE..];
+        match be16(&frame[12..14]) {
+            ETHERTYPE_ARP => self.arp(payload),
+            ETHERTYPE_IPV4 => self.ipv4(payload),
+            _ => {}
+        }
+    }
+
+    // Returns the frames to the guest, including the data received from the host.
+    pub fn recv(&mut self) -> Vec<Vec<u8>> {
+        for i in 0..self.connections.len() {
+            self.tcp_receive_host(i);
+        }
+        self.connections.retain(|c| !c.closed());
+        std::mem::take(&mut self.output)
+    }
+
+    /*
+        (RFC 826) htype: be16, ptype: be16, hlen: u8, plen: u8, oper: be16,
+        sha: [u8; 6], spa: [u8; 4], tha: [u8; 6], tpa: [u8; 4]
+    */
+    fn arp(&mut self, packet: &[u8]) {
+        if packet.len() < 28 || be16(&packet[6..8]) != ARP_REQUEST || packet[24..28] != GATEWAY_IP {
+            return;
+        }
+        let mut reply = packet[..28].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&GATEWAY_MAC);
+        reply[14..18].copy_from_slice(&GATEWAY_IP);
+        reply[18..28].copy_from_slice(&packet[8..18]);
+        self.output
+            .push(ethernet(self.guest_mac, ETHERTYPE_ARP, &reply));
+    }
+
+    fn ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return;
+        }
+        let ihl = (packet[0] & 0xF) as usize * 4;
+        let total = (be16(&packet[2..4]) as usize).min(packet.len());
+        // Fragments aren't reassembled.
+        if ihl < IPV4_HEADER_SIZE
+            || total < ihl
+            || be16(&packet[6..8]) & IPV4_FRAGMENT != 0
+            || packet[16..20] != GATEWAY_IP
+        {
+            return;
+        }
+        let src = [packet[12], packet[13], packet[14], packet[15]];
+        let payload = &packet[ihl..total];
+        match packet[9] {
+            IPPROTO_ICMP => self.icmp(src, payload),
+            IPPROTO_UDP => self.udp(src, payload),
+            IPPROTO_TCP => self.tcp(src, payload),
+            _ => {}
+        }
+    }
+
+    fn reply_ipv4(&mut self, dst: [u8; 4], protocol: u8, payload: &[u8]) {
+        let packet = ipv4(dst, protocol, payload);
+        self.output
+            .push(ethernet(self.guest_mac, ETHERTYPE_IPV4, &packet));
+    }
+
+    // (RFC 792) type: u8, code: u8, checksum: be16, identifier: be16, sequence: be16, data
+    fn icmp(&mut self, src: [u8; 4], message: &[u8]) {
+        if message.len() < 8 || message[0] != ICMP_ECHO_REQUEST {
+            return;
+        }
+        let mut reply = message.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].fill(0);
+        let sum = checksum(&[&reply]);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.reply_ipv4(src, IPPROTO_ICMP, &reply);
+    }
+
+    // (RFC 768) source port: be16, destination port: be16, length: be16, checksum: be16, data
+    fn udp(&mut self, src: [u8; 4], datagram: &[u8]) {
+        if datagram.len() < 8 || be16(&datagram[2..4]) != UDP_ECHO_PORT {
+            return;
+        }
+        let len = (be16(&datagram[4..6]) as usize).clamp(8, datagram.len());
+        let mut reply = datagram[..len].to_vec();
+        reply[0..2].copy_from_slice(&datagram[2..4]);
+        reply[2..4].copy_from_slice(&datagram[0..2]);
+        reply[6..8].fill(0);
+        // A zero checksum means no checksum, so it is sent as all ones.
+        let sum = match transport_checksum(src, IPPROTO_UDP, &reply) {
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        reply[6..8].copy_from_slice(&sum.to_be_bytes());
+        self.reply_ipv4(src, IPPROTO_UDP, &reply);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_tcp(
+        &mut self,
+        dst: [u8; 4],
+        src_port: u16,
+        dst_port: u16,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        data: &[u8],
+    ) {
+        let options: &[u8] = if flags & TCP_SYN != 0 {
+            &[TCP_OPTION_MSS, 4, (TCP_MSS >> 8) as u8, TCP_MSS as u8]
+        } else {
+            &[]
+        };
+        let header_size = TCP_HEADER_SIZE + options.len();
+        let mut segment = vec![0; header_size];
+        segment[0..2].copy_from_slice(&src_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&seq.to_be_bytes());
+        segment[8..12].copy_from_slice(&ack.to_be_bytes());
+        segment[12] = ((header_size / 4) as u8) << 4;
+        segment[13] = flags;
+        segment[14..16].copy_from_slice(&TCP_WINDOW.to_be_bytes());
+        segment[TCP_HEADER_SIZE..].copy_from_slice(options);
+        segment.extend_from_slice(data);
+        let sum = transport_checksum(dst, IPPROTO_TCP, &segment);
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+        self.reply_ipv4(dst, IPPROTO_TCP, &segment);
+    }
+
+    fn send_segment(&mut self, i: usize, flags: u8, data: &[u8]) {
+        let c = &self.connections[i];
+        let (dst, src_port, dst_port) = (c.guest_ip, c.port, c.guest_port);
+        let (seq, ack) = (c.snd_nxt, c.rcv_nxt);
+        self.send_tcp(dst, src_port, dst_port, seq, ack, flags, data);
+    }
+
+    /*
+        (RFC 793) source port: be16, destination port: be16, sequence number: be32,
+        acknowledgment number: be32, data offset: 4 bits, flags: 12 bits, window: be16,
+        checksum: be16, urgent pointer: be16, options
+    */
+    fn tcp(&mut self, src: [u8; 4], segment: &[u8]) {
+        if segment.len() < TCP_HEADER_SIZE {
+            return;
+        }
+        let src_port = be16(&segment[0..2]);
+        let port = be16(&segment[2..4]);
+        let seq = be32(&segment[4..8]);
+        let ack = be32(&segment[8..12]);
+        let offset = (segment[12] >> 4) as usize * 4;
+        let flags = segment[13];
+        let window = be16(&segment[14..16]) as u32;
+        if offset < TCP_HEADER_SIZE || offset > segment.len() {
+            return;
+        }
+        let data = &segment[offset..];
+
+        let i = match self
+            .connections
+            .iter()
+            .position(|c| c.guest_ip == src && c.guest_port == src_port && c.port == port)
+        {
+            Some(i) => i,
+            None => {
+                if flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN {
+                    self.tcp_connect(src, src_port, port, seq, window);
+                } else if flags & TCP_RST == 0 {
+                    // (RFC 793 3.4) A segment of no connection is answered by a reset.
+                    if flags & TCP_ACK != 0 {
+                        self.send_tcp(src, port, src_port, ack, 0, TCP_RST, &[]);
+                    } else {
+                        let len = data.len() as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32;
+                        let ack = seq.wrapping_add(len);
+                        self.send_tcp(src, port, src_port, 0, ack, TCP_RST | TCP_ACK, &[]);
+                    }
+                }
+                return;
+            }
+        };
+
+        if flags & TCP_RST != 0 {
+            self.connections.remove(i);
+            return;
+        }
+        let c = &mut self.connections[i];
+        if flags & TCP_SYN != 0 {
+            // The SYN-ACK was lost or the guest retried.
+            if !c.established {
+                c.snd_nxt = c.iss;
+                self.send_segment(i, TCP_SYN | TCP_ACK, &[]);
+                self.connections[i].snd_nxt = self.connections[i].iss.wrapping_add(1);
+            }
+            return;
+        }
+        if flags & TCP_ACK != 0 && seq_le(c.snd_una, ack) && seq_le(ack, c.snd_nxt) {
+            c.snd_una = ack;
+            c.window = window;
+            if ack != c.iss {
+                c.established = true;
+            }
+        }
+        if !c.established {
+            return;
+        }
+
+        // Only the data in order is accepted. The rest is retransmitted by the guest.
+        let mut consumed = seq == c.rcv_nxt;
+        if consumed && !data.is_empty() && !c.fin_received {
+            let written = match c.stream.write(data) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                Err(_) => {
+                    self.send_segment(i, TCP_RST, &[]);
+                    self.connections.remove(i);
+                    return;
+                }
+            };
+            c.rcv_nxt = c.rcv_nxt.wrapping_add(written as u32);
+            consumed = written == data.len();
+        }
+        if consumed && flags & TCP_FIN != 0 && !c.fin_received {
+            c.rcv_nxt = c.rcv_nxt.wrapping_add(1);
+            c.fin_received = true;
+            c.stream.shutdown(Shutdown::Write).ok();
+        }
+        if !data.is_empty() || flags & TCP_FIN != 0 {
+            self.send_segment(i, TCP_ACK, &[]);
+        }
+    }
+
+    fn tcp_connect(&mut self, src: [u8; 4], src_port: u16, port: u16, seq: u32, window: u32) {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let stream = match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) if stream.set_nonblocking(true).is_ok() => stream,
+            _ => {
+                let ack = seq.wrapping_add(1);
+                self.send_tcp(src, port, src_port, 0, ack, TCP_RST | TCP_ACK, &[]);
+                return;
+            }
+        };
+        let iss = self.next_iss;
+        self.next_iss = self.next_iss.wrapping_add(0x10000);
+        self.connections.push(TcpConnection {
+            guest_ip: src,
+            guest_port: src_port,
+            port,
+            stream,
+            established: false,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            rcv_nxt: seq.wrapping_add(1),
+            window,
+            fin_sent: false,
+            fin_received: false,
+        });
+        let i = self.connections.len() - 1;
+        self.send_segment(i, TCP_SYN | TCP_ACK, &[]);
+        self.connections[i].snd_nxt = iss.wrapping_add(1);
+    }
+
+    // Sends the data received from the host as far as the window of the guest allows.
+    fn tcp_receive_host(&mut self, i: usize) {
+        let mut buf = [0; TCP_MSS];
+        loop {
+            let c = &mut self.connections[i];
+            let in_flight = c.snd_nxt.wrapping_sub(c.snd_una);
+            if !c.established || c.fin_sent || in_flight >= c.window {
+                return;
+            }
+            let len = ((c.window - in_flight) as usize).min(TCP_MSS);
+            match c.stream.read(&mut buf[..len]) {
+                Ok(0) => {
+                    self.send_segment(i, TCP_FIN | TCP_ACK, &[]);
+                    let c = &mut self.connections[i];
+                    c.snd_nxt = c.snd_nxt.wrapping_add(1);
+                    c.fin_sent = true;
+                }
+                Ok(n) => {
+                    self.send_segment(i, TCP_PSH | TCP_ACK, &buf[..n]);
+                    let c = &mut self.connections[i];
+                    c.snd_nxt = c.snd_nxt.wrapping_add(n as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_) => {
+                    self.send_segment(i, TCP_RST, &[]);
+                    let c = &mut self.connections[i];
+                    // Dropped by recv().
+                    c.fin_sent = true;
+                    c.fin_received = true;
+                    c.snd_una = c.snd_nxt;
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use std::io;
+
+use crate::memory::DRAM_BASE;
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
+
+/*
+    Virtual I/O Device (VIRTIO) Version 1.1, (4.2) Virtio Over MMIO
+
+    0x000  MagicValue           "virt"
+    0x004  Version              2
+    0x008  DeviceID             Subsystem device ID (2: block, 3: console, 4: entropy, ...)
+    0x00c  VendorID
+    0x010  DeviceFeatures       Flags of the features selected by DeviceFeaturesSel
+    0x014  DeviceFeaturesSel
+    0x020  DriverFeatures       Flags of the features selected by DriverFeaturesSel
+    0x024  DriverFeaturesSel
+    0x030  QueueSel             Selects the queue of the following Queue* registers
+    0x034  QueueNumMax
+    0x038  QueueNum
+    0x044  QueueReady
+    0x050  QueueNotify          Writing a queue index notifies the device of new buffers
+    0x060  InterruptStatus
+    0x064  InterruptACK
+    0x070  Status               Writing zero resets the device
+    0x080  QueueDescLow/High    Physical address of the descriptor area
+    0x090  QueueDriverLow/High  Physical address of the driver area (available ring)
+    0x0a0  QueueDeviceLow/High  Physical address of the device area (used ring)
+    0x0fc  ConfigGeneration
+    0x100  Config               Device-specific configuration space
+*/
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const VENDOR: u32 = 0x554d_4551; // "QEMU"
+
+// (6) The device supports the version 1 interface, which is required by the MMIO version 2.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// The buffers were used.
+const INTERRUPT_USED_BUFFER: u32 = 0b1;
+
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const POLL_INTERVAL: u32 = 1024;
+
+// (2.6.5) Virtqueue Descriptor Table
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+fn set_low(reg: &mut u64, val: u32) {
+    *reg = *reg & !0xFFFF_FFFF | val as u64;
+}
+
+fn set_high(reg: &mut u64, val: u32) {
+    *reg = *reg & 0xFFFF_FFFF | (val as u64) << 32;
+}
+
+/*
+    Guest physical memory, which is DRAM. The ranges written by the device are recorded, so that
+    the instructions decoded from them are invalidated as after a store by the hart.
+*/
+pub struct GuestMemory<'a> {
+    ram: &'a mut [u8],
+    // (physical address, length)
+    written: &'a mut Vec<(u32, u32)>,
+}
+
+impl GuestMemory<'_> {
+    fn range(&self, addr: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = addr.checked_sub(DRAM_BASE as u64)? as usize;
+        // The address and the length come from the guest, so the sum may overflow.
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= self.ram.len())?;
+        Some(start..end)
+    }
+
+    pub fn read(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let range = self.range(addr, len)?;
+        Some(&self.ram[range])
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Option<()> {
+        let range = self.range(addr, data.len())?;
+        self.ram[range].copy_from_slice(data);
+        self.written.push((addr as u32, data.len() as u32));
+        Some(())
+    }
+
+    fn read16(&self, addr: u64) -> Option<u16> {
+        let b = self.read(addr, 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn read32(&self, addr: u64) -> Option<u32> {
+        let b = self.read(addr, 4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read64(&self, addr: u64) -> Option<u64> {
+        Some(self.read32(addr)? as u64 | (self.read32(addr.wrapping_add(4))? as u64) << 32)
+    }
+}
+
+// A descriptor chain taken from the available ring.
+pub struct DescChain {
+    head: u16,
+    // The device-readable buffers, concatenated.
+    pub readable: Vec<u8>,
+    // The device-writable buffers. (address, length)
+    writable: Vec<(u64, u32)>,
+}
+
+impl DescChain {
+    // The total length of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+}
+
+/*
+    (2.6) Split Virtqueues
+
+    Descriptor table: 16 bytes per descriptor (addr: le64, len: le32, flags: le16, next: le16)
+    Available ring:   flags: le16, idx: le16, ring: [le16; size]
+    Used ring:        flags: le16, idx: le16, ring: [(id: le32, len: le32); size]
+*/
+pub struct Virtqueue {
+    pub num: u32,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    // The next index of the available ring to be processed.
+    last_avail: u16,
+}
+
+impl Virtqueue {
+    pub fn new() -> Self {
+        Self {
+            num: QUEUE_SIZE_MAX,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail: 0,
+        }
+    }
+
+    // Takes the next descriptor chain made available by the driver.
+    pub fn pop(&mut self, mem: &GuestMemory) -> Option<DescChain> {
+        if !self.ready || self.num == 0 {
+            return None;
+        }
+        let avail_idx = mem.read16(self.driver.wrapping_add(2))?;
+        if avail_idx == self.last_avail {
+            return None;
+        }
+        let slot = self.last_avail as u64 % self.num as u64;
+        let head = mem.read16(self.driver.wrapping_add(4 + 2 * slot))?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = DescChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let (mut table, mut size) = (self.desc, self.num);
+        let mut index = head;
+        // A loop in the chain is a driver bug. Stop after visiting every descriptor once. The
+        // addresses wrap around instead of overflowing, and then are outside of DRAM.
+        for _ in 0..QUEUE_SIZE_MAX * 2 {
+            let desc = table.wrapping_add(16 * index as u64);
+            let addr = mem.read64(desc)?;
+            let len = mem.read32(desc.wrapping_add(8))?;
+            let flags = mem.read16(desc.wrapping_add(12))?;
+            let next = mem.read16(desc.wrapping_add(14))?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // (2.6.5.3) An indirect descriptor points to a table of descriptors.
+                table = addr;
+                size = len / 16;
+                index = 0;
+                continue;
+            }
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                chain
+                    .readable
+                    .extend_from_slice(mem.read(addr, len as usize)?);
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 || next as u32 >= size {
+                break;
+            }
+            index = next;
+        }
+        Some(chain)
+    }
+
+    // Writes the data to the device-writable buffers and returns the chain to the driver.
+    pub fn push(&mut self, mem: &mut GuestMemory, chain: &DescChain, data: &[u8]) {
+        self.push_at(mem, chain, 0, data);
+    }
+
+    /*
+        Writes the data at the offset in the device-writable buffers and returns the chain to the
+        driver. The buffers before the offset are left as they are, but are counted as used.
+    */
+    pub fn push_at(
+        &mut self,
+        mem: &mut GuestMemory,
+        chain: &DescChain,
+        offset: usize,
+        data: &[u8],
+    ) {
+        let mut skip = offset;
+        let mut written = 0;
+        for &(addr, len) in &chain.writable {
+            if written == data.len() {
+                break;
+            }
+            if skip >= len as usize {
+                skip -= len as usize;
+                continue;
+            }
+            let n = (len as usize - skip).min(data.len() - written);
+            let addr = addr.wrapping_add(skip as u64);
+            if mem.write(addr, &data[written..written + n]).is_none() {
+                break;
+            }
+            skip = 0;
+            written += n;
+        }
+        let written = if written == 0 { 0 } else { offset + written };
+
+        let used_idx = match mem.read16(self.device.wrapping_add(2)) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let elem = self
+            .device
+            .wrapping_add(4 + 8 * (used_idx as u64 % self.num as u64));
+        mem.write(elem, &(chain.head as u32).to_le_bytes());
+        mem.write(elem.wrapping_add(4), &(written as u32).to_le_bytes());
+        mem.write(
+            self.device.wrapping_add(2),
+            &used_idx.wrapping_add(1).to_le_bytes(),
+        );
+    }
+}
+
+// The device type specific part of a virtio device.
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+    // Device-specific feature bits. VIRTIO_F_VERSION_1 is added by the transport.
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    fn config(&self) -> Vec<u8>;
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+    // Processes the buffers of the notified queue. Returns whether any buffer was used.
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool;
+    // Called periodically to pass input from the host to the driver. Returns whether any buffer was used.
+    fn poll(&mut self, _queues: &mut [Virtqueue], _mem: &mut GuestMemory) -> bool {
+        false
+    }
+    fn reset(&mut self) {}
+    // Replaces randomness from the host, if any, with a generator started from the seed.
+    fn seed(&mut self, _seed: u64) {}
+    // Saves the state which is not in guest memory, such as data not passed to the driver yet.
+    fn save(&self, _w: &mut Writer) {}
+    fn restore(&mut self, _r: &mut Reader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A virtio device on the MMIO transport.
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Virtqueue>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    interrupt_status: u32,
+    status: u32,
+    ticks: u32,
+    // The ranges of DRAM written by the device since they were last taken.
+    pub dma_writes: Vec<(u32, u32)>,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.num_queues()).map(|_| Virtqueue::new()).collect();
+        Self {
+            device,
+            queues,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+            status: 0,
+            ticks: 0,
+            dma_writes: Vec::new(),
+        }
+    }
+
+    pub fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    // Called once per instruction. The device is polled for input every POLL_INTERVAL ticks.
+    pub fn tick(&mut self, ram: &mut [u8]) {
+        self.ticks += 1;
+        if self.ticks < POLL_INTERVAL {
+            return;
+        }
+        self.ticks = 0;
+        let mut mem = GuestMemory {
+            ram,
+            written: &mut self.dma_writes,
+        };
+        if self.device.poll(&mut self.queues, &mut mem) {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for queue in self.queues.iter_mut() {
+            *queue = Virtqueue::new();
+        }
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.device.seed(seed);
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    pub fn read(&mut self, offset: u32, size: u32) -> u32 {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            return match config.get(start..start + size as usize) {
+                Some(bytes) => bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |val, &byte| val << 8 | byte as u32),
+                None => 0,
+            };
+        }
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => match self.queue() {
+                Some(_) => QUEUE_SIZE_MAX,
+                None => 0,
+            },
+            QUEUE_READY => self.queue().map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, size: u32, val: u32, ram: &mut [u8]) {
+        if offset >= CONFIG {
+            let bytes = val.to_le_bytes();
+            self.device
+                .write_config((offset - CONFIG) as usize, &bytes[..size as usize]);
+            return;
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, val),
+                1 => set_high(&mut self.driver_features, val),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            QUEUE_SEL => self.queue_sel = val,
+            QUEUE_NUM => {
+                if let Some(q) = self.queue() {
+                    q.num = val.min(QUEUE_SIZE_MAX);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = val & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                let queue = val as usize;
+                let mut mem = GuestMemory {
+                    ram,
+                    written: &mut self.dma_writes,
+                };
+                if queue < self.queues.len()
+                    && self.device.notify(queue, &mut self.queues, &mut mem)
+                {
+                    self.interrupt_status |= INTERRUPT_USED_BUFFER;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !val,
+            STATUS => {
+                if val == 0 {
+                    self.reset();
+                } else {
+                    self.status = val;
+                }
+            }
+            QUEUE_DESC_LOW..=QUEUE_DEVICE_HIGH => {
+                if let Some(q) = self.queue() {
+                    let addr = match offset & !0b100 {
+                        QUEUE_DESC_LOW => &mut q.desc,
+                        QUEUE_DRIVER_LOW => &mut q.driver,
+                        QUEUE_DEVICE_LOW => &mut q.device,
+                        _ => return,
+                    };
+                    match offset {
+                        QUEUE_DESC_HIGH | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_HIGH => {
+                            set_high(addr, val)
+                        }
+                        _ => set_low(addr, val),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for VirtioMmio {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.device.device_id());
+        w.u32(self.queues.len() as u32);
+        for q in &self.queues {
+            w.u32(q.num);
+            w.bool(q.ready);
+            w.u64(q.desc);
+            w.u64(q.driver);
+            w.u64(q.device);
+            w.u32(q.last_avail as u32);
+        }
+        w.u32(self.device_features_sel);
+        w.u64(self.driver_features);
+        w.u32(self.driver_features_sel);
+        w.u32(self.queue_sel);
+        w.u32(self.interrupt_status);
+        w.u32(self.status);
+        w.u32(self.ticks);
+        self.device.save(w);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        if r.u32()? != self.device.device_id() || r.u32()? as usize != self.queues.len() {
+            return Err(invalid("the virtio devices differ"));
+        }
+        for q in self.queues.iter_mut() {
+            q.num = r.u32()?;
+            q.ready = r.bool()?;
+            q.desc = r.u64()?;
+            q.driver = r.u64()?;
+            q.device = r.u64()?;
+            q.last_avail = r.u32()? as u16;
+        }
+        self.device_features_sel = r.u32()?;
+        self.driver_features = r.u64()?;
+        self.driver_features_sel = r.u32()?;
+        self.queue_sel = r.u32()?;
+        self.interrupt_status = r.u32()?;
+        self.status = r.u32()?;
+        self.ticks = r.u32()?;
+        self.device.restore(r)
+    }
+}
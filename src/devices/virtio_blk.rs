@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::virtio::{GuestMemory, VirtioDevice, Virtqueue};
+use crate::snapshot::{invalid, Reader, Writer};
+
+/*
+    Virtual I/O Device (VIRTIO) Version 1.1, (5.2) Block Device
+
+    A request consists of a header (type: le32, reserved: le32, sector: le64) and the data
+    readable by the device, followed by the data written by the device and a status byte.
+    Sectors are 512 bytes. The configuration space starts with the capacity in sectors (le64).
+*/
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+// The device supports the flush command.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: usize = 512;
+const HEADER_SIZE: usize = 16;
+// The length of the device ID string.
+const ID_SIZE: usize = 20;
+
+pub struct VirtioBlk {
+    file: File,
+    capacity: u64,
+    readonly: bool,
+    // Sectors written in snapshot mode, which are never written back to the file.
+    overlay: Option<HashMap<u64, Vec<u8>>>,
+}
+
+impl VirtioBlk {
+    /*
+        Opens a raw disk image. In snapshot mode, the image is opened read-only and writes go to
+        a copy-on-write overlay in memory, so the base image is never modified.
+    */
+    pub fn open(path: &str, readonly: bool, snapshot: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!readonly && !snapshot)
+            .open(path)?;
+        let capacity = file.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self {
+            file,
+            capacity,
+            readonly,
+            overlay: if snapshot { Some(HashMap::new()) } else { None },
+        })
+    }
+
+    fn read_sectors(&mut self, sector: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.file
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.file.read_exact(&mut data)?;
+        if let Some(overlay) = &self.overlay {
+            for (i, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+                if let Some(written) = overlay.get(&(sector + i as u64)) {
+                    chunk.copy_from_slice(written);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match &mut self.overlay {
+            Some(overlay) => {
+                for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    overlay.insert(sector + i as u64, chunk.to_vec());
+                }
+                Ok(())
+            }
+            None => {
+                self.file
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.file.write_all(data)
+            }
+        }
+    }
+
+    /*
+        Handles a request and returns the data written to the driver. The status is the last byte
+        of the device-writable buffers.
+    */
+    fn request(&mut self, readable: &[u8], writable_len: usize) -> Vec<u8> {
+        if writable_len == 0 {
+            return Vec::new();
+        }
+        let (mut data, status) = if readable.len() < HEADER_SIZE {
+            (Vec::new(), VIRTIO_BLK_S_IOERR)
+        } else {
+            self.execute(readable, writable_len - 1)
+        };
+        data.resize(writable_len - 1, 0);
+        data.push(status);
+        data
+    }
+
+    // Executes the command in the header. Returns the data read and the status.
+    fn execute(&mut self, readable: &[u8], len: usize) -> (Vec<u8>, u8) {
+        let le32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let kind = le32(&readable[0..4]);
+        let sector = le32(&readable[8..12]) as u64 | (le32(&readable[12..16]) as u64) << 32;
+        // The data must be whole sectors within the disk.
+        let capacity = self.capacity;
+        let in_range = |size: usize| {
+            size.is_multiple_of(SECTOR_SIZE)
+                && sector
+                    .checked_add((size / SECTOR_SIZE) as u64)
+                    .is_some_and(|end| end <= capacity)
+        };
+
+        match kind {
+            VIRTIO_BLK_T_IN => match in_range(len).then(|| self.read_sectors(sector, len)) {
+                Some(Ok(data)) => (data, VIRTIO_BLK_S_OK),
+                _ => (Vec::new(), VIRTIO_BLK_S_IOERR),
+            },
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[HEADER_SIZE..];
+                let ok = !self.readonly
+                    && in_range(data.len())
+                    && self.write_sectors(sector, data).is_ok();
+                let status = if ok {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                };
+                (Vec::new(), status)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                let ok = self.overlay.is_some() || self.file.sync_data().is_ok();
+                let status = if ok {
+                    VIRTIO_BLK_S_OK
+                } else {
+                    VIRTIO_BLK_S_IOERR
+                };
+                (Vec::new(), status)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"rv32g-emulator".to_vec();
+                id.resize(ID_SIZE.min(len), 0);
+                (id, VIRTIO_BLK_S_OK)
+            }
+            _ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        if self.readonly {
+            VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.capacity.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            let len = chain.writable_len();
+            // No request writes more than the whole disk, so a larger chain is not allocated and
+            // only gets the status in its last byte.
+            let max = (self.capacity as usize)
+                .saturating_mul(SECTOR_SIZE)
+                .max(ID_SIZE)
+                + 1;
+            if len > max {
+                queues[queue].push_at(mem, &chain, len - 1, &[VIRTIO_BLK_S_IOERR]);
+            } else {
+                let data = self.request(&chain.readable, len);
+                queues[queue].push(mem, &chain, &data);
+            }
+            used = true;
+        }
+        used
+    }
+
+    // Without snapshot mode, the writes are in the image file, which must not have changed since.
+    fn save(&self, w: &mut Writer) {
+        let mut sectors: Vec<_> = self.overlay.iter().flatten().collect();
+        sectors.sort_by_key(|&(&sector, _)| sector);
+        w.u32(sectors.len() as u32);
+        for (&sector, data) in sectors {
+            w.u64(sector);
+            w.bytes(data);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        let count = r.u32()?;
+        let overlay = match &mut self.overlay {
+            Some(overlay) => overlay,
+            None if count == 0 => return Ok(()),
+            None => return Err(invalid("the disk must be in snapshot mode")),
+        };
+        overlay.clear();
+        for _ in 0..count {
+            let sector = r.u64()?;
+            overlay.insert(sector, r.bytes()?);
+        }
+        Ok(())
+    }
+}
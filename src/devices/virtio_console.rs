@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::chardev::Chardev;
+use super::virtio::{GuestMemory, VirtioDevice, Virtqueue};
+use crate::snapshot::{Reader, Writer};
+
+/*
+    Virtual I/O Device (VIRTIO) Version 1.1, (5.3) Console Device
+
+    Queues: 0 receiveq(port0), 1 transmitq(port0), 2 control receiveq, 3 control transmitq,
+            4 receiveq(port1), 5 transmitq(port1), ...
+    The control queues and the ports other than port 0 exist only with VIRTIO_CONSOLE_F_MULTIPORT.
+
+    Configuration space: cols: le16, rows: le16, max_nr_ports: le32, emerg_wr: le32
+*/
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+// The device supports multiple ports and the control virtqueues.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+// The device supports emergency write.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// The offset of emerg_wr in the configuration space.
+const EMERG_WR: usize = 8;
+
+const CONTROL_RECEIVEQ: usize = 2;
+const CONTROL_TRANSMITQ: usize = 3;
+
+/*
+    (5.3.6.2) Multiport Device Operation
+
+    A control message consists of id: le32, event: le16 and value: le16. The driver reports
+    DEVICE_READY, then the device adds each port, and the driver reports PORT_READY for each port.
+*/
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = id.to_le_bytes().to_vec();
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+    msg
+}
+
+struct Port {
+    chardev: Chardev,
+    // Input not passed to the driver yet.
+    rx: VecDeque<u8>,
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    // Control messages waiting for buffers of the control receiveq.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    // Port 0 is the console. Multiple ports are provided through VIRTIO_CONSOLE_F_MULTIPORT.
+    pub fn new(chardevs: Vec<Chardev>) -> Self {
+        assert!(!chardevs.is_empty(), "a console requires at least one port");
+        Self {
+            ports: chardevs
+                .into_iter()
+                .map(|chardev| Port {
+                    chardev,
+                    rx: VecDeque::new(),
+                })
+                .collect(),
+            control: VecDeque::new(),
+        }
+    }
+
+    fn multiport(&self) -> bool {
+        self.ports.len() > 1
+    }
+
+    fn receiveq(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            2 + 2 * port
+        }
+    }
+
+    // Returns the port of a transmitq.
+    fn transmitq_port(&self, queue: usize) -> Option<usize> {
+        let port = match queue {
+            1 => 0,
+            _ if queue >= 4 && queue % 2 == 1 => (queue - 2) / 2,
+            _ => return None,
+        };
+        (port < self.ports.len()).then_some(port)
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) {
+        if msg.len() < 8 {
+            return;
+        }
+        let id = u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]);
+        let event = u16::from_le_bytes([msg[4], msg[5]]);
+        let value = u16::from_le_bytes([msg[6], msg[7]]);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() as u32 {
+                    self.control
+                        .push_back(control_message(port, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.control
+                        .push_back(control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                }
+                // The host side of every port is always open.
+                self.control
+                    .push_back(control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            _ => {}
+        }
+    }
+
+    // Passes pending input and control messages to the buffers available. Returns whether any
+    // buffer was used.
+    fn deliver(&mut self, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        for (i, port) in self.ports.iter_mut().enumerate() {
+            let queue = &mut queues[Self::receiveq(i)];
+            while !port.rx.is_empty() {
+                let chain = match queue.pop(mem) {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                let n = chain.writable_len().min(port.rx.len());
+                let data: Vec<u8> = port.rx.drain(..n).collect();
+                queue.push(mem, &chain, &data);
+                used = true;
+            }
+        }
+        if self.multiport() {
+            let queue = &mut queues[CONTROL_RECEIVEQ];
+            while let Some(msg) = self.control.front() {
+                let chain = match queue.pop(mem) {
+                    Some(chain) => chain,
+                    None => break,
+                };
+                queue.push(mem, &chain, msg);
+                self.control.pop_front();
+                used = true;
+            }
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        if self.multiport() {
+            VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+        } else {
+            VIRTIO_CONSOLE_F_EMERG_WRITE
+        }
+    }
+
+    fn num_queues(&self) -> usize {
+        if self.multiport() {
+            2 + 2 * self.ports.len()
+        } else {
+            2
+        }
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config
+    }
+
+    // (5.3.4) Writing a character to emerg_wr outputs it to port 0 immediately.
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if offset == EMERG_WR {
+            self.ports[0].chardev.write(&data[..1]);
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        if let Some(port) = self.transmitq_port(queue) {
+            while let Some(chain) = queues[queue].pop(mem) {
+                self.ports[port].chardev.write(&chain.readable);
+                queues[queue].push(mem, &chain, &[]);
+                used = true;
+            }
+        } else if queue == CONTROL_TRANSMITQ && self.multiport() {
+            while let Some(chain) = queues[queue].pop(mem) {
+                self.handle_control(&chain.readable);
+                queues[queue].push(mem, &chain, &[]);
+                used = true;
+            }
+        }
+        // New buffers of a receiveq may take pending input.
+        self.deliver(queues, mem) || used
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        for port in self.ports.iter_mut() {
+            port.rx.extend(port.chardev.read());
+        }
+        self.deliver(queues, mem)
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+
+    fn save(&self, w: &mut Writer) {
+        for port in &self.ports {
+            w.bytes(&port.rx.iter().copied().collect::<Vec<u8>>());
+        }
+        w.u32(self.control.len() as u32);
+        for msg in &self.control {
+            w.bytes(msg);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for port in self.ports.iter_mut() {
+            port.rx = r.bytes()?.into();
+        }
+        self.control.clear();
+        for _ in 0..r.u32()? {
+            self.control.push_back(r.bytes()?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::netdev::Netdev;
+use super::virtio::{GuestMemory, VirtioDevice, Virtqueue};
+use crate::snapshot::{Reader, Writer};
+
+/*
+    Virtual I/O Device (VIRTIO) Version 1.1, (5.1) Network Device
+
+    Queues: 0 receiveq, 1 transmitq
+    Configuration space: mac: [u8; 6], status: le16
+
+    Every packet is preceded by struct virtio_net_hdr (flags: u8, gso_type: u8, hdr_len: le16,
+    gso_size: le16, csum_start: le16, csum_offset: le16, num_buffers: le16). No offloads are
+    offered, so a packet is a complete Ethernet frame with valid checksums.
+*/
+const VIRTIO_ID_NET: u32 = 1;
+
+// The device has a MAC address in the configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+// The configuration space has the link status.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+const HEADER_SIZE: usize = 12;
+// The offset of num_buffers in the header.
+const NUM_BUFFERS: usize = 10;
+
+pub struct VirtioNet {
+    netdev: Netdev,
+    mac: [u8; 6],
+    // Frames not passed to the driver yet.
+    rx: VecDeque<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(netdev: Netdev, mac: [u8; 6]) -> Self {
+        Self {
+            netdev,
+            mac,
+            rx: VecDeque::new(),
+        }
+    }
+
+    // Passes the pending frames to the buffers available. Returns whether any buffer was used.
+    fn deliver(&mut self, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(frame) = self.rx.front() {
+            let chain = match queues[RECEIVEQ].pop(mem) {
+                Some(chain) => chain,
+                None => break,
+            };
+            // Without VIRTIO_NET_F_MRG_RXBUF, a packet always fits in one chain. A frame larger
+            // than the buffers is truncated.
+            let mut packet = vec![0; HEADER_SIZE];
+            packet[NUM_BUFFERS] = 1;
+            packet.extend_from_slice(frame);
+            queues[RECEIVEQ].push(mem, &chain, &packet);
+            self.rx.pop_front();
+            used = true;
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        if queue == TRANSMITQ {
+            while let Some(chain) = queues[queue].pop(mem) {
+                if let Some(frame) = chain.readable.get(HEADER_SIZE..) {
+                    self.netdev.send(frame);
+                }
+                queues[queue].push(mem, &chain, &[]);
+                used = true;
+            }
+            // The user-mode stack answers immediately.
+            self.rx.extend(self.netdev.recv());
+        }
+        self.deliver(queues, mem) || used
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        self.rx.extend(self.netdev.recv());
+        self.deliver(queues, mem)
+    }
+
+    fn reset(&mut self) {
+        self.rx.clear();
+    }
+
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.rx.len() as u32);
+        for frame in &self.rx {
+            w.bytes(frame);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.rx.clear();
+        for _ in 0..r.u32()? {
+            self.rx.push_back(r.bytes()?);
+        }
+        Ok(())
+    }
+}
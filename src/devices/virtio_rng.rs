@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use super::virtio::{GuestMemory, VirtioDevice, Virtqueue};
+use crate::replay;
+use crate::snapshot::{Reader, Writer};
+
+/*
+    Virtual I/O Device (VIRTIO) Version 1.1, (5.4) Entropy Device
+
+    The device fills the device-writable buffers of its only queue with random bytes. It has no
+    feature bits and no configuration space.
+*/
+const VIRTIO_ID_ENTROPY: u32 = 4;
+
+enum Source {
+    // SplitMix64. The same seed always produces the same bytes, which keeps runs reproducible.
+    Prng(u64),
+    Host(File),
+}
+
+pub struct VirtioRng {
+    source: Source,
+}
+
+impl VirtioRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            source: Source::Prng(seed),
+        }
+    }
+
+    // Reads the host entropy pool.
+    pub fn host() -> io::Result<Self> {
+        Ok(Self {
+            source: Source::Host(File::open("/dev/urandom")?),
+        })
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        match &mut self.source {
+            Source::Prng(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+            Source::Host(file) => {
+                let data = replay::bytes(replay::Source::Rng, || {
+                    let mut data = vec![0; buf.len()];
+                    file.read_exact(&mut data).ok();
+                    data
+                });
+                buf.copy_from_slice(&data);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Virtqueue], mem: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(mem) {
+            let mut data = vec![0; chain.writable_len()];
+            self.fill(&mut data);
+            queues[queue].push(mem, &chain, &data);
+            used = true;
+        }
+        used
+    }
+
+    // The host source is replaced by the generator in deterministic runs.
+    fn seed(&mut self, seed: u64) {
+        if let Source::Host(_) = self.source {
+            self.source = Source::Prng(seed);
+        }
+    }
+
+    // The generator continues from the saved state. The host source has no state.
+    fn save(&self, w: &mut Writer) {
+        if let Source::Prng(state) = self.source {
+            w.u64(state);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        if let Source::Prng(state) = &mut self.source {
+            *state = r.u64()?;
+        }
+        Ok(())
+    }
+}
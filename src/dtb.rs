@@ -0,0 +1,308 @@
+use crate::cpu::{isa_string, Cpu, MHARTID};
+use crate::devices::PLIC_NUM_SOURCES;
+use crate::memory::*;
+
+/*
+    Devicetree Specification v0.3 (5) Flattened Devicetree (DTB) Format
+
+    All values are big-endian.
+
+    | header | memory reservation block | structure block | strings block |
+
+    The structure block is a sequence of tokens. A node begins with FDT_BEGIN_NODE followed by
+    its null-terminated name, and contains FDT_PROP tokens followed by the value length, the offset
+    of the property name in the strings block, and the value. Every token is 4-byte aligned.
+*/
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: u32 = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Frequency of the time CSR and mtime.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+// phandle of the interrupt controller of hart 0.
+const CPU0_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+const TEST_PHANDLE: u32 = 3;
+
+// The values written to the test finisher to power off and reset.
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+// Interrupt causes of the local interrupt controller.
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+// Frequency of the UART input clock.
+const UART_CLOCK_FREQUENCY: u32 = 0x38_4000;
+
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn token(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // Returns the offset of the name in the strings block, adding it if it isn't there yet.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property(name, &val.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) {
+        let mut value = val.as_bytes().to_vec();
+        value.push(0);
+        self.property(name, &value);
+    }
+
+    // boot_cpuid_phys is the hart which boots, the same as the one the boot ROM passes in a0.
+    pub fn finish(mut self, boot_cpuid_phys: u32) -> Vec<u8> {
+        self.token(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        // An empty memory reservation block is a single zero entry. (address, size)
+        let rsvmap_size = 16;
+        let off_dt_struct = off_mem_rsvmap + rsvmap_size;
+        let off_dt_strings = off_dt_struct + self.structure.len() as u32;
+        let totalsize = off_dt_strings + self.strings.len() as u32;
+
+        let header = [
+            FDT_MAGIC,
+            totalsize,
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid_phys,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut dtb: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+        dtb.extend_from_slice(&[0; 16]);
+        dtb.extend_from_slice(&self.structure);
+        dtb.extend_from_slice(&self.strings);
+        dtb
+    }
+}
+
+// Parameters passed to the operating system in the /chosen node.
+#[derive(Default)]
+pub struct Chosen {
+    pub bootargs: Option<String>,
+    // The start and end addresses of the initial ramdisk.
+    pub initrd: Option<(u32, u32)>,
+}
+
+// Generates the device tree of the emulated machine, which follows the layout of QEMU virt.
+pub fn generate(cpu: &Cpu, chosen: &Chosen) -> Vec<u8> {
+    let hartid = cpu.csrs[MHARTID];
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscv-virtio,qemu");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", UART0_BASE));
+    if let Some(bootargs) = &chosen.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = chosen.initrd {
+        fdt.property_u32("linux,initrd-start", start);
+        fdt.property_u32("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", DRAM_BASE));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &[0, DRAM_BASE, 0, MEMORY_SIZE]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    fdt.begin_node(&format!("cpu@{:x}", hartid));
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", hartid);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &isa_string(cpu.isa));
+    fdt.property_string("mmu-type", "riscv,sv32");
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU0_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    // Memory-mapped devices are children of the soc node.
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property("compatible", b"sifive,clint0\0riscv,clint0\0");
+    fdt.property_cells("reg", &[0, CLINT_BASE, 0, CLINT_SIZE]);
+    fdt.property_cells(
+        "interrupts-extended",
+        &[
+            CPU0_INTC_PHANDLE,
+            IRQ_M_SOFT,
+            CPU0_INTC_PHANDLE,
+            IRQ_M_TIMER,
+        ],
+    );
+    fdt.end_node();
+
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.property("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0");
+    fdt.property_cells("reg", &[0, PLIC_BASE, 0, PLIC_SIZE]);
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    // Context 0 is M-mode and context 1 is S-mode.
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU0_INTC_PHANDLE, IRQ_M_EXT, CPU0_INTC_PHANDLE, IRQ_S_EXT],
+    );
+    fdt.property_u32("riscv,ndev", PLIC_NUM_SOURCES as u32 - 1);
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", UART0_BASE));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_cells("reg", &[0, UART0_BASE, 0, UART0_SIZE]);
+    fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.property_u32("interrupts", UART0_IRQ);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("test@{:x}", TEST_BASE));
+    fdt.property("compatible", b"sifive,test1\0sifive,test0\0syscon\0");
+    fdt.property_cells("reg", &[0, TEST_BASE, 0, TEST_SIZE]);
+    fdt.property_u32("phandle", TEST_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("rtc@{:x}", RTC_BASE));
+    fdt.property_string("compatible", "google,goldfish-rtc");
+    fdt.property_cells("reg", &[0, RTC_BASE, 0, RTC_SIZE]);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.property_u32("interrupts", RTC_IRQ);
+    fdt.end_node();
+
+    if let Some(fb) = &cpu.ram.framebuffer {
+        fdt.begin_node(&format!("framebuffer@{:x}", FB_VRAM_BASE));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_cells("reg", &[0, FB_VRAM_BASE, 0, fb.vram.len() as u32]);
+        fdt.property_u32("width", fb.width);
+        fdt.property_u32("height", fb.height);
+        fdt.property_u32("stride", fb.stride());
+        fdt.property_string("format", fb.format.name());
+        fdt.end_node();
+    }
+
+    for i in 0..cpu.ram.virtio.len() as u32 {
+        let base = VIRTIO_BASE + VIRTIO_SIZE * i;
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[0, base, 0, VIRTIO_SIZE]);
+        fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.property_u32("interrupts", VIRTIO_IRQ + i);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+
+    // Linux powers off and reboots the machine through the test finisher.
+    for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+        fdt.begin_node(name);
+        fdt.property_string("compatible", &format!("syscon-{}", name));
+        fdt.property_u32("regmap", TEST_PHANDLE);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", value);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.finish(hartid)
+}
+
+// Like QEMU, the DTB is placed at the end of DRAM, aligned to 2 MiB.
+pub fn load_address(dtb: &[u8]) -> u32 {
+    let end = DRAM_BASE + MEMORY_SIZE;
+    (end - dtb.len() as u32) & !(0x20_0000 - 1)
+}
@@ -18,6 +18,7 @@ pub enum Exception {
     StoreAMOPageFault,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum Interrupt {
     UserSoftwareInterrupt,
     SupervisorSoftwareInterrupt,
@@ -17,6 +17,45 @@ const FFLAGS_NV: u8 = 1 << 4; // Invalid Operation
 
 pub static mut FCSR: u32 = 0;
 
+/*
+    (11.3) NaN Generation and Propagation
+
+    Except when otherwise stated, if the result of a floating-point operation is NaN, it is the
+    canonical NaN. The canonical NaN has a positive sign and all significand bits clear except the
+    MSB, a.k.a. the quiet bit.
+
+    softfloat is built with the RISC-V specialization, which also gives the canonical NaN and the
+    RISC-V results of out-of-range conversions to integers.
+*/
+const CANONICAL_NAN_32: u32 = 0x7fc0_0000;
+
+/*
+    (12.2) NaN Boxing of Narrower Values
+
+    When multiple floating-point precisions are supported, then valid values of narrower n-bit types,
+    n < FLEN, are represented in the lower n bits of an FLEN-bit NaN value, in a process termed
+    NaN-boxing. The upper bits of a valid NaN-boxed value must be all 1s. ... Floating-point n-bit
+    transfer operations move external values held in IEEE standard formats into and out of the f
+    registers, and comprise floating-point loads and stores (FLn/FSn) and floating-point move
+    instructions (FMV.n.X/FMV.X.n). ... Apart from transfer operations described in the previous
+    paragraph, all other floating-point operations on narrower n-bit operations, n < FLEN, check if
+    the input operands are correctly NaN-boxed, i.e., all upper FLEN-n bits are 1. If so, the n
+    least-significant bits of the input are used as the input value, otherwise the input value is
+    treated as an n-bit canonical NaN.
+*/
+pub fn nan_box(f: f32) -> f64 {
+    f64::from_bits(0xffff_ffff_0000_0000 | f.to_bits() as u64)
+}
+
+pub fn unbox(f: f64) -> f32 {
+    let bits = f.to_bits();
+    if bits >> 32 == 0xffff_ffff {
+        f32::from_bits(bits as u32)
+    } else {
+        f32::from_bits(CANONICAL_NAN_32)
+    }
+}
+
 fn rnd_from_u32(rnd: u32) -> Result<RoundingMode, Exception> {
     match rnd {
         0b000 => Ok(RoundingMode::TiesToEven),
@@ -35,33 +74,47 @@ fn soft_float<U: num_traits::Unsigned, F: Float>(a: U, f: fn(U) -> F) -> F {
 }
 
 fn write_fflags(f: ExceptionFlags) {
-    let flags = if f.is_inexact() {
-        FFLAGS_NX
-    } else if f.is_underflow() {
-        FFLAGS_UF
-    } else if f.is_overflow() {
-        FFLAGS_OF
-    } else if f.is_invalid() {
-        FFLAGS_NV
-    } else {
-        0
-    };
+    let mut flags = 0;
+    if f.is_inexact() {
+        flags |= FFLAGS_NX;
+    }
+    if f.is_underflow() {
+        flags |= FFLAGS_UF;
+    }
+    if f.is_overflow() {
+        flags |= FFLAGS_OF;
+    }
+    if f.is_infinite() {
+        flags |= FFLAGS_DZ;
+    }
+    if f.is_invalid() {
+        flags |= FFLAGS_NV;
+    }
     unsafe {
         FCSR |= flags as u32;
     }
 }
 
-fn read_frm() -> Result<RoundingMode, Exception> {
-    Ok(RoundingMode::TiesToEven)
-}
-
-fn f_arithmetic<F: Float>(a: F, b: F, rnd: RoundingMode, f: fn(&F, F, RoundingMode) -> F) -> F {
+// Runs softfloat operations and accrues the exception flags they raise in fflags.
+fn accrue<T>(f: impl FnOnce() -> T) -> T {
     let mut flag = ExceptionFlags::default();
     flag.set();
-    let c = f(&a, b, rnd);
+    let c = f();
     flag.get();
     write_fflags(flag);
-    return c;
+    c
+}
+
+fn read_frm() -> Result<RoundingMode, Exception> {
+    match unsafe { (FCSR >> 5) & 0b111 } {
+        // The dynamic rounding mode itself is not a valid value of frm.
+        0b111 => Err(Exception::IllegalInstruction),
+        frm => rnd_from_u32(frm),
+    }
+}
+
+fn f_arithmetic<F: Float>(a: F, b: F, rnd: RoundingMode, f: fn(&F, F, RoundingMode) -> F) -> F {
+    accrue(|| f(&a, b, rnd))
 }
 
 pub fn fadd_32(fa: f32, fb: f32, funct3: u32) -> Result<f32, Exception> {
@@ -113,42 +166,32 @@ pub fn fmul_64(fa: f64, fb: f64, funct3: u32) -> Result<f64, Exception> {
 }
 
 pub fn fdiv_32(fa: f32, fb: f32, funct3: u32) -> Result<f32, Exception> {
-    if fb != 0.0 {
-        let a = soft_float(fa.to_bits(), F32::from_bits);
-        let b = soft_float(fb.to_bits(), F32::from_bits);
-        let rnd = rnd_from_u32(funct3)?;
-        let c = f_arithmetic(a, b, rnd, F32::div);
-        Ok(f32::from_bits(c.bits()))
-    } else {
-        unsafe { FCSR |= FFLAGS_DZ as u32 };
-        Ok(f32::INFINITY)
-    }
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    let rnd = rnd_from_u32(funct3)?;
+    let c = f_arithmetic(a, b, rnd, F32::div);
+    Ok(f32::from_bits(c.bits()))
 }
 
 pub fn fdiv_64(fa: f64, fb: f64, funct3: u32) -> Result<f64, Exception> {
-    if fb != 0.0 {
-        let a = soft_float(fa.to_bits(), F64::from_bits);
-        let b = soft_float(fb.to_bits(), F64::from_bits);
-        let rnd = rnd_from_u32(funct3)?;
-        let c = f_arithmetic(a, b, rnd, F64::div);
-        Ok(f64::from_bits(c.bits()))
-    } else {
-        unsafe { FCSR |= FFLAGS_DZ as u32 };
-        Ok(f64::INFINITY)
-    }
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    let rnd = rnd_from_u32(funct3)?;
+    let c = f_arithmetic(a, b, rnd, F64::div);
+    Ok(f64::from_bits(c.bits()))
 }
 
 pub fn fsqrt_32(fa: f32, funct3: u32) -> Result<f32, Exception> {
     let a = soft_float(fa.to_bits(), F32::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    let c = a.sqrt(rnd);
+    let c = accrue(|| a.sqrt(rnd));
     Ok(f32::from_bits(c.bits()))
 }
 
 pub fn fsqrt_64(fa: f64, funct3: u32) -> Result<f64, Exception> {
     let a = soft_float(fa.to_bits(), F64::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    let c = a.sqrt(rnd);
+    let c = accrue(|| a.sqrt(rnd));
     Ok(f64::from_bits(c.bits()))
 }
 
@@ -188,18 +231,21 @@ fn fsgnj<F: Float>(mut a: F, b: F, funct3: u32) -> Result<F, Exception> {
     return Ok(a);
 }
 
+const QUIET_BIT_32: u32 = 1 << 22;
+const QUIET_BIT_64: u64 = 1 << 51;
+
 pub fn fclass_32(fa: f32) -> u32 {
     let a = soft_float(fa.to_bits(), F32::from_bits);
-    return fclass(a);
+    return fclass(a, fa.to_bits() & QUIET_BIT_32 != 0);
 }
 
 pub fn fclass_64(fa: f64) -> u32 {
     let a = soft_float(fa.to_bits(), F64::from_bits);
-    return fclass(a);
+    return fclass(a, fa.to_bits() & QUIET_BIT_64 != 0);
 }
 
-fn fclass<F: Float>(a: F) -> u32 {
-    if a.is_positive_infinity() {
+fn fclass<F: Float>(a: F, quiet: bool) -> u32 {
+    if a.is_negative_infinity() {
         1
     } else if a.is_negative_normal() {
         1 << 1
@@ -215,94 +261,200 @@ fn fclass<F: Float>(a: F) -> u32 {
         1 << 6
     } else if a.is_positive_infinity() {
         1 << 7
-    } else if a.is_nan() {
+    } else if a.is_nan() && !quiet {
         1 << 8
+    } else if a.is_nan() {
+        1 << 9
     } else {
         0
     }
 }
 
-pub fn fmin<T: num_traits::Float>(fa: T, fb: T) -> T {
-    fa.min(fb)
+/*
+    (11.6) Single-Precision Floating-Point Computational Instructions
+
+    Floating-point minimum-number and maximum-number instructions FMIN.S and FMAX.S write,
+    respectively, the smaller or larger of rs1 and rs2 to rd. For the purposes of these
+    instructions only, the value -0.0 is considered to be less than the value +0.0. If both inputs
+    are NaNs, the result is the canonical NaN. If only one operand is a NaN, the result is the
+    non-NaN operand. Signaling NaN inputs set the invalid operation exception flag, even when the
+    result is not NaN.
+*/
+fn fmin_max<F: Float + Copy>(a: F, b: F, max: bool) -> F {
+    // The quiet comparison only raises the invalid operation exception for signaling NaNs.
+    let lt = accrue(|| a.lt_quiet(b));
+    if a.is_nan() && b.is_nan() {
+        F::quiet_nan()
+    } else if a.is_nan() {
+        b
+    } else if b.is_nan() {
+        a
+    } else if a.is_zero() && b.is_zero() {
+        if a.is_negative() != max {
+            a
+        } else {
+            b
+        }
+    } else if lt != max {
+        a
+    } else {
+        b
+    }
+}
+
+pub fn fmin_32(fa: f32, fb: f32) -> f32 {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    f32::from_bits(fmin_max(a, b, false).bits())
+}
+
+pub fn fmin_64(fa: f64, fb: f64) -> f64 {
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    f64::from_bits(fmin_max(a, b, false).bits())
 }
 
-pub fn fmax<T: num_traits::Float>(fa: T, fb: T) -> T {
-    fa.max(fb)
+pub fn fmax_32(fa: f32, fb: f32) -> f32 {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    f32::from_bits(fmin_max(a, b, true).bits())
+}
+
+pub fn fmax_64(fa: f64, fb: f64) -> f64 {
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    f64::from_bits(fmin_max(a, b, true).bits())
 }
 
-pub fn fle<T: num_traits::Float>(fa: T, fb: T) -> u32 {
-    (fa <= fb) as u32
+/*
+    (11.8) Single-Precision Floating-Point Compare Instructions
+
+    FEQ.S performs a quiet comparison: it only sets the invalid operation exception flag if either
+    input is a signaling NaN. FLT.S and FLE.S perform what the IEEE 754-2008 standard refers to as
+    signaling comparisons: that is, they set the invalid operation exception flag if either input is
+    NaN. For all three instructions, the result is 0 if either operand is NaN.
+*/
+pub fn fle_32(fa: f32, fb: f32) -> u32 {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    accrue(|| a.le(b)) as u32
 }
 
-pub fn flt<T: num_traits::Float>(fa: T, fb: T) -> u32 {
-    (fa < fb) as u32
+pub fn fle_64(fa: f64, fb: f64) -> u32 {
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    accrue(|| a.le(b)) as u32
 }
 
-pub fn feq<T: num_traits::Float>(fa: T, fb: T) -> u32 {
-    (fa == fb) as u32
+pub fn flt_32(fa: f32, fb: f32) -> u32 {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    accrue(|| a.lt(b)) as u32
+}
+
+pub fn flt_64(fa: f64, fb: f64) -> u32 {
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    accrue(|| a.lt(b)) as u32
+}
+
+pub fn feq_32(fa: f32, fb: f32) -> u32 {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let b = soft_float(fb.to_bits(), F32::from_bits);
+    accrue(|| a.eq(b)) as u32
+}
+
+pub fn feq_64(fa: f64, fb: f64) -> u32 {
+    let a = soft_float(fa.to_bits(), F64::from_bits);
+    let b = soft_float(fb.to_bits(), F64::from_bits);
+    accrue(|| a.eq(b)) as u32
 }
 
 // f64 -> f32
 pub fn fcvt_s_d(fa: f64, funct3: u32) -> Result<f32, Exception> {
     let a = soft_float(fa.to_bits(), F64::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    let c = a.to_f32(rnd);
+    let c = accrue(|| a.to_f32(rnd));
     Ok(f32::from_bits(c.bits()))
 }
 
+// f32 -> f64
+pub fn fcvt_d_s(fa: f32, funct3: u32) -> Result<f64, Exception> {
+    let a = soft_float(fa.to_bits(), F32::from_bits);
+    let rnd = rnd_from_u32(funct3)?;
+    let c = accrue(|| a.to_f64(rnd));
+    Ok(f64::from_bits(c.bits()))
+}
+
+/*
+    softfloat only raises the inexact exception in the conversions to integers if asked to, which
+    the wrapper does not, so it is raised here when the value is not an integer and is in range.
+*/
+fn to_integer<F: Float + Copy, T>(a: F, rnd: RoundingMode, f: impl FnOnce(&F) -> T) -> T {
+    let mut flag = ExceptionFlags::default();
+    flag.set();
+    let c = f(&a);
+    flag.get();
+    write_fflags(flag);
+    if !flag.is_invalid() && a.round_to_integral(rnd).bits() != a.bits() {
+        unsafe { FCSR |= FFLAGS_NX as u32 };
+    }
+    c
+}
+
 // f32 -> i32
 pub fn fcvt_w_s(fa: f32, funct3: u32) -> Result<i32, Exception> {
     let a = soft_float(fa.to_bits(), F32::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    Ok(a.to_i32(rnd))
+    Ok(to_integer(a, rnd, |a| a.to_i32(rnd)))
 }
 
 // f64 -> i32
 pub fn fcvt_w_d(fa: f64, funct3: u32) -> Result<i32, Exception> {
     let a = soft_float(fa.to_bits(), F64::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    Ok(a.to_i32(rnd))
+    Ok(to_integer(a, rnd, |a| a.to_i32(rnd)))
 }
 
 // f32 -> u32
 pub fn fcvt_wu_s(fa: f32, funct3: u32) -> Result<u32, Exception> {
     let a = soft_float(fa.to_bits(), F32::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    Ok(a.to_u32(rnd))
+    Ok(to_integer(a, rnd, |a| a.to_u32(rnd)))
 }
 
 // f64 -> u32
 pub fn fcvt_wu_d(fa: f64, funct3: u32) -> Result<u32, Exception> {
     let a = soft_float(fa.to_bits(), F64::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    Ok(a.to_u32(rnd))
+    Ok(to_integer(a, rnd, |a| a.to_u32(rnd)))
 }
 
 // i32 -> f32
 pub fn fcvt_s_w(i: i32, funct3: u32) -> Result<f32, Exception> {
     let rnd = rnd_from_u32(funct3)?;
-    let c = F32::from_i32(i, rnd);
+    let c = accrue(|| F32::from_i32(i, rnd));
     Ok(f32::from_bits(c.bits()))
 }
 
 // i32 -> f64
 pub fn fcvt_d_w(i: i32, funct3: u32) -> Result<f64, Exception> {
     let rnd = rnd_from_u32(funct3)?;
-    let c = F64::from_i32(i, rnd);
+    let c = accrue(|| F64::from_i32(i, rnd));
     Ok(f64::from_bits(c.bits()))
 }
 
 // u32 -> f32
 pub fn fcvt_s_wu(u: u32, funct3: u32) -> Result<f32, Exception> {
     let rnd = rnd_from_u32(funct3)?;
-    let c = F32::from_u32(u, rnd);
+    let c = accrue(|| F32::from_u32(u, rnd));
     Ok(f32::from_bits(c.bits()))
 }
 
 // u32 -> f64
 pub fn fcvt_d_wu(u: u32, funct3: u32) -> Result<f64, Exception> {
     let rnd = rnd_from_u32(funct3)?;
-    let c = F64::from_u32(u, rnd);
+    let c = accrue(|| F64::from_u32(u, rnd));
     Ok(f64::from_bits(c.bits()))
 }
 
@@ -311,7 +463,7 @@ pub fn fmadd_32(fa: f32, fb: f32, fc: f32, funct3: u32) -> Result<f32, Exception
     let b = soft_float(fb.to_bits(), F32::from_bits);
     let c = soft_float(fc.to_bits(), F32::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    let d = a.fused_mul_add(b, c, rnd);
+    let d = accrue(|| a.fused_mul_add(b, c, rnd));
     Ok(f32::from_bits(d.bits()))
 }
 
@@ -320,6 +472,6 @@ pub fn fmadd_64(fa: f64, fb: f64, fc: f64, funct3: u32) -> Result<f64, Exception
     let b = soft_float(fb.to_bits(), F64::from_bits);
     let c = soft_float(fc.to_bits(), F64::from_bits);
     let rnd = rnd_from_u32(funct3)?;
-    let d = a.fused_mul_add(b, c, rnd);
+    let d = accrue(|| a.fused_mul_add(b, c, rnd));
     Ok(f64::from_bits(d.bits()))
 }
@@ -0,0 +1,464 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::Cpu;
+use crate::replay;
+use crate::snapshot::{Reader, Snapshot, Writer};
+
+/*
+    GDB Remote Serial Protocol
+
+    A packet is $<data>#<checksum>, where the checksum is the sum of the data modulo 256 in two
+    hex digits, and the receiver acknowledges each packet with +. Ctrl-C (0x03) sent outside a
+    packet interrupts the target.
+
+    ?                        Reports why the target stopped
+    g / G<regs>              Reads / writes all registers
+    p<n> / P<n>=<value>      Reads / writes register n
+    m<addr>,<len>            Reads memory
+    M<addr>,<len>:<data>     Writes memory
+    c / s                    Continues / steps
+    bc / bs                  Continues / steps backward
+    Z0,<addr>,<kind> / z0    Inserts / removes a breakpoint (Z1 and z1 as well)
+    qXfer:features:read:target.xml:<offset>,<len>
+                             Reads the description of the registers
+    D / k                    Detaches / kills the target
+
+    The registers are x0-x31 (0-31), pc (32), f0-f31 (33-64), fflags (65), frm (66) and fcsr (67).
+    Integers are sent in the target byte order, which is little-endian.
+*/
+const PC: usize = 32;
+const F0: usize = 33;
+const FFLAGS: usize = 65;
+const FRM: usize = 66;
+const FCSR: usize = 67;
+const NUM_REGS: usize = 68;
+
+const X_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+const F_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+// Stop replies. SIGTRAP after a step or a breakpoint, and SIGINT after Ctrl-C.
+const STOPPED: &str = "S05";
+const INTERRUPTED: &str = "S02";
+// Reverse execution reached the first checkpoint, before which nothing was recorded.
+const BEGIN: &str = "T05replaylog:begin;";
+
+const INTERRUPT: u8 = 0x03;
+// The connection is checked for Ctrl-C every this many steps while running.
+const INTERRUPT_INTERVAL: u64 = 0x10000;
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for name in X_NAMES {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\"/>", name);
+    }
+    xml += "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/></feature>";
+    xml += "<feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for name in F_NAMES {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"ieee_double\"/>",
+            name
+        );
+    }
+    for name in ["fflags", "frm", "fcsr"] {
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"int\"/>", name);
+    }
+    xml + "</feature></target>"
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// Parses <addr>,<len>.
+fn range(s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((number(addr)?, number(len)?))
+}
+
+/*
+    Reverse execution
+
+    A step is one instruction retired or one trap taken. The machine is checkpointed every
+    `interval` steps with the position in the replay log. To go back to step n, the latest
+    checkpoint at or before n is restored, and the machine runs forward to n while its inputs are
+    replayed from the log, so it goes through exactly the same states as before.
+*/
+struct Checkpoint {
+    step: u64,
+    state: Vec<u8>,
+    log: usize,
+}
+
+pub struct Gdb<'a> {
+    stream: TcpStream,
+    cpu: &'a mut Cpu,
+    // Executes one step. Returns the exit status when the machine stops.
+    step: &'a mut dyn FnMut(&mut Cpu) -> Option<i32>,
+    breakpoints: BTreeSet<u32>,
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    // The number of steps since the debugger attached.
+    steps: u64,
+    // The number of steps executed for the first time. Steps before it are executed again.
+    latest: u64,
+}
+
+/*
+    Waits for the debugger on the TCP port of localhost and serves it. Returns the exit status
+    when the machine stops or the debugger kills it, or None when the debugger detaches.
+*/
+pub fn serve(
+    port: u16,
+    cpu: &mut Cpu,
+    interval: u64,
+    step: &mut dyn FnMut(&mut Cpu) -> Option<i32>,
+) -> io::Result<Option<i32>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on port {}", port);
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    replay::start_recording();
+    let mut gdb = Gdb {
+        stream,
+        cpu,
+        step,
+        breakpoints: BTreeSet::new(),
+        checkpoints: Vec::new(),
+        interval: interval.max(1),
+        steps: 0,
+        latest: 0,
+    };
+    gdb.checkpoint();
+    let status = gdb.run();
+    replay::set_muted(false);
+    status
+}
+
+impl Gdb<'_> {
+    fn run(&mut self) -> io::Result<Option<i32>> {
+        loop {
+            let packet = match self.recv()? {
+                Some(packet) => packet,
+                // The debugger closed the connection.
+                None => return Ok(None),
+            };
+            let reply = match packet.as_str() {
+                "?" => STOPPED.to_string(),
+                "g" => self.read_registers(),
+                "c" => self.resume(),
+                "s" => self.forward().map_or(STOPPED.to_string(), exited),
+                "bc" => self.reverse_continue().to_string(),
+                "bs" => self.reverse_step().to_string(),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(None);
+                }
+                "k" => return Ok(Some(0)),
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ if packet.starts_with("qSupported") => {
+                    "PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string()
+                }
+                _ if packet.starts_with('H') => "OK".to_string(),
+                _ => self.handle(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+            self.send(&reply)?;
+            // The machine stopped.
+            if let Some(status) = reply.strip_prefix('W') {
+                return Ok(Some(i32::from_str_radix(status, 16).unwrap_or(0)));
+            }
+        }
+    }
+
+    // Handles the packets with arguments. Returns None for a malformed packet.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let (kind, args) = packet.split_at(1);
+        match kind {
+            "G" => {
+                let data = unhex(args)?;
+                for (n, chunk) in data.chunks(4).enumerate().take(F0) {
+                    self.write_register(n, chunk);
+                }
+                self.diverge();
+                Some("OK".to_string())
+            }
+            "p" => Some(hex(&self.register(number(args)? as usize)?)),
+            "P" => {
+                let (n, value) = args.split_once('=')?;
+                let n = number(n)? as usize;
+                if n >= NUM_REGS {
+                    return None;
+                }
+                self.write_register(n, &unhex(value)?);
+                self.diverge();
+                Some("OK".to_string())
+            }
+            "m" => {
+                let (addr, len) = range(args)?;
+                let data: Vec<u8> = (0..len)
+                    .map_while(|i| self.peek(addr.wrapping_add(i)))
+                    .collect();
+                (!data.is_empty() || len == 0).then(|| hex(&data))
+            }
+            "M" => {
+                let (range_, data) = args.split_once(':')?;
+                let (addr, _) = range(range_)?;
+                let data = unhex(data)?;
+                let ok = data
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &b)| self.poke(addr.wrapping_add(i as u32), b).is_some());
+                self.diverge();
+                ok.then(|| "OK".to_string())
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next()?;
+                let addr = number(fields.next()?)?;
+                if kind != "0" && kind != "1" {
+                    // Watchpoints are not supported.
+                    return Some(String::new());
+                }
+                if packet.starts_with('Z') {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                Some("OK".to_string())
+            }
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                let (offset, len) = range(args.rsplit(':').next()?)?;
+                let xml = target_xml();
+                let start = (offset as usize).min(xml.len());
+                let end = (start + len as usize).min(xml.len());
+                let more = if end < xml.len() { 'm' } else { 'l' };
+                Some(format!("{}{}", more, &xml[start..end]))
+            }
+            // An empty reply tells that the packet is not supported.
+            _ => Some(String::new()),
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<Vec<u8>> {
+        let fcsr = unsafe { crate::fpu::FCSR };
+        let value = match n {
+            0..=31 => self.cpu.xregs[n].to_le_bytes().to_vec(),
+            PC => self.cpu.pc.to_le_bytes().to_vec(),
+            F0..FFLAGS => self.cpu.fregs[n - F0].to_bits().to_le_bytes().to_vec(),
+            FFLAGS => (fcsr & 0x1F).to_le_bytes().to_vec(),
+            FRM => (fcsr >> 5 & 0b111).to_le_bytes().to_vec(),
+            FCSR => (fcsr & 0xFF).to_le_bytes().to_vec(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS)
+            .filter_map(|n| self.register(n))
+            .map(|value| hex(&value))
+            .collect()
+    }
+
+    fn write_register(&mut self, n: usize, value: &[u8]) {
+        let mut bytes = [0; 8];
+        let len = value.len().min(8);
+        bytes[..len].copy_from_slice(&value[..len]);
+        let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let fcsr = unsafe { crate::fpu::FCSR };
+        match n {
+            // x0 is hardwired to 0.
+            1..=31 => self.cpu.xregs[n] = word,
+            PC => self.cpu.pc = word,
+            F0..FFLAGS => self.cpu.fregs[n - F0] = f64::from_bits(u64::from_le_bytes(bytes)),
+            FFLAGS => unsafe { crate::fpu::FCSR = fcsr & !0x1F | word & 0x1F },
+            FRM => unsafe { crate::fpu::FCSR = fcsr & !0xE0 | (word & 0b111) << 5 },
+            FCSR => unsafe { crate::fpu::FCSR = word & 0xFF },
+            _ => {}
+        }
+    }
+
+    fn peek(&self, va: u32) -> Option<u8> {
+        let pa = self.cpu.debug_translate(va)?;
+        Some(self.cpu.ram.peek(pa, 1)?[0])
+    }
+
+    fn poke(&mut self, va: u32, byte: u8) -> Option<()> {
+        let pa = self.cpu.debug_translate(va)?;
+        self.cpu.decode_cache.invalidate(pa, 1);
+        self.cpu.ram.poke(pa, &[byte])
+    }
+
+    // Reads a packet, skipping acknowledgments and interrupts. Returns None at EOF.
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+
+    // Whether the debugger sent Ctrl-C.
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        self.stream.set_nonblocking(true).ok();
+        let interrupted = matches!(self.stream.read(&mut byte), Ok(1) if byte[0] == INTERRUPT);
+        self.stream.set_nonblocking(false).ok();
+        interrupted
+    }
+
+    fn checkpoint(&mut self) {
+        let mut w = Writer::new();
+        self.cpu.save(&mut w);
+        self.checkpoints.push(Checkpoint {
+            step: self.steps,
+            state: w.finish(),
+            log: replay::position(),
+        });
+    }
+
+    fn restore(&mut self, i: usize) {
+        let checkpoint = &self.checkpoints[i];
+        let mut r = Reader::new(&checkpoint.state).expect("invalid checkpoint");
+        self.cpu.restore(&mut r).expect("invalid checkpoint");
+        replay::rewind(checkpoint.log);
+        self.steps = checkpoint.step;
+    }
+
+    // The debugger changed the state, so the execution recorded after this step is no longer valid.
+    fn diverge(&mut self) {
+        let steps = self.steps;
+        self.checkpoints.retain(|c| c.step < steps);
+        self.latest = steps;
+        replay::truncate();
+        self.checkpoint();
+    }
+
+    // Executes one step. Returns the exit status when the machine stops.
+    fn forward(&mut self) -> Option<i32> {
+        let last = self.checkpoints.last().map_or(0, |c| c.step);
+        if self.steps >= last + self.interval {
+            self.checkpoint();
+        }
+        replay::set_muted(self.steps < self.latest);
+        self.steps += 1;
+        self.latest = self.latest.max(self.steps);
+        (self.step)(self.cpu)
+    }
+
+    fn resume(&mut self) -> String {
+        loop {
+            if let Some(status) = self.forward() {
+                return exited(status);
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return STOPPED.to_string();
+            }
+            if self.steps.is_multiple_of(INTERRUPT_INTERVAL) && self.interrupted() {
+                return INTERRUPTED.to_string();
+            }
+        }
+    }
+
+    // Goes back to the state after the step.
+    fn goto(&mut self, step: u64) {
+        let i = self
+            .checkpoints
+            .iter()
+            .rposition(|c| c.step <= step)
+            .unwrap_or(0);
+        self.restore(i);
+        while self.steps < step {
+            self.forward();
+        }
+    }
+
+    fn reverse_step(&mut self) -> &'static str {
+        if self.steps == self.checkpoints[0].step {
+            return BEGIN;
+        }
+        self.goto(self.steps - 1);
+        STOPPED
+    }
+
+    // Goes back to the last state before the current one where pc is at a breakpoint.
+    fn reverse_continue(&mut self) -> &'static str {
+        let end = self.steps;
+        for i in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[i].step;
+            if start >= end {
+                continue;
+            }
+            let limit = self.checkpoints.get(i + 1).map_or(end, |c| c.step.min(end));
+            self.restore(i);
+            let mut hit = None;
+            while self.steps < limit {
+                if self.breakpoints.contains(&self.cpu.pc) {
+                    hit = Some(self.steps);
+                }
+                self.forward();
+            }
+            if let Some(step) = hit {
+                self.goto(step);
+                return STOPPED;
+            }
+        }
+        self.goto(self.checkpoints[0].step);
+        BEGIN
+    }
+}
+
+fn exited(status: i32) -> String {
+    format!("W{:02x}", status as u8)
+}
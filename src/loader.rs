@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+
+use crate::memory::Memory;
+
+pub struct Image {
+    pub entry: u32,
+    // The address just after the image.
+    pub end: u32,
+}
+
+/*
+    Loads an ELF file or a raw binary into DRAM.
+
+    The PT_LOAD segments of an ELF file are loaded at their physical addresses and the entry point
+    is taken from the ELF header. A raw binary, such as fw_dynamic.bin or the Linux Image, is loaded
+    at `addr` and starts at its first byte.
+*/
+pub fn load(ram: &mut Memory, filename: &str, addr: u32) -> io::Result<Image> {
+    let binary = fs::read(filename)?;
+    if !binary.starts_with(b"\x7fELF") {
+        load_segment(ram, addr, &binary);
+        return Ok(Image {
+            entry: addr,
+            end: addr + binary.len() as u32,
+        });
+    }
+
+    let elf = elf::File::open_stream(&mut io::Cursor::new(&binary))
+        .unwrap_or_else(|e| panic!("{}: invalid ELF file: {:?}", filename, e));
+    let mut end = 0;
+    for ph in elf
+        .phdrs
+        .iter()
+        .filter(|ph| ph.progtype == elf::types::PT_LOAD)
+    {
+        let start = ph.offset as usize;
+        load_segment(
+            ram,
+            ph.paddr as u32,
+            &binary[start..start + ph.filesz as usize],
+        );
+        // The rest of the segment (.bss) is zero-filled already.
+        end = end.max((ph.paddr + ph.memsz) as u32);
+    }
+    Ok(Image {
+        entry: elf.ehdr.entry as u32,
+        end,
+    })
+}
+
+fn load_segment(ram: &mut Memory, addr: u32, data: &[u8]) {
+    ram.load(addr, data)
+        .unwrap_or_else(|_| panic!("the image at {:#x} does not fit in DRAM", addr));
+}
+
+// Returns the address of write_tohost of riscv-tests, or 0 if the file doesn't have the symbol.
+pub fn get_write_tohost_address(filename: &str) -> u32 {
+    let file = match elf::File::open_path(filename) {
+        Ok(file) => file,
+        Err(_) => return 0,
+    };
+    let symtab = match file.get_section(".symtab") {
+        Some(symtab) => symtab,
+        None => return 0,
+    };
+    let symbols = file.get_symbols(symtab).unwrap();
+
+    for s in symbols {
+        if s.name == "write_tohost" {
+            return s.value as u32;
+        }
+    }
+    0
+}
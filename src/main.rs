@@ -1,56 +1,507 @@
 extern crate elf;
 
 use std::env;
-use std::fs::File;
 use std::io;
-use std::io::prelude::*;
 
 mod bits;
+mod bootrom;
 mod cpu;
+mod devices;
+mod dtb;
 mod exception;
 mod fpu;
+mod gdb;
+mod loader;
 mod memory;
+mod png;
+mod replay;
+mod snapshot;
 
-use cpu::Cpu;
+use cpu::{parse_isa, Cpu, Tlb, MARCHID, MCONFIGPTR, MHARTID, MIMPID, MVENDORID};
+use devices::{
+    Chardev, Finish, Framebuffer, Netdev, PixelFormat, VirtioBlk, VirtioConsole, VirtioNet,
+    VirtioRng,
+};
+use memory::{DRAM_BASE, MEMORY_SIZE, MROM_BASE};
+
+const A0: usize = 10;
+const A1: usize = 11;
+
+// The MAC address of the first network device. The others count up from it.
+const MAC_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+// The machine is checkpointed for reverse execution every this many steps by default.
+const CHECKPOINT_INTERVAL: u64 = 1_000_000;
+
+// RV32 kernels are loaded at a 4 MiB (megapage) boundary after the firmware.
+const KERNEL_ALIGN: u32 = 0x40_0000;
+
+const USAGE: &str = "Usage: rv32g-emulator [options] <filename>
+       rv32g-emulator [options] --bios <firmware> [--kernel <kernel>]
+Options:
+    --isa <isa>          Implemented extensions, e.g. rv32imac (default: rv32imafdc)
+    --svadu              Update A/D bits of PTEs by hardware
+    --tlb-entries <n>    Number of TLB entries (default: 64, 0 disables the TLB)
+    --tlb-unified        Share one TLB between instructions and data
+    --tlb-stats          Print TLB statistics at exit
+    --mvendorid <n>      Value of mvendorid (default: 0)
+    --marchid <n>        Value of marchid (default: 0)
+    --mimpid <n>         Value of mimpid (default: 0)
+    --mhartid <n>        Value of mhartid (default: 0)
+    --mconfigptr <n>     Value of mconfigptr (default: 0)
+    --boot-rom           Start from a boot ROM which jumps to the image with a0=mhartid, a1=DTB
+    --reset-vector <n>   Address of the first instruction (default: the boot ROM or the ELF entry)
+    --dtb <file>         Pass the device tree blob in the file instead of the generated one
+    --dump-dtb <file>    Write the generated device tree blob to the file and exit
+    --bios <file>        Firmware such as OpenSBI fw_jump.elf or fw_dynamic.bin, loaded at the
+                         start of DRAM and started from the boot ROM
+    --kernel <file>      Payload of the firmware, loaded at the next 4 MiB boundary
+    --initrd <file>      Initial ramdisk for the kernel
+    --append <cmdline>   Kernel command line
+    --drive <file>[,readonly][,snapshot]
+                         Add a virtio block device backed by a raw disk image. With snapshot,
+                         writes go to a copy-on-write overlay in memory and the image is unchanged
+    --serial <chardev>   Connect the UART to the character device (default: stdio)
+    --console <chardev>  Add a port to the virtio console. The first port is the console (hvc0),
+                         and more ports are added through the multiport feature
+    --rng <seed>|host    Add a virtio entropy device fed by a PRNG with the seed, or by the host
+    --rtc <seconds>|host Start the real-time clock at the seconds since the Unix epoch and advance
+                         it by the emulated time, or follow the host time (default: host)
+    --net <netdev>[,pcap=<file>]
+                         Add a virtio network device. With pcap, the traffic is also captured
+    --fb <w>x<h>[,<format>]
+                         Add a framebuffer. The format is r5g6b5, r8g8b8, x8r8g8b8 (default),
+                         a8r8g8b8 or a8b8g8r8
+    --fb-dump <file>     Save the framebuffer to the file (.png or .ppm) at exit. Screenshots
+                         requested by the guest are saved next to it as <name>-<frame>.<ext>
+    --fb-every <n>       Also save a screenshot every n frames (60 frames per emulated second)
+    --save <file>        Save a snapshot of the machine to the file and exit after the number of
+                         instructions given by --save-at
+    --save-at <n>        Number of instructions retired since power-on before saving
+    --restore <file>     Restore a snapshot and continue from it. The other options must be the
+                         same as when it was saved, and the firmware may be omitted
+    --record <file>      Record the inputs from the host (time, character devices, network and
+                         host entropy) to the file at exit
+    --replay <file>      Take the inputs from a recording instead of the host, which repeats the
+                         recorded execution. The other options must be the same
+    --gdb <port>         Wait for GDB on the TCP port of localhost before starting. Reverse
+                         execution (reverse-step, reverse-continue) is supported
+    --checkpoint-interval <n>
+                         Checkpoint the machine for reverse execution every n instructions
+                         (default: 1000000)
+    --deterministic      Derive the time from the number of instructions instead of the host
+                         clock, start the real-time clock at 0 unless --rtc is given and feed
+                         --rng host by a PRNG with the seed, so that runs without input from the
+                         host are identical. --rtc host is rejected. stdin and the other inputs
+                         of character devices and --net (except file: and pcap:) still arrive at
+                         host-dependent times, so a warning is printed for them unless replaying
+    --seed <n>           Seed of the randomness with --deterministic (default: 0)
+Character devices:
+    stdio                stdin and stdout
+    file:<path>          Write output to the file
+    pipe:<path>          Read input from <path>.in and write output to <path>.out
+    unix:<path>          Listen on a Unix domain socket
+Network backends:
+    user                 Built-in gateway 10.0.2.2 which answers ARP, ping and UDP echo (port 7),
+                         and forwards TCP connections to the same port of 127.0.0.1
+    unix:<path>          Listen on a Unix domain socket and exchange length-prefixed frames
+    pcap:<path>          Write the transmitted frames to a pcap file";
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: rv32g-emulator <filename>");
+    let mut cpu = Cpu::new();
+    let mut filename = None;
+    let mut tlb_entries = 64;
+    let mut tlb_split = true;
+    let mut tlb_stats = false;
+    let mut boot_rom = false;
+    let mut reset_vector = None;
+    let mut dtb_file = None;
+    let mut dump_dtb = None;
+    let mut bios = None;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut chosen = dtb::Chosen::default();
+    let mut serial = None;
+    let mut console_ports = Vec::new();
+    let mut nics = 0;
+    let mut fb_dump = None;
+    let mut fb_every = 0;
+    let mut save = None;
+    let mut restore = None;
+    let mut record = None;
+    let mut gdb_port = None;
+    let mut checkpoint_interval = CHECKPOINT_INTERVAL;
+    let mut deterministic = false;
+    let mut seed = 0;
+    let mut rtc_epoch = None;
+    // Options which take values from the host rather than the emulated machine.
+    let mut host_options = Vec::new();
+    // Options which pass input from the host to the guest at host-dependent times.
+    let mut host_inputs = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--isa" => {
+                let isa = args.next().expect("--isa requires an ISA string");
+                cpu.set_isa(parse_isa(&isa).unwrap_or_else(|e| panic!("{}\n{}", e, USAGE)));
+            }
+            "--svadu" => cpu.svadu = true,
+            "--tlb-entries" => {
+                tlb_entries = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--tlb-entries requires a number")
+            }
+            "--tlb-unified" => tlb_split = false,
+            "--tlb-stats" => tlb_stats = true,
+            "--mvendorid" => cpu.csrs[MVENDORID] = parse_number(&arg, args.next()),
+            "--marchid" => cpu.csrs[MARCHID] = parse_number(&arg, args.next()),
+            "--mimpid" => cpu.csrs[MIMPID] = parse_number(&arg, args.next()),
+            "--mhartid" => cpu.csrs[MHARTID] = parse_number(&arg, args.next()),
+            "--mconfigptr" => cpu.csrs[MCONFIGPTR] = parse_number(&arg, args.next()),
+            "--boot-rom" => boot_rom = true,
+            "--reset-vector" => reset_vector = Some(parse_number(&arg, args.next())),
+            "--dtb" => dtb_file = Some(args.next().expect("--dtb requires a file")),
+            "--dump-dtb" => dump_dtb = Some(args.next().expect("--dump-dtb requires a file")),
+            "--bios" => bios = Some(args.next().expect("--bios requires a file")),
+            "--kernel" => kernel = Some(args.next().expect("--kernel requires a file")),
+            "--initrd" => initrd = Some(args.next().expect("--initrd requires a file")),
+            "--drive" => {
+                let drive = args.next().expect("--drive requires a file");
+                cpu.ram.add_virtio(Box::new(open_drive(&drive)?));
+            }
+            "--serial" => serial = Some(open_chardev(&arg, args.next(), &mut host_inputs)?),
+            "--console" => console_ports.push(open_chardev(&arg, args.next(), &mut host_inputs)?),
+            "--rng" => {
+                let rng = match args.next().as_deref() {
+                    Some("host") => VirtioRng::host()?,
+                    seed => VirtioRng::seeded(parse_number(&arg, seed.map(String::from)) as u64),
+                };
+                cpu.ram.add_virtio(Box::new(rng));
+            }
+            "--rtc" => match args.next().as_deref() {
+                Some("host") => {
+                    host_options.push("--rtc host");
+                    rtc_epoch = None;
+                }
+                seconds => rtc_epoch = Some(parse_number(&arg, seconds.map(String::from)) as u64),
+            },
+            "--net" => {
+                let spec = args.next().expect("--net requires a backend");
+                if takes_host_input(&spec) {
+                    host_inputs.push(format!("--net {}", spec));
+                }
+                let netdev = Netdev::open(&spec)?;
+                let mut mac = MAC_ADDRESS;
+                mac[5] += nics;
+                nics += 1;
+                cpu.ram.add_virtio(Box::new(VirtioNet::new(netdev, mac)));
+            }
+            "--fb" => {
+                let fb = args.next().expect("--fb requires a resolution");
+                cpu.ram.framebuffer = Some(parse_framebuffer(&fb));
+            }
+            "--fb-dump" => fb_dump = Some(args.next().expect("--fb-dump requires a file")),
+            "--fb-every" => fb_every = parse_number(&arg, args.next()),
+            "--save" => save = Some(args.next().expect("--save requires a file")),
+            "--save-at" => {
+                cpu.stop_at = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .map(Some)
+                    .expect("--save-at requires a number")
+            }
+            "--restore" => restore = Some(args.next().expect("--restore requires a file")),
+            "--record" => record = Some(args.next().expect("--record requires a file")),
+            "--replay" => replay::load(&args.next().expect("--replay requires a file"))?,
+            "--gdb" => gdb_port = Some(parse_number(&arg, args.next()) as u16),
+            "--checkpoint-interval" => {
+                checkpoint_interval = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--checkpoint-interval requires a number")
+            }
+            "--deterministic" => deterministic = true,
+            "--seed" => seed = parse_number(&arg, args.next()) as u64,
+            "--append" => chosen.bootargs = Some(args.next().expect("--append requires a string")),
+            _ if arg.starts_with('-') => panic!("unknown option: {}\n{}", arg, USAGE),
+            _ => filename = Some(arg),
+        }
+    }
+    cpu.tlb = Tlb::new(tlb_entries, tlb_split);
+    match &mut cpu.ram.framebuffer {
+        Some(fb) => {
+            fb.output = fb_dump;
+            fb.every = fb_every;
+        }
+        None if fb_dump.is_some() || fb_every != 0 => {
+            panic!("--fb-dump and --fb-every require --fb\n{}", USAGE)
+        }
+        None => {}
+    }
+    if save.is_some() != cpu.stop_at.is_some() {
+        panic!("--save and --save-at must be given together\n{}", USAGE);
+    }
+    if save.is_some() && gdb_port.is_some() {
+        panic!("--save and --gdb are exclusive\n{}", USAGE);
+    }
+    if record.is_some() {
+        replay::start_recording();
+    }
+    if deterministic {
+        if let Some(option) = host_options.first() {
+            panic!("{} is not allowed with --deterministic\n{}", option, USAGE);
+        }
+        if !replay::replaying() {
+            for option in &host_inputs {
+                eprintln!(
+                    "warning: {} passes input at host-dependent times. Use --record to repeat the run",
+                    option
+                );
+            }
+        }
+        cpu.ram.clint.deterministic = true;
+        rtc_epoch = rtc_epoch.or(Some(0));
+        for virtio in cpu.ram.virtio.iter_mut() {
+            virtio.seed(seed);
+        }
+    }
+    if let Some(seconds) = rtc_epoch {
+        cpu.ram.rtc.set_epoch(seconds);
+    }
+    cpu.ram.uart.connect(serial.unwrap_or_else(Chardev::stdio));
+    if !console_ports.is_empty() {
+        cpu.ram
+            .add_virtio(Box::new(VirtioConsole::new(console_ports)));
     }
 
-    let mut file = File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.seek(io::SeekFrom::Start(0x1000))?; // skip ELF header
-    file.read_to_end(&mut binary)?;
+    // The firmware is the first stage which runs in M-mode.
+    if bios.is_some() {
+        assert!(
+            filename.is_none(),
+            "<filename> and --bios are exclusive\n{}",
+            USAGE
+        );
+        boot_rom = true;
+    }
+    let firmware = bios.or(filename);
+    if firmware.is_none() && (kernel.is_some() || initrd.is_some()) {
+        panic!("--kernel and --initrd require --bios\n{}", USAGE);
+    }
+    if kernel.is_none() && initrd.is_some() {
+        panic!("--initrd requires --kernel\n{}", USAGE);
+    }
 
-    let mut cpu = Cpu::new();
-    cpu.ram.set(&binary);
+    let mut entry = DRAM_BASE;
+    let mut next_addr = 0;
+    let mut end_address = 0;
+    // The regions of DRAM loaded with images, which are loaded again on reset. (start, end)
+    let mut regions = Vec::new();
+    if let Some(firmware) = &firmware {
+        let fw = loader::load(&mut cpu.ram, firmware, DRAM_BASE)?;
+        regions.push((DRAM_BASE, fw.end));
+        entry = fw.entry;
+        end_address = loader::get_write_tohost_address(firmware);
+        if let Some(kernel) = &kernel {
+            let start = (fw.end + KERNEL_ALIGN - 1) & !(KERNEL_ALIGN - 1);
+            let kernel = loader::load(&mut cpu.ram, kernel, start)?;
+            regions.push((start, kernel.end));
+            next_addr = kernel.entry;
+            if let Some(initrd) = &initrd {
+                // Like QEMU, keep the initrd far enough from the kernel not to be clobbered when the
+                // kernel is uncompressed.
+                let start = kernel.entry + (MEMORY_SIZE / 2).min(128 * 1024 * 1024);
+                let initrd = loader::load(&mut cpu.ram, initrd, start)?;
+                chosen.initrd = Some((start, initrd.end));
+                regions.push((start, initrd.end));
+            }
+        }
+    }
 
-    let end_address = get_write_tohost_address(&args[1]);
+    let dtb = match dtb_file {
+        Some(path) => std::fs::read(path)?,
+        None => dtb::generate(&cpu, &chosen),
+    };
+    if let Some(path) = dump_dtb {
+        return std::fs::write(path, dtb);
+    }
+    if firmware.is_none() && restore.is_none() {
+        panic!("{}", USAGE);
+    }
+    let dtb_address = dtb::load_address(&dtb);
+    cpu.ram
+        .load(dtb_address, &dtb)
+        .expect("the device tree blob is too large");
+    regions.push((dtb_address, dtb_address + dtb.len() as u32));
+    let images: Vec<(u32, Vec<u8>)> = regions
+        .into_iter()
+        .map(|(start, end)| {
+            let offset = (start - DRAM_BASE) as usize;
+            (
+                start,
+                cpu.ram.ram[offset..offset + (end - start) as usize].to_vec(),
+            )
+        })
+        .collect();
+    if boot_rom {
+        cpu.ram.set_rom(bootrom::bootrom(
+            entry,
+            dtb_address,
+            next_addr,
+            cpu.csrs[MHARTID],
+        ));
+    }
+    let start = |cpu: &mut Cpu| {
+        if !boot_rom {
+            // Without the boot ROM, the hart starts with the registers the boot ROM would set.
+            cpu.xregs[A0] = cpu.csrs[MHARTID];
+            cpu.xregs[A1] = dtb_address;
+        }
+        cpu.pc = reset_vector.unwrap_or(if boot_rom { MROM_BASE } else { entry });
+    };
+    start(&mut cpu);
+    // The snapshot replaces the state set up above, and the host side of the devices is attached
+    // again from the options.
+    if let Some(path) = &restore {
+        cpu.restore_snapshot(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    }
+
+    // Restarts the machine reset by the guest with the images loaded again.
+    let restart = |cpu: &mut Cpu| {
+        cpu.reset();
+        for (addr, image) in &images {
+            cpu.ram.load(*addr, image).ok();
+        }
+        start(cpu);
+    };
+
+    let mut status = None;
+    if let Some(port) = gdb_port {
+        // Executes one instruction or takes one trap. Returns the exit status when the machine
+        // stops.
+        let mut step = |cpu: &mut Cpu| {
+            cpu.stop_at = Some(cpu.instret + 1);
+            match cpu.run(end_address) {
+                Ok(_) => match cpu.ram.test.finish.take() {
+                    Some(Finish::Pass) => return Some(0),
+                    Some(Finish::Fail(code)) => return Some(code as i32),
+                    Some(Finish::Reset) => restart(cpu),
+                    None if cpu.pc == end_address => return Some(0),
+                    None => {}
+                },
+                Err(e) => cpu.trap(e),
+            }
+            None
+        };
+        status = gdb::serve(port, &mut cpu, checkpoint_interval, &mut step)?;
+        // The machine runs on after the debugger detaches.
+        cpu.stop_at = None;
+    }
 
-    loop {
-        let result = cpu.run(end_address as u32);
-        match result {
-            Ok(_) => break, // reach to end point
-            Err(e) => cpu.trap(e),
+    // The exit status requested through the test finisher.
+    let status = if status.is_some() {
+        status
+    } else {
+        loop {
+            let result = cpu.run(end_address);
+            match result {
+                Ok(_) => match cpu.ram.test.finish.take() {
+                    Some(Finish::Pass) => break Some(0),
+                    Some(Finish::Fail(code)) => break Some(code as i32),
+                    Some(Finish::Reset) => restart(&mut cpu),
+                    None if cpu.stop_at == Some(cpu.instret) => {
+                        let path = save.as_deref().unwrap_or_default();
+                        cpu.save_snapshot(path)?;
+                        eprintln!(
+                            "saved a snapshot to {} at {} instructions",
+                            path, cpu.instret
+                        );
+                        break Some(0);
+                    }
+                    None => break None, // reach to end point
+                },
+                Err(e) => cpu.trap(e),
+            }
+        }
+    };
+    if let Some(path) = &record {
+        replay::save(path)?;
+    }
+    if status.is_none() {
+        cpu.dump_registers();
+    }
+    if let Some(fb) = &cpu.ram.framebuffer {
+        if let Some(path) = &fb.output {
+            fb.save(path)?;
         }
     }
-    cpu.dump_registers();
+    if tlb_stats {
+        let stats = &cpu.tlb.stats;
+        println!(
+            "TLB: {} hits, {} misses, {} flushes",
+            stats.hits, stats.misses, stats.flushes
+        );
+    }
+    if let Some(code) = status {
+        std::process::exit(code);
+    }
     Ok(())
 }
 
-fn get_write_tohost_address(filename: &str) -> u64 {
-    let file = elf::File::open_path(filename).expect("No such file.");
-    let symtab = file
-        .get_section(".symtab")
-        .expect("Failed to look up .symtab section");
-    let symbols = file.get_symbols(symtab).unwrap();
+// Opens a disk image given as <file>[,readonly][,snapshot].
+fn open_drive(drive: &str) -> io::Result<VirtioBlk> {
+    let mut options = drive.split(',');
+    let path = options.next().unwrap_or_default();
+    let (mut readonly, mut snapshot) = (false, false);
+    for option in options {
+        match option {
+            "readonly" => readonly = true,
+            "snapshot" => snapshot = true,
+            _ => panic!("--drive: unknown option {}\n{}", option, USAGE),
+        }
+    }
+    VirtioBlk::open(path, readonly, snapshot)
+}
+
+fn open_chardev(
+    option: &str,
+    spec: Option<String>,
+    host_inputs: &mut Vec<String>,
+) -> io::Result<Chardev> {
+    let spec = spec.unwrap_or_else(|| panic!("{} requires a character device", option));
+    if takes_host_input(&spec) {
+        host_inputs.push(format!("{} {}", option, spec));
+    }
+    Chardev::open(&spec)
+}
+
+// Whether the character device or the network backend reads from the host. file: and pcap: only
+// write.
+fn takes_host_input(spec: &str) -> bool {
+    !spec.starts_with("file:") && !spec.starts_with("pcap:")
+}
 
-    for s in symbols {
-        if s.name == "write_tohost" {
-            return s.value - file.ehdr.entry;
+// Parses <width>x<height>[,<format>].
+fn parse_framebuffer(spec: &str) -> Framebuffer {
+    let (resolution, format) = spec.split_once(',').unwrap_or((spec, "x8r8g8b8"));
+    let size = resolution
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+    match (size, PixelFormat::parse(format)) {
+        (Some((width, height)), Some(format)) if width > 0 && height > 0 => {
+            Framebuffer::new(width, height, format)
         }
+        _ => panic!("--fb: invalid framebuffer {}\n{}", spec, USAGE),
     }
-    return 0;
+}
+
+// Accepts decimal or 0x-prefixed hexadecimal numbers.
+fn parse_number(option: &str, value: Option<String>) -> u32 {
+    let value = value.unwrap_or_else(|| panic!("{} requires a number", option));
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.unwrap_or_else(|_| panic!("{}: invalid number {}", option, value))
 }
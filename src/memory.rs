@@ -1,26 +1,78 @@
+use std::io;
+
+use crate::devices::*;
 use crate::exception::Exception;
+use crate::snapshot::{invalid, Reader, Snapshot, Writer};
+
+/*
+    Physical memory map (compatible with QEMU virt)
+
+    0x0000_1000 - 0x0000_FFFF   Boot ROM (read-only)
+    0x0010_0000 - 0x0010_0FFF   Test finisher
+    0x0010_1000 - 0x0010_1FFF   RTC (IRQ 11)
+    0x0200_0000 - 0x0200_FFFF   CLINT
+    0x0C00_0000 - 0x0C5F_FFFF   PLIC
+    0x1000_0000 - 0x1000_00FF   UART0 (IRQ 10)
+    0x1000_1000 - 0x1000_8FFF   virtio-mmio (0x1000 bytes and IRQ 1-8 per device)
+    0x4000_0000 - 0x4000_0FFF   Framebuffer control registers
+    0x4000_1000 -               Framebuffer VRAM (optional)
+    0x8000_0000 -               DRAM
 
+    Devices only support 32-bit accesses, except that the UART and the configuration space of
+    virtio devices support any size up to 32 bits. VRAM is accessed like DRAM.
+*/
+pub const MROM_BASE: u32 = 0x1000;
+pub const MROM_SIZE: u32 = 0xF000;
+pub const TEST_BASE: u32 = 0x10_0000;
+pub const TEST_SIZE: u32 = 0x1000;
+pub const RTC_BASE: u32 = 0x10_1000;
+pub const RTC_SIZE: u32 = 0x1000;
+pub const RTC_IRQ: u32 = 11;
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x60_0000;
+pub const UART0_BASE: u32 = 0x1000_0000;
+pub const UART0_SIZE: u32 = 0x100;
+pub const UART0_IRQ: u32 = 10;
+pub const VIRTIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_SIZE: u32 = 0x1000;
+pub const VIRTIO_COUNT: usize = 8;
+pub const VIRTIO_IRQ: u32 = 1;
+pub const FB_BASE: u32 = 0x4000_0000;
+pub const FB_SIZE: u32 = 0x1000;
+pub const FB_VRAM_BASE: u32 = 0x4000_1000;
+pub const DRAM_BASE: u32 = 0x8000_0000;
 pub const MEMORY_SIZE: u32 = 1024 * 1024 * 128;
 
 pub struct Memory {
     pub ram: Vec<u8>,
+    pub rom: Vec<u8>,
+    pub clint: Clint,
+    pub plic: Plic,
+    pub rtc: Rtc,
+    pub test: SifiveTest,
+    pub uart: Uart,
+    pub virtio: Vec<VirtioMmio>,
+    pub framebuffer: Option<Framebuffer>,
 }
 
+#[derive(Copy, Clone)]
 pub enum MemOps {
     Load,
     Store,
     Fetch,
 }
 
-fn chk_address(start: u32, size: u32, ops: MemOps) -> Result<(), Exception> {
-    if start + size >= MEMORY_SIZE {
-        match ops {
-            MemOps::Load => return Err(Exception::LoadAccessFault),
-            MemOps::Store => return Err(Exception::StoreAMOAccessFault),
-            MemOps::Fetch => return Err(Exception::InstructionAccessFault),
-        }
+fn access_fault(ops: MemOps) -> Exception {
+    match ops {
+        MemOps::Load => Exception::LoadAccessFault,
+        MemOps::Store => Exception::StoreAMOAccessFault,
+        MemOps::Fetch => Exception::InstructionAccessFault,
     }
+}
 
+fn chk_address(start: u32, size: u32, ops: MemOps) -> Result<(), Exception> {
     if start % size != 0 {
         match ops {
             MemOps::Load => return Err(Exception::LoadAddressMisaligned),
@@ -31,91 +83,314 @@ fn chk_address(start: u32, size: u32, ops: MemOps) -> Result<(), Exception> {
     Ok(())
 }
 
+// Returns the offset of [start, start+size) in a region of `len` bytes at `base`.
+fn offset_in(start: u32, size: u32, base: u32, len: usize) -> Option<usize> {
+    let offset = start.checked_sub(base)? as usize;
+    if offset + size as usize <= len {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
 impl Memory {
     pub fn new() -> Memory {
         Self {
             ram: vec![0; MEMORY_SIZE as usize],
+            rom: Vec::new(),
+            clint: Clint::new(),
+            plic: Plic::new(),
+            rtc: Rtc::new(),
+            test: SifiveTest::new(),
+            uart: Uart::new(),
+            virtio: Vec::new(),
+            framebuffer: None,
+        }
+    }
+
+    // Adds a virtio device in the next free virtio-mmio slot.
+    pub fn add_virtio(&mut self, device: Box<dyn VirtioDevice>) {
+        assert!(self.virtio.len() < VIRTIO_COUNT, "too many virtio devices");
+        self.virtio.push(VirtioMmio::new(device));
+    }
+
+    // Resets the devices. DRAM and the boot ROM are unchanged.
+    pub fn reset(&mut self) {
+        self.rtc.reset(self.clint.mtime);
+        self.clint.reset();
+        self.plic = Plic::new();
+        self.test = SifiveTest::new();
+        self.uart.reset();
+        for virtio in self.virtio.iter_mut() {
+            virtio.reset();
+        }
+        if let Some(fb) = &mut self.framebuffer {
+            fb.reset();
+        }
+    }
+
+    // Advances the devices by one instruction.
+    pub fn tick(&mut self) {
+        self.clint.tick();
+        self.rtc.tick(self.clint.mtime);
+        self.plic.set_irq(RTC_IRQ as usize, self.rtc.interrupt());
+        self.uart.tick();
+        self.plic.set_irq(UART0_IRQ as usize, self.uart.interrupt());
+        for (i, virtio) in self.virtio.iter_mut().enumerate() {
+            virtio.tick(&mut self.ram);
+            self.plic
+                .set_irq(VIRTIO_IRQ as usize + i, virtio.interrupt());
+        }
+        if let Some(fb) = &mut self.framebuffer {
+            fb.tick(self.clint.mtime);
+        }
+    }
+
+    // Returns the bytes at the physical address. Accesses to unmapped addresses and stores to the ROM fail.
+    fn bytes(&self, addr: u32, size: u32, ops: MemOps) -> Result<&[u8], Exception> {
+        if let Some(i) = offset_in(addr, size, DRAM_BASE, self.ram.len()) {
+            return Ok(&self.ram[i..i + size as usize]);
+        }
+        if let Some(i) = offset_in(addr, size, MROM_BASE, self.rom.len()) {
+            return Ok(&self.rom[i..i + size as usize]);
+        }
+        if let Some(fb) = &self.framebuffer {
+            if let Some(i) = offset_in(addr, size, FB_VRAM_BASE, fb.vram.len()) {
+                return Ok(&fb.vram[i..i + size as usize]);
+            }
+        }
+        Err(access_fault(ops))
+    }
+
+    fn bytes_mut(&mut self, addr: u32, size: u32) -> Result<&mut [u8], Exception> {
+        if let Some(i) = offset_in(addr, size, DRAM_BASE, self.ram.len()) {
+            return Ok(&mut self.ram[i..i + size as usize]);
+        }
+        if let Some(fb) = &mut self.framebuffer {
+            if let Some(i) = offset_in(addr, size, FB_VRAM_BASE, fb.vram.len()) {
+                return Ok(&mut fb.vram[i..i + size as usize]);
+            }
+        }
+        Err(Exception::StoreAMOAccessFault)
+    }
+
+    // Reads a device register. Returns None if no device is mapped at the address.
+    fn read_device(&mut self, addr: u32, size: u32) -> Option<u32> {
+        if let Some(offset) = offset_in(addr, size, UART0_BASE, UART0_SIZE as usize) {
+            return Some(self.uart.read(offset as u32));
+        }
+        let virtio_size = VIRTIO_SIZE as usize * self.virtio.len();
+        if let Some(offset) = offset_in(addr, size, VIRTIO_BASE, virtio_size) {
+            let virtio = &mut self.virtio[offset / VIRTIO_SIZE as usize];
+            return Some(virtio.read(offset as u32 % VIRTIO_SIZE, size));
+        }
+        if size != 4 {
+            return None;
+        }
+        if let Some(offset) = offset_in(addr, size, CLINT_BASE, CLINT_SIZE as usize) {
+            return Some(self.clint.read(offset as u32));
+        }
+        if let Some(offset) = offset_in(addr, size, PLIC_BASE, PLIC_SIZE as usize) {
+            return Some(self.plic.read(offset as u32));
+        }
+        if let Some(offset) = offset_in(addr, size, TEST_BASE, TEST_SIZE as usize) {
+            return Some(self.test.read(offset as u32));
+        }
+        if let Some(fb) = &self.framebuffer {
+            if let Some(offset) = offset_in(addr, size, FB_BASE, FB_SIZE as usize) {
+                return Some(fb.read(offset as u32));
+            }
         }
+        if let Some(offset) = offset_in(addr, size, RTC_BASE, RTC_SIZE as usize) {
+            return Some(self.rtc.read(offset as u32, self.clint.mtime));
+        }
+        None
+    }
+
+    // Writes a device register. Returns None if no device is mapped at the address.
+    fn write_device(&mut self, addr: u32, size: u32, val: u32) -> Option<()> {
+        if let Some(offset) = offset_in(addr, size, UART0_BASE, UART0_SIZE as usize) {
+            self.uart.write(offset as u32, val);
+            return Some(());
+        }
+        let virtio_size = VIRTIO_SIZE as usize * self.virtio.len();
+        if let Some(offset) = offset_in(addr, size, VIRTIO_BASE, virtio_size) {
+            let virtio = &mut self.virtio[offset / VIRTIO_SIZE as usize];
+            virtio.write(offset as u32 % VIRTIO_SIZE, size, val, &mut self.ram);
+            return Some(());
+        }
+        if size != 4 {
+            return None;
+        }
+        if let Some(offset) = offset_in(addr, size, CLINT_BASE, CLINT_SIZE as usize) {
+            self.clint.write(offset as u32, val);
+            return Some(());
+        }
+        if let Some(offset) = offset_in(addr, size, PLIC_BASE, PLIC_SIZE as usize) {
+            self.plic.write(offset as u32, val);
+            return Some(());
+        }
+        if let Some(offset) = offset_in(addr, size, TEST_BASE, TEST_SIZE as usize) {
+            self.test.write(offset as u32, val);
+            return Some(());
+        }
+        if let Some(fb) = &mut self.framebuffer {
+            if let Some(offset) = offset_in(addr, size, FB_BASE, FB_SIZE as usize) {
+                fb.write(offset as u32, val);
+                return Some(());
+            }
+        }
+        if let Some(offset) = offset_in(addr, size, RTC_BASE, RTC_SIZE as usize) {
+            self.rtc.write(offset as u32, val, self.clint.mtime);
+            return Some(());
+        }
+        None
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> Result<u32, Exception> {
+        chk_address(addr, size, MemOps::Load)?;
+        if addr < DRAM_BASE {
+            if let Some(val) = self.read_device(addr, size) {
+                return Ok(val);
+            }
+        }
+        let b = self.bytes(addr, size, MemOps::Load)?;
+        Ok(b.iter().rev().fold(0, |val, &byte| val << 8 | byte as u32))
+    }
+
+    fn write(&mut self, addr: u32, size: u32, val: u32) -> Result<(), Exception> {
+        chk_address(addr, size, MemOps::Store)?;
+        if addr < DRAM_BASE && self.write_device(addr, size, val).is_some() {
+            return Ok(());
+        }
+        let b = self.bytes_mut(addr, size)?;
+        for (i, byte) in b.iter_mut().enumerate() {
+            *byte = (val >> (8 * i)) as u8;
+        }
+        Ok(())
     }
 
-    pub fn set(&mut self, binary: &Vec<u8>) {
-        self.ram.splice(..binary.len(), binary.iter().cloned());
+    // Copies an image into DRAM.
+    pub fn load(&mut self, addr: u32, data: &[u8]) -> Result<(), Exception> {
+        self.bytes_mut(addr, data.len() as u32)?
+            .copy_from_slice(data);
+        Ok(())
     }
 
+    // Accesses memory for the debugger. Device registers are not accessible, as reading them may
+    // have side effects.
+    pub fn peek(&self, addr: u32, size: u32) -> Option<&[u8]> {
+        self.bytes(addr, size, MemOps::Load).ok()
+    }
+
+    pub fn poke(&mut self, addr: u32, data: &[u8]) -> Option<()> {
+        self.bytes_mut(addr, data.len() as u32)
+            .ok()?
+            .copy_from_slice(data);
+        Some(())
+    }
+
+    pub fn set_rom(&mut self, rom: Vec<u8>) {
+        assert!(rom.len() <= MROM_SIZE as usize, "boot ROM is too large");
+        self.rom = rom;
+    }
+
+    // Instructions are fetched in 16-bit parcels.
     pub fn fetch(&self, addr: u32) -> Result<u32, Exception> {
-        chk_address(addr, 4, MemOps::Fetch)?;
-        return self.read32(addr);
+        chk_address(addr, 2, MemOps::Fetch)?;
+        let b = self.bytes(addr, 2, MemOps::Fetch)?;
+        Ok((b[0] as u32) | ((b[1] as u32) << 8))
     }
 
-    pub fn read8(&self, addr: u32) -> Result<u32, Exception> {
-        chk_address(addr, 1, MemOps::Load)?;
-        Ok(self.ram[addr as usize] as u32)
+    pub fn read8(&mut self, addr: u32) -> Result<u32, Exception> {
+        self.read(addr, 1)
     }
 
-    pub fn read16(&self, addr: u32) -> Result<u32, Exception> {
-        chk_address(addr, 2, MemOps::Load)?;
-        let index = addr as usize;
-        Ok((self.ram[index] as u32) | ((self.ram[index + 1] as u32) << 8))
+    pub fn read16(&mut self, addr: u32) -> Result<u32, Exception> {
+        self.read(addr, 2)
     }
 
-    pub fn read32(&self, addr: u32) -> Result<u32, Exception> {
-        chk_address(addr, 4, MemOps::Load)?;
-        let index = addr as usize;
-        Ok((self.ram[index] as u32)
-            | ((self.ram[index + 1] as u32) << 8)
-            | ((self.ram[index + 2] as u32) << 16)
-            | ((self.ram[index + 3] as u32) << 24))
+    pub fn read32(&mut self, addr: u32) -> Result<u32, Exception> {
+        self.read(addr, 4)
     }
 
-    pub fn read64(&self, addr: u32) -> Result<u64, Exception> {
+    pub fn read64(&mut self, addr: u32) -> Result<u64, Exception> {
         chk_address(addr, 8, MemOps::Load)?;
-        let index = addr as usize;
-        Ok((self.ram[index] as u64)
-            | ((self.ram[index + 1] as u64) << 8)
-            | ((self.ram[index + 2] as u64) << 16)
-            | ((self.ram[index + 3] as u64) << 24)
-            | ((self.ram[index + 4] as u64) << 32)
-            | ((self.ram[index + 5] as u64) << 40)
-            | ((self.ram[index + 6] as u64) << 48)
-            | ((self.ram[index + 7] as u64) << 56))
+        let b = self.bytes(addr, 8, MemOps::Load)?;
+        Ok((b[0] as u64)
+            | ((b[1] as u64) << 8)
+            | ((b[2] as u64) << 16)
+            | ((b[3] as u64) << 24)
+            | ((b[4] as u64) << 32)
+            | ((b[5] as u64) << 40)
+            | ((b[6] as u64) << 48)
+            | ((b[7] as u64) << 56))
     }
 
     pub fn write8(&mut self, addr: u32, val: u8) -> Result<(), Exception> {
-        chk_address(addr, 1, MemOps::Store)?;
-        let index = addr as usize;
-        self.ram[index] = val;
-        Ok(())
+        self.write(addr, 1, val as u32)
     }
 
     pub fn write16(&mut self, addr: u32, val: u16) -> Result<(), Exception> {
-        chk_address(addr, 2, MemOps::Store)?;
-        let index = addr as usize;
-        self.ram[index] = (val & 0xff) as u8;
-        self.ram[index + 1] = ((val >> 8) & 0xff) as u8;
-        Ok(())
+        self.write(addr, 2, val as u32)
     }
 
     pub fn write32(&mut self, addr: u32, val: u32) -> Result<(), Exception> {
-        chk_address(addr, 4, MemOps::Store)?;
-        let index = addr as usize;
-        self.ram[index] = (val & 0xff) as u8;
-        self.ram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.ram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.ram[index + 3] = ((val >> 24) & 0xff) as u8;
-        Ok(())
+        self.write(addr, 4, val)
     }
 
     pub fn write64(&mut self, addr: u32, val: u64) -> Result<(), Exception> {
         chk_address(addr, 8, MemOps::Store)?;
-        let index = addr as usize;
-        self.ram[index] = (val & 0xff) as u8;
-        self.ram[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.ram[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.ram[index + 3] = ((val >> 24) & 0xff) as u8;
-        self.ram[index + 4] = ((val >> 32) & 0xff) as u8;
-        self.ram[index + 5] = ((val >> 40) & 0xff) as u8;
-        self.ram[index + 6] = ((val >> 48) & 0xff) as u8;
-        self.ram[index + 7] = ((val >> 56) & 0xff) as u8;
+        let b = self.bytes_mut(addr, 8)?;
+        b[0] = (val & 0xff) as u8;
+        b[1] = ((val >> 8) & 0xff) as u8;
+        b[2] = ((val >> 16) & 0xff) as u8;
+        b[3] = ((val >> 24) & 0xff) as u8;
+        b[4] = ((val >> 32) & 0xff) as u8;
+        b[5] = ((val >> 40) & 0xff) as u8;
+        b[6] = ((val >> 48) & 0xff) as u8;
+        b[7] = ((val >> 56) & 0xff) as u8;
         Ok(())
     }
 }
+
+// DRAM, the boot ROM and the state of the devices. The test finisher stops the machine, so it has
+// no state to save.
+impl Snapshot for Memory {
+    fn save(&self, w: &mut Writer) {
+        w.sparse(&self.ram);
+        w.bytes(&self.rom);
+        self.clint.save(w);
+        self.plic.save(w);
+        self.rtc.save(w);
+        self.uart.save(w);
+        w.u32(self.virtio.len() as u32);
+        for virtio in &self.virtio {
+            virtio.save(w);
+        }
+        w.bool(self.framebuffer.is_some());
+        if let Some(fb) = &self.framebuffer {
+            Snapshot::save(fb, w);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        r.sparse(&mut self.ram)?;
+        self.rom = r.bytes()?;
+        self.clint.restore(r)?;
+        self.plic.restore(r)?;
+        self.rtc.restore(r)?;
+        self.uart.restore(r)?;
+        if r.u32()? as usize != self.virtio.len() {
+            return Err(invalid("the number of virtio devices differs"));
+        }
+        for virtio in self.virtio.iter_mut() {
+            virtio.restore(r)?;
+        }
+        match (r.bool()?, &mut self.framebuffer) {
+            (false, None) => Ok(()),
+            (true, Some(fb)) => fb.restore(r),
+            _ => Err(invalid("the framebuffer differs")),
+        }
+    }
+}
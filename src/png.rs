@@ -0,0 +1,204 @@
+/*
+    PNG (Portable Network Graphics) Specification, Version 1.2
+
+    An image is the signature followed by the IHDR, IDAT and IEND chunks. Each chunk consists of
+    length: be32, type: [u8; 4], data and crc32 of the type and data. The IDAT chunk holds a zlib
+    stream of the scanlines, each of which starts with a filter type byte (0: None).
+*/
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGB: u8 = 2;
+
+// Encodes 8-bit RGB pixels.
+pub fn encode(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row = width as usize * 3;
+    let mut scanlines = Vec::with_capacity((row + 1) * height as usize);
+    for line in rgb.chunks(row).take(height as usize) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(line);
+    }
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Compression method 0, filter method 0 and no interlace.
+    ihdr.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &ihdr);
+    chunk(&mut png, b"IDAT", &zlib(&scanlines, row + 1));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// (ISO 3309) CRC-32 with the reflected polynomial 0xEDB88320.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/*
+    (RFC 1950) ZLIB Compressed Data Format
+
+    CMF: u8 (deflate, 32K window), FLG: u8, compressed data, ADLER-32: be32
+*/
+fn zlib(data: &[u8], row: usize) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data, row));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+/*
+    (RFC 1951) DEFLATE Compressed Data Format
+
+    The data is a single block compressed with the fixed Huffman codes. Screenshots mostly repeat
+    the previous pixel or the pixel above, so only those two distances are searched for matches.
+*/
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const WINDOW_SIZE: usize = 32768;
+const END_OF_BLOCK: u16 = 256;
+
+// Bits are packed starting from the least significant bit of each byte.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from the most significant bit.
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    // (3.2.6) The fixed literal/length code.
+    fn write_symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn deflate(data: &[u8], row: usize) -> Vec<u8> {
+    let mut writer = BitWriter {
+        out: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman codes)
+    writer.write(0b011, 3);
+
+    let match_len = |i: usize, distance: usize| {
+        if distance > i || distance > WINDOW_SIZE {
+            return 0;
+        }
+        (0..MAX_MATCH.min(data.len() - i))
+            .take_while(|&k| data[i + k] == data[i + k - distance])
+            .count()
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let (len, distance) = [3, row]
+            .iter()
+            .map(|&d| (match_len(i, d), d))
+            .max_by_key(|&(len, _)| len)
+            .unwrap();
+        if len < MIN_MATCH {
+            writer.write_symbol(data[i] as u16);
+            i += 1;
+            continue;
+        }
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&b| b as usize <= len)
+            .unwrap();
+        writer.write_symbol(257 + code as u16);
+        writer.write(
+            (len - LENGTH_BASE[code] as usize) as u32,
+            LENGTH_EXTRA[code] as u32,
+        );
+        let code = DISTANCE_BASE
+            .iter()
+            .rposition(|&b| b as usize <= distance)
+            .unwrap();
+        writer.write_code(code as u32, 5);
+        writer.write(
+            (distance - DISTANCE_BASE[code] as usize) as u32,
+            DISTANCE_EXTRA[code] as u32,
+        );
+        i += len;
+    }
+    writer.write_symbol(END_OF_BLOCK);
+    writer.finish()
+}
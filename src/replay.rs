@@ -0,0 +1,215 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+
+/*
+    Record and replay of the nondeterministic inputs
+
+    Given the same inputs, the machine executes the same instructions. The inputs are the values
+    read from the host: the clock (mtime and the RTC), the input of character devices, received
+    frames and host entropy. While recording, each value is appended to the log as it is read.
+    While replaying, the values are taken from the log instead of the host, so the execution
+    repeats exactly. Output to the host is muted while the debugger executes the same steps again.
+
+    Each event is kind: u8 followed by a value or a length, both LEB128-encoded, and the bytes. The
+    kind detects a replay which has diverged from the recorded execution, e.g. with other options.
+
+    The log file is the magic "RV32RLOG" followed by the events.
+*/
+const MAGIC: &[u8; 8] = b"RV32RLOG";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    Clock = 0,
+    Rtc = 1,
+    Chardev = 2,
+    Net = 3,
+    Rng = 4,
+}
+
+struct Log {
+    events: Vec<u8>,
+    // The next event to replay, or the end of the log once all the events are replayed.
+    pos: usize,
+    // Whether new events are appended at the end of the log.
+    recording: bool,
+    muted: bool,
+}
+
+thread_local! {
+    static LOG: RefCell<Log> = const {
+        RefCell::new(Log {
+            events: Vec::new(),
+            pos: 0,
+            recording: false,
+            muted: false,
+        })
+    };
+}
+
+impl Log {
+    fn replaying(&self) -> bool {
+        self.pos < self.events.len()
+    }
+
+    fn put(&mut self, mut val: u64) {
+        loop {
+            let byte = val as u8 & 0x7F;
+            val >>= 7;
+            if val == 0 {
+                self.events.push(byte);
+                return;
+            }
+            self.events.push(byte | 0x80);
+        }
+    }
+
+    fn get(&mut self) -> u64 {
+        let mut val = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.next_byte();
+            val |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        val
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let byte = *self
+            .events
+            .get(self.pos)
+            .expect("replay: the log ends in the middle of an event");
+        self.pos += 1;
+        byte
+    }
+
+    fn expect(&mut self, source: Source) {
+        let kind = self.next_byte();
+        if kind != source as u8 {
+            panic!(
+                "replay: the execution diverged from the log (expected {:?}, found kind {})",
+                source, kind
+            );
+        }
+    }
+
+    fn value(&mut self, source: Source, host: impl FnOnce() -> u64) -> u64 {
+        if self.replaying() {
+            self.expect(source);
+            return self.get();
+        }
+        let val = host();
+        if self.recording {
+            self.events.push(source as u8);
+            self.put(val);
+            self.pos = self.events.len();
+        }
+        val
+    }
+
+    fn bytes(&mut self, source: Source, host: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        if self.replaying() {
+            self.expect(source);
+            let len = self.get() as usize;
+            return (0..len).map(|_| self.next_byte()).collect();
+        }
+        let data = host();
+        if self.recording {
+            self.events.push(source as u8);
+            self.put(data.len() as u64);
+            self.events.extend_from_slice(&data);
+            self.pos = self.events.len();
+        }
+        data
+    }
+}
+
+// Returns a value read from the host, or the recorded one.
+pub fn value(source: Source, host: impl FnOnce() -> u64) -> u64 {
+    LOG.with(|log| log.borrow_mut().value(source, host))
+}
+
+// Returns bytes read from the host, or the recorded ones.
+pub fn bytes(source: Source, host: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+    LOG.with(|log| log.borrow_mut().bytes(source, host))
+}
+
+// Returns packets read from the host, or the recorded ones.
+pub fn packets(source: Source, host: impl FnOnce() -> Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        if log.replaying() {
+            let count = log.value(source, || 0);
+            return (0..count).map(|_| log.bytes(source, Vec::new)).collect();
+        }
+        let packets = host();
+        log.value(source, || packets.len() as u64);
+        for packet in &packets {
+            log.bytes(source, || packet.clone());
+        }
+        packets
+    })
+}
+
+// Whether the inputs come from the log.
+pub fn replaying() -> bool {
+    LOG.with(|log| log.borrow().replaying())
+}
+
+// Whether output to the host is discarded, as it was already written.
+pub fn muted() -> bool {
+    LOG.with(|log| log.borrow().muted)
+}
+
+pub fn set_muted(muted: bool) {
+    LOG.with(|log| log.borrow_mut().muted = muted);
+}
+
+pub fn start_recording() {
+    LOG.with(|log| log.borrow_mut().recording = true);
+}
+
+// Replays the events in the file. The inputs come from the host again after the last event.
+pub fn load(path: &str) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let events = data
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a replay log"))?;
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.events = events.to_vec();
+        log.pos = 0;
+    });
+    Ok(())
+}
+
+// Writes all the events recorded or loaded to the file.
+pub fn save(path: &str) -> io::Result<()> {
+    LOG.with(|log| {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&log.borrow().events);
+        fs::write(path, data)
+    })
+}
+
+// The position of the next event, which a checkpoint of the machine goes with.
+pub fn position() -> usize {
+    LOG.with(|log| log.borrow().pos)
+}
+
+// Replays the events from the position, after the machine is restored to the checkpoint.
+pub fn rewind(pos: usize) {
+    LOG.with(|log| log.borrow_mut().pos = pos);
+}
+
+// Drops the events after the current position, which belong to an execution that will not
+// happen again, e.g. after the debugger changed a register.
+pub fn truncate() {
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let pos = log.pos;
+        log.events.truncate(pos);
+    });
+}
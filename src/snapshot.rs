@@ -0,0 +1,174 @@
+use std::io;
+
+/*
+    Machine snapshot file
+
+    magic: "RV32SNAP", version: le32, followed by the state of the hart and the bus in a fixed
+    order. Integers are little-endian, and byte strings are preceded by their length (le32).
+
+    Only the state of the machine is saved. The configuration, such as the devices and their host
+    backends, comes from the command line, which must be the same when restoring.
+*/
+const MAGIC: &[u8; 8] = b"RV32SNAP";
+// Incremented whenever the layout changes.
+const VERSION: u32 = 2;
+
+// Memory is saved in pages, and pages of zeros are omitted.
+const PAGE_SIZE: usize = 4096;
+
+pub trait Snapshot {
+    fn save(&self, w: &mut Writer);
+    fn restore(&mut self, r: &mut Reader) -> io::Result<()>;
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("snapshot: {}", msg))
+}
+
+pub struct Writer(Vec<u8>);
+
+impl Writer {
+    pub fn new() -> Self {
+        let mut w = Self(MAGIC.to_vec());
+        w.u32(VERSION);
+        w
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.0.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32s(&mut self, vals: &[u32]) {
+        vals.iter().for_each(|&val| self.u32(val));
+    }
+
+    pub fn option_u64(&mut self, val: Option<u64>) {
+        self.bool(val.is_some());
+        self.u64(val.unwrap_or(0));
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.0.extend_from_slice(data);
+    }
+
+    // Writes the length, then each page with any non-zero byte as (index: le32, page).
+    pub fn sparse(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+            if page.iter().any(|&b| b != 0) {
+                self.u32(i as u32);
+                self.0.extend_from_slice(page);
+            }
+        }
+        self.u32(u32::MAX);
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        let mut r = Self { data, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a snapshot file"));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "version {} is not supported (expected {})",
+                version, VERSION
+            )));
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    pub fn u32s(&mut self, vals: &mut [u32]) -> io::Result<()> {
+        for val in vals.iter_mut() {
+            *val = self.u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn option_u64(&mut self) -> io::Result<Option<u64>> {
+        let some = self.bool()?;
+        let val = self.u64()?;
+        Ok(some.then_some(val))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Reads pages written by Writer::sparse into memory of the same size.
+    pub fn sparse(&mut self, data: &mut [u8]) -> io::Result<()> {
+        if self.u32()? as usize != data.len() {
+            return Err(invalid("the memory size differs"));
+        }
+        data.fill(0);
+        loop {
+            let i = self.u32()?;
+            if i == u32::MAX {
+                return Ok(());
+            }
+            let page = data
+                .chunks_mut(PAGE_SIZE)
+                .nth(i as usize)
+                .ok_or_else(|| invalid("page out of range"))?;
+            page.copy_from_slice(self.take(page.len())?);
+        }
+    }
+
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid("trailing data"));
+        }
+        Ok(())
+    }
+}
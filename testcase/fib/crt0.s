@@ -0,0 +1,7 @@
+# The boot ROM jumps here with a0 = mhartid and a1 = DTB. The stack is the top of 128 MiB DRAM.
+    .globl _start
+_start:
+    li sp, 0x88000000
+    call main
+1:
+    j 1b